@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+use std::io::{self, Write, ErrorKind as IoErrorKind};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use crate::protocol::{read_frame, write_frame, ClientMessage, Command, DriverMessage, Error, Event, Reply,
+                      MAGIC, VERSION};
+
+/// A blocking connection to the control socket.
+pub struct Client {
+    stream: UnixStream,
+    next_id: u64,
+    /// Events that arrived while we waited for a result
+    pending: VecDeque<Event>,
+    pub version: u32,
+}
+
+impl Client {
+    /// Connects and negotiates the protocol version. `None` if the driver isn't running.
+    pub fn connect<P: AsRef<Path>>(socket: P) -> io::Result<Option<Client>> {
+        let mut stream = match UnixStream::connect(socket) {
+            Err(ref e) if e.kind() == IoErrorKind::ConnectionRefused || e.kind() == IoErrorKind::NotFound
+                => return Ok(None),
+            x => x?,
+        };
+        stream.write_all(MAGIC)?;
+        write_frame(&mut stream, &ClientMessage::Hello { version: VERSION })?;
+        match read_frame(&mut stream)? {
+            Some(DriverMessage::Hello { version }) => Ok(Some(Client { stream, next_id: 1, pending: VecDeque::new(), version })),
+            Some(DriverMessage::Error { error }) => Err(io::Error::other(error)),
+            other => Err(io::Error::new(IoErrorKind::InvalidData, format!("unexpected handshake reply {:?}", other))),
+        }
+    }
+
+    /// Connects to the driver of the instance `name`, see `socket_path`.
+    pub fn connect_instance(name: Option<&str>) -> io::Result<Option<Client>> {
+        Client::connect(crate::socket_path(name))
+    }
+
+    /// Sends `command` and waits for its result. Events that arrive in the meantime are kept for `next_event`.
+    pub fn request(&mut self, command: Command) -> io::Result<Result<Reply, Error>> {
+        let mut events = Vec::new();
+        let result = self.request_with(command, |event| events.push(event));
+        self.pending.extend(events);
+        result
+    }
+
+    /// Sends `command` and waits for its result, passing events that arrive in the meantime to `on_event`.
+    pub fn request_with<F: FnMut(Event)>(&mut self, command: Command, mut on_event: F)
+                                         -> io::Result<Result<Reply, Error>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        write_frame(&mut self.stream, &ClientMessage::Request { id, command })?;
+        loop {
+            match read_frame(&mut self.stream)? {
+                Some(DriverMessage::Result { id: reply_id, result }) if reply_id == id => return Ok(result),
+                Some(DriverMessage::Event { event }) => on_event(event),
+                Some(DriverMessage::Error { error }) => return Ok(Err(error)),
+                // e.g. a late result of an earlier request that we stopped waiting for
+                Some(_) => (),
+                None => return Err(io::Error::new(IoErrorKind::UnexpectedEof, "the driver closed the connection")),
+            }
+        }
+    }
+
+    /// Asks for status events, which `next_event` returns from now on.
+    ///
+    /// The first one arrives right away.
+    pub fn subscribe(&mut self) -> io::Result<Result<(), Error>> {
+        Ok(self.request(Command::Subscribe)?.map(|_| ()))
+    }
+
+    /// Waits for the next event, `None` once the driver closed the connection.
+    pub fn next_event(&mut self) -> io::Result<Option<Event>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Ok(Some(event));
+        }
+        loop {
+            match read_frame(&mut self.stream)? {
+                Some(DriverMessage::Event { event }) => return Ok(Some(event)),
+                Some(_) => (),
+                None => return Ok(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind as IoErrorKind};
+use std::path::Path;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use crate::protocol::{encode_frame, frame_len, ClientMessage, Command, DriverMessage, Error, Event, Reply,
+                      MAGIC, VERSION};
+
+/// A connection to the control socket for use on tokio, see `blocking::Client` for the details.
+pub struct Client {
+    stream: UnixStream,
+    next_id: u64,
+    /// Events that arrived while we waited for a result
+    pending: VecDeque<Event>,
+    pub version: u32,
+}
+
+impl Client {
+    /// Connects and negotiates the protocol version. `None` if the driver isn't running.
+    pub async fn connect<P: AsRef<Path>>(socket: P) -> io::Result<Option<Client>> {
+        let stream = match UnixStream::connect(socket).await {
+            Err(ref e) if e.kind() == IoErrorKind::ConnectionRefused || e.kind() == IoErrorKind::NotFound
+                => return Ok(None),
+            x => x?,
+        };
+        let mut client = Client { stream, next_id: 1, pending: VecDeque::new(), version: 0 };
+        client.stream.write_all(MAGIC).await?;
+        client.write(&ClientMessage::Hello { version: VERSION }).await?;
+        match client.read().await? {
+            Some(DriverMessage::Hello { version }) => {
+                client.version = version;
+                Ok(Some(client))
+            }
+            Some(DriverMessage::Error { error }) => Err(io::Error::other(error)),
+            other => Err(io::Error::new(IoErrorKind::InvalidData, format!("unexpected handshake reply {:?}", other))),
+        }
+    }
+
+    /// Connects to the driver of the instance `name`, see `socket_path`.
+    pub async fn connect_instance(name: Option<&str>) -> io::Result<Option<Client>> {
+        Client::connect(crate::socket_path(name)).await
+    }
+
+    async fn write<T: Serialize>(&mut self, msg: &T) -> io::Result<()> {
+        self.stream.write_all(&encode_frame(msg)?).await
+    }
+
+    async fn read<T: DeserializeOwned>(&mut self) -> io::Result<Option<T>> {
+        let mut len = [0; 4];
+        match self.stream.read_exact(&mut len).await {
+            Err(ref e) if e.kind() == IoErrorKind::UnexpectedEof => return Ok(None),
+            x => x?,
+        };
+        let mut buf = vec![0; frame_len(len)?];
+        self.stream.read_exact(&mut buf).await?;
+        Ok(Some(serde_json::from_slice(&buf)?))
+    }
+
+    /// Sends `command` and waits for its result. Events that arrive in the meantime are kept for `next_event`.
+    pub async fn request(&mut self, command: Command) -> io::Result<Result<Reply, Error>> {
+        let mut events = Vec::new();
+        let result = self.request_with(command, |event| events.push(event)).await;
+        self.pending.extend(events);
+        result
+    }
+
+    /// Sends `command` and waits for its result, passing events that arrive in the meantime to `on_event`.
+    pub async fn request_with<F: FnMut(Event)>(&mut self, command: Command, mut on_event: F)
+                                               -> io::Result<Result<Reply, Error>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.write(&ClientMessage::Request { id, command }).await?;
+        loop {
+            match self.read().await? {
+                Some(DriverMessage::Result { id: reply_id, result }) if reply_id == id => return Ok(result),
+                Some(DriverMessage::Event { event }) => on_event(event),
+                Some(DriverMessage::Error { error }) => return Ok(Err(error)),
+                // e.g. a late result of an earlier request that we stopped waiting for
+                Some(_) => (),
+                None => return Err(io::Error::new(IoErrorKind::UnexpectedEof, "the driver closed the connection")),
+            }
+        }
+    }
+
+    /// Asks for status events, which `next_event` returns from now on.
+    pub async fn subscribe(&mut self) -> io::Result<Result<(), Error>> {
+        Ok(self.request(Command::Subscribe).await?.map(|_| ()))
+    }
+
+    /// Waits for the next event, `None` once the driver closed the connection.
+    pub async fn next_event(&mut self) -> io::Result<Option<Event>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Ok(Some(event));
+        }
+        loop {
+            match self.read().await? {
+                Some(DriverMessage::Event { event }) => return Ok(Some(event)),
+                Some(_) => (),
+                None => return Ok(None),
+            }
+        }
+    }
+}
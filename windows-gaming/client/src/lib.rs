@@ -0,0 +1,50 @@
+//! Talks to a running `windows-gaming` driver through its control socket.
+//!
+//! ```no_run
+//! use windows_gaming_client::blocking::Client;
+//! use windows_gaming_client::protocol::{AttachMode, Command};
+//!
+//! let mut client = Client::connect_instance(None).unwrap().expect("Windows is down");
+//! client.request(Command::Attach { mode: AttachMode::Auto }).unwrap().expect("the driver refused");
+//! ```
+//!
+//! With the `async` feature, `Client` offers the same on tokio.
+
+#[macro_use]
+extern crate serde_derive;
+
+use std::env;
+use std::path::PathBuf;
+
+pub mod protocol;
+pub mod blocking;
+#[cfg(feature = "async")]
+mod client;
+
+#[cfg(feature = "async")]
+pub use crate::client::Client;
+
+/// Name of the directories and files of the default instance, named instances append `@NAME`
+/// (just like systemd template units).
+pub const PREFIX: &str = "windows-gaming-driver";
+
+/// Name of the directories and files of the instance `name`.
+pub fn dir_name(name: Option<&str>) -> String {
+    match name {
+        None => PREFIX.to_owned(),
+        Some(name) => format!("{}@{}", PREFIX, name),
+    }
+}
+
+/// Where the driver of the instance `name` listens, unless its config has a `runtime_directory_override`.
+///
+/// Root talks to the system instances, everyone else to their own.
+pub fn socket_path(name: Option<&str>) -> PathBuf {
+    let runtime_dir = if unsafe { libc::getuid() } == 0 {
+        PathBuf::from("/run")
+    } else {
+        env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(format!("/run/user/{}", unsafe { libc::getuid() })))
+    };
+    runtime_dir.join(dir_name(name)).join("control.sock")
+}
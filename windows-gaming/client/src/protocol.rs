@@ -0,0 +1,316 @@
+//! The control socket protocol.
+//!
+//! Clients open the connection with `MAGIC`, followed by frames: a big endian `u32` length and a JSON document of
+//! that length. The first frame has to be a `ClientMessage::Hello`, which the driver answers with the version both
+//! sides speak. After that, every request gets exactly one `DriverMessage::Result` with the same id, while events
+//! can arrive at any time.
+//!
+//! Connections that don't start with `MAGIC` speak the legacy protocol of single byte opcodes.
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{self, Read, Write, ErrorKind as IoErrorKind};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+pub const MAGIC: &[u8] = b"WGCP";
+pub const VERSION: u32 = 1;
+/// Frames larger than this are rejected
+pub const MAX_FRAME_LEN: u32 = 1 << 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// The highest protocol version the client speaks
+    Hello { version: u32 },
+    Request { id: u64, command: Command },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DriverMessage {
+    /// The protocol version used for this connection
+    Hello { version: u32 },
+    Result { id: u64, result: Result<Reply, Error> },
+    Event { event: Event },
+    /// An error that can't be attributed to a request, e.g. because the request was unreadable
+    Error { error: Error },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    Attach { mode: AttachMode },
+    Detach,
+    /// Starts shutting down Windows. The connection receives progress events until Windows is down.
+    Shutdown,
+    /// Completes once Windows is suspended
+    Suspend,
+    Pause,
+    Resume,
+    /// Completes once the VM state is on disk
+    Save,
+    /// Completes once the disks are redirected to snapshot overlays
+    EnterBackupMode,
+    /// Completes once the snapshot overlays are committed
+    LeaveBackupMode,
+    /// Attaches input until the mouse leaves the screen at an edge, which is reported as an event
+    TemporaryLightEntry { x: i32, y: i32 },
+    Status,
+    /// Sends a status event right away and another one whenever the status changes
+    Subscribe,
+    /// Executes a raw QMP command, if the driver's `qmp_commands` allow it
+    Qmp {
+        execute: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        arguments: Option<Value>,
+    },
+    /// Sends every QMP event qemu emits from now on
+    QmpEvents,
+    /// Sends the events the driver understands from now on, see `QemuEvent`
+    QemuEvents,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttachMode {
+    /// Full entry if the guest agent is up, light entry (upgraded later) otherwise
+    Auto,
+    /// Full entry if the guest agent is up, nothing otherwise
+    Try,
+    /// Full entry regardless of the guest agent
+    Force,
+    Light,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+pub enum Reply {
+    Done,
+    Status {
+        status: Status,
+        /// Seconds since qemu started
+        uptime: u64,
+    },
+    /// What qemu returned for a `Qmp` command
+    Qmp { result: Value },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    ShutdownProgress { stage: ShutdownStage },
+    MouseEdged { x: i32, y: i32 },
+    TemporaryLightAttached,
+    TemporaryLightDetached,
+    Status { status: Status },
+    /// A QMP event just as qemu sent it
+    Qmp { qmp: Value },
+    Qemu { qemu: QemuEvent },
+}
+
+/// A QMP event the driver understands, named like the QMP event (e.g. `DEVICE_DELETED`).
+///
+/// Enum values like `reason` use qemu's names as well.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "name", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum QemuEvent {
+    Shutdown { guest: bool, reason: String },
+    Reset { guest: bool, reason: String },
+    Powerdown,
+    Stop,
+    Resume,
+    Suspend,
+    Wakeup,
+    GuestPanicked { action: String },
+    Watchdog { action: String },
+    /// `device` is the id given to `device_add`, if there was one
+    DeviceDeleted { device: Option<String>, path: String },
+    /// Windows changed the RTC, `offset` is its distance to the host clock in seconds
+    RtcChange { offset: i64 },
+    /// `actual` is the memory the guest has now, in bytes
+    BalloonChange { actual: i64 },
+    BlockJobReady { device: String },
+    BlockJobCompleted { device: String, error: Option<String> },
+    BlockJobCancelled { device: String },
+    BlockJobError { device: String, operation: String, action: String },
+}
+
+impl QemuEvent {
+    /// The name of the QMP event
+    pub fn name(&self) -> &'static str {
+        match *self {
+            QemuEvent::Shutdown { .. } => "SHUTDOWN",
+            QemuEvent::Reset { .. } => "RESET",
+            QemuEvent::Powerdown => "POWERDOWN",
+            QemuEvent::Stop => "STOP",
+            QemuEvent::Resume => "RESUME",
+            QemuEvent::Suspend => "SUSPEND",
+            QemuEvent::Wakeup => "WAKEUP",
+            QemuEvent::GuestPanicked { .. } => "GUEST_PANICKED",
+            QemuEvent::Watchdog { .. } => "WATCHDOG",
+            QemuEvent::DeviceDeleted { .. } => "DEVICE_DELETED",
+            QemuEvent::RtcChange { .. } => "RTC_CHANGE",
+            QemuEvent::BalloonChange { .. } => "BALLOON_CHANGE",
+            QemuEvent::BlockJobReady { .. } => "BLOCK_JOB_READY",
+            QemuEvent::BlockJobCompleted { .. } => "BLOCK_JOB_COMPLETED",
+            QemuEvent::BlockJobCancelled { .. } => "BLOCK_JOB_CANCELLED",
+            QemuEvent::BlockJobError { .. } => "BLOCK_JOB_ERROR",
+        }
+    }
+}
+
+/// What the guest agent is up to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GaStatus {
+    Down,
+    Up,
+    Suspending,
+    Suspended,
+    Resuming,
+}
+
+/// Which devices Windows currently gets, without the details of how we got there.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IoMode {
+    Detached,
+    LightEntry,
+    FullEntry,
+}
+
+/// A snapshot of what the driver is doing, published whenever any of it changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub ga: GaStatus,
+    pub io: IoMode,
+    /// USB devices we attached through full entry
+    pub usb_devices: Vec<UsbDevice>,
+    pub paused: bool,
+    /// Disks are redirected to snapshot overlays
+    pub backup: bool,
+    pub shutting_down: bool,
+}
+
+/// A USB device attached to qemu.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UsbDevice {
+    /// qemu's id for it
+    pub id: String,
+    /// How the config finds it, e.g. `id 046d:c52b` or `port 3.1`
+    pub binding: String,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if self.shutting_down {
+            return f.write_str("Shutting down ...");
+        }
+
+        f.write_str(match (self.paused, self.ga) {
+            (true, _) => "Paused",
+            (false, GaStatus::Down) => "Running, guest agent down",
+            (false, GaStatus::Up) => "Running",
+            (false, GaStatus::Suspending) => "Suspending",
+            (false, GaStatus::Suspended) => "Suspended",
+            (false, GaStatus::Resuming) => "Waking up",
+        })?;
+        match self.io {
+            IoMode::Detached => (),
+            IoMode::LightEntry => f.write_str(", light entry")?,
+            IoMode::FullEntry => {
+                write!(f, ", {} USB device(s) attached", self.usb_devices.len())?;
+                for (i, dev) in self.usb_devices.iter().enumerate() {
+                    write!(f, "{}{} ({})", if i == 0 { ": " } else { ", " }, dev.id, dev.binding)?;
+                }
+            }
+        }
+        if self.backup {
+            f.write_str(", backup in progress")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownStage {
+    /// Asked the guest agent to shut down
+    GuestAgent,
+    /// Sent an ACPI powerdown
+    Acpi,
+    /// Told qemu to quit
+    Quit,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    UnsupportedVersion,
+    /// The request was malformed or not allowed right now
+    InvalidRequest,
+    /// The driver won't do it in the current state
+    Refused,
+    /// The driver tried but failed
+    Failed,
+    /// The client may not send this request
+    PermissionDenied,
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(kind: ErrorKind, message: S) -> Error {
+        Error { kind, message: message.into() }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Writes `msg` as a single frame.
+pub fn write_frame<W: Write, T: Serialize>(w: &mut W, msg: &T) -> io::Result<()> {
+    w.write_all(&encode_frame(msg)?)?;
+    w.flush()
+}
+
+/// `msg` as a single frame, length prefix included.
+pub fn encode_frame<T: Serialize>(msg: &T) -> io::Result<Vec<u8>> {
+    let json = serde_json::to_vec(msg)?;
+    let mut frame = Vec::with_capacity(4 + json.len());
+    frame.extend_from_slice(&(json.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&json);
+    Ok(frame)
+}
+
+/// Reads a single frame, `None` on a clean end of file.
+pub fn read_frame<R: Read, T: DeserializeOwned>(r: &mut R) -> io::Result<Option<T>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Err(ref e) if e.kind() == IoErrorKind::UnexpectedEof => return Ok(None),
+        x => x?,
+    }
+    let mut buf = vec![0; frame_len(len)?];
+    r.read_exact(&mut buf)?;
+    Ok(Some(serde_json::from_slice(&buf)?))
+}
+
+/// Checks the length prefix of a frame.
+pub(crate) fn frame_len(prefix: [u8; 4]) -> io::Result<usize> {
+    let len = u32::from_be_bytes(prefix);
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(IoErrorKind::InvalidData, format!("frame of {} bytes is too large", len)));
+    }
+    Ok(len as usize)
+}
@@ -1,5 +1,6 @@
+use std::collections::BTreeMap;
 use std::path::Path;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write};
 use std::fmt::{Display, Formatter, Result as FmtResult};
 
@@ -84,6 +85,10 @@ pub struct HotKey {
 pub enum HotKeyAction {
     Exec(String),
     Action(Action),
+    /// Runs the actions one after another
+    Sequence(Vec<HotKeyAction>),
+    /// Waits this many milliseconds before the rest of a sequence runs
+    Delay(u64),
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Copy)]
@@ -91,6 +96,17 @@ pub enum Action {
     IoUpgrade,
     IoEntryForced,
     IoExit,
+    LightEntry,
+    /// Detaches if anything is attached, attaches like `IoUpgrade` otherwise
+    ToggleAttach,
+    Suspend,
+    Shutdown,
+    Pause,
+    Resume,
+    Save,
+    BackupStart,
+    BackupStop,
+    SendCtrlAltDel,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
@@ -102,18 +118,286 @@ pub struct Config {
     pub additional_qemu_cmdline: Option<String>,
     pub runtime_directory_override: Option<String>,
     pub data_directory_override: Option<String>,
+    pub state_directory_override: Option<String>,
     pub tpm_state_folder: Option<String>,
     pub hooks: HooksConfig,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub idle_inhibit: IdleInhibitConfig,
+    #[serde(default)]
+    pub restart: RestartConfig,
+    #[serde(default)]
+    pub watchdog: WatchdogConfig,
+    #[serde(default)]
+    pub access: AccessConfig,
 }
 
-// TODO: maybe using run-parts would be a more flexible solution here?
-#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+/// What qemu does when the emulated watchdog fires.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    Reset,
+    Shutdown,
+    Poweroff,
+    Pause,
+    Debug,
+    None,
+    InjectNmi,
+}
+
+impl Display for WatchdogAction {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(match self {
+            &WatchdogAction::Reset => "reset",
+            &WatchdogAction::Shutdown => "shutdown",
+            &WatchdogAction::Poweroff => "poweroff",
+            &WatchdogAction::Pause => "pause",
+            &WatchdogAction::Debug => "debug",
+            &WatchdogAction::None => "none",
+            &WatchdogAction::InjectNmi => "inject-nmi",
+        })
+    }
+}
+
+/// How we notice that Windows hangs.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    /// Adds an emulated i6300esb watchdog that triggers this action. Windows needs a driver for it to be armed.
+    pub action: Option<WatchdogAction>,
+    /// Seconds between two pings of the guest agent.
+    pub ga_ping_interval: u64,
+    /// How many pings in a row the guest agent may miss before we consider it dead.
+    pub ga_missed_pings: u32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> WatchdogConfig {
+        WatchdogConfig {
+            action: None,
+            ga_ping_interval: 5,
+            ga_missed_pings: 1,
+        }
+    }
+}
+
+/// Who may do what through the control socket.
+///
+/// The user running the driver and root may always do everything. Everyone else gets the permissions of all rules
+/// matching their uid or one of their groups, which is nothing if there are no rules.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct AccessConfig {
+    pub rules: Vec<AccessRule>,
+    /// The raw QMP commands anyone (the driver's user included) may execute. A trailing `*` matches any suffix,
+    /// so `["*"]` allows everything.
+    pub qmp_commands: Vec<String>,
+}
+
+impl Default for AccessConfig {
+    fn default() -> AccessConfig {
+        AccessConfig {
+            rules: Vec::new(),
+            // looking is fine, touching needs to be allowed explicitly
+            qmp_commands: vec!["query-*".to_owned()],
+        }
+    }
+}
+
+/// Grants `permissions` to the user `uid` and/or members of the group `gid`.
+///
+/// A rule without `uid` and `gid` applies to everyone.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccessRule {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Query and subscribe to the driver status
+    Status,
+    /// Attach and detach input devices, including temporary light entry
+    Attach,
+    /// Suspend, pause and resume the VM
+    Suspend,
+    /// Shut down the VM or save it to disk
+    Shutdown,
+    /// Enter and leave backup mode
+    Backup,
+    /// Execute raw QMP commands (within `qmp_commands`) and watch QMP events
+    Qmp,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Stop the driver whenever qemu goes down
+    Never,
+    /// Start Windows again after a guest crash (or qemu dying unexpectedly)
+    OnCrash,
+    /// Start Windows again unless we were told to shut it down
+    Always,
+}
+
+/// Whether (and how quickly) to start Windows again once it went down.
+///
+/// Restarts back off exponentially from `backoff_initial` up to `backoff_max` seconds. A run that stayed up for
+/// `backoff_reset` seconds resets the backoff.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct RestartConfig {
+    pub policy: RestartPolicy,
+    pub backoff_initial: u64,
+    pub backoff_max: u64,
+    pub backoff_reset: u64,
+}
+
+impl Default for RestartConfig {
+    fn default() -> RestartConfig {
+        RestartConfig {
+            policy: RestartPolicy::Never,
+            backoff_initial: 5,
+            backoff_max: 300,
+            backoff_reset: 600,
+        }
+    }
+}
+
+/// Whether to keep the host from going idle (screen blanking, locking) while Windows has our input devices.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct IdleInhibitConfig {
+    pub light_entry: bool,
+    pub full_entry: bool,
+}
+
+impl Default for IdleInhibitConfig {
+    fn default() -> IdleInhibitConfig {
+        IdleInhibitConfig {
+            light_entry: true,
+            full_entry: true,
+        }
+    }
+}
+
+/// How hard we try to shut Windows down before pulling the plug.
+///
+/// A shutdown starts with the guest agent (or ACPI if the GA is down). If the guest is still running after the
+/// respective timeout, we escalate to ACPI powerdown and finally to quitting qemu.
+/// A timeout of `None` waits forever at that stage.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// Seconds to wait after asking the guest agent before sending an ACPI powerdown.
+    pub guest_agent_timeout: Option<u64>,
+    /// Seconds to wait after the ACPI powerdown before quitting qemu.
+    pub acpi_timeout: Option<u64>,
+    /// Seconds we hold up a host shutdown while Windows shuts down. `None` waits until qemu is gone.
+    ///
+    /// Note that logind never waits longer than its `InhibitDelayMaxSec`, so you probably want to raise that as well.
+    pub host_max_delay: Option<u64>,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> ShutdownConfig {
+        ShutdownConfig {
+            guest_agent_timeout: Some(120),
+            acpi_timeout: Some(60),
+            host_max_delay: Some(180),
+        }
+    }
+}
+
+/// Commands run when something happens to the VM.
+///
+/// Every hook gets a JSON description of the event and the driver status on stdin, and the most important parts of
+/// it in `WG_*` environment variables.
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(default)]
 pub struct HooksConfig {
+    /// Windows booted (or the guest agent restarted)
     pub ready: Option<String>,
     pub attach: Option<String>,
     pub detach: Option<String>,
+    /// qemu is gone and the driver is about to exit
     pub down: Option<String>,
+    pub ga_up: Option<String>,
+    pub ga_down: Option<String>,
+    pub suspend: Option<String>,
+    pub resume: Option<String>,
+    pub backup_start: Option<String>,
+    pub backup_stop: Option<String>,
+    pub guest_crash: Option<String>,
+    pub hotkey: Option<String>,
+    /// Commands to run on qemu events by event name, e.g. `DEVICE_DELETED`, or `*` for all of them.
+    ///
+    /// They get the event name in `WG_EVENT` and the whole event as JSON in `WG_EVENT_JSON`.
+    pub events: BTreeMap<String, String>,
+    /// A directory with a `<hook>.d` directory per hook, e.g. `ga-up.d` or `qemu-event.d`.
+    ///
+    /// Their executables run in lexical order after the command configured above, like with run-parts.
+    pub directory: Option<String>,
+    /// Seconds a hook may take before it is killed and considered failed, `None` waits forever.
+    pub timeout: Option<u64>,
+    /// Hooks the driver waits for, if one of them fails it doesn't go ahead.
+    ///
+    /// Only `attach`, `suspend` and `backup-start` run early enough to stop anything.
+    pub blocking: Vec<String>,
+}
+
+impl Default for HooksConfig {
+    fn default() -> HooksConfig {
+        HooksConfig {
+            ready: None,
+            attach: None,
+            detach: None,
+            down: None,
+            ga_up: None,
+            ga_down: None,
+            suspend: None,
+            resume: None,
+            backup_start: None,
+            backup_stop: None,
+            guest_crash: None,
+            hotkey: None,
+            events: BTreeMap::new(),
+            directory: None,
+            timeout: Some(30),
+            blocking: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct BackupConfig {
+    /// How many snapshot overlays to keep per disk.
+    /// Entering backup mode always adds a new overlay. If that pushes the chain above this limit,
+    /// the oldest overlay is committed into the base image.
+    pub keep_overlays: usize,
+    /// Local time of day (`HH:MM`) at which the running driver enters backup mode on its own.
+    pub schedule: Option<String>,
+    /// Leave backup mode again once the post hook of a scheduled backup has finished.
+    pub leave_after_post_hook: bool,
+    /// Runs (and is waited for) before a scheduled backup. A failing pre hook skips the backup.
+    pub pre_hook: Option<String>,
+    /// Runs (and is waited for) after a scheduled backup entered backup mode.
+    pub post_hook: Option<String>,
+}
+
+impl Default for BackupConfig {
+    fn default() -> BackupConfig {
+        BackupConfig {
+            keep_overlays: 1,
+            schedule: None,
+            leave_after_post_hook: false,
+            pre_hook: None,
+            post_hook: None,
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -241,8 +525,21 @@ pub struct MachineConfig {
     pub network: Option<NetworkConfig>,
     pub storage: Vec<StorageDevice>,
     pub usb_devices: Vec<UsbDevice>,
+    /// Seconds qemu gets to remove the USB devices on detach before we report them as stuck, `None` waits forever.
+    #[serde(default = "machineconfig_usb_detach_timeout_default")]
+    pub usb_detach_timeout: Option<u64>,
     #[serde(default = "machineconfig_hotkeys_default")]
     pub hotkeys: Vec<HotKey>,
+    /// Hotkeys we catch on `host_keyboards` ourselves, only while detached
+    #[serde(default)]
+    pub host_hotkeys: Vec<HotKey>,
+    /// evdev nodes like `/dev/input/by-id/...-event-kbd` to watch for `host_hotkeys`, we don't grab them
+    #[serde(default)]
+    pub host_keyboards: Vec<String>,
+}
+
+fn machineconfig_usb_detach_timeout_default() -> Option<u64> {
+    Some(10)
 }
 
 fn machineconfig_hotkeys_default() -> Vec<HotKey> {
@@ -266,6 +563,70 @@ pub struct StorageDevice {
     pub snapshot_file: Option<String>,
 }
 
+/// A single snapshot overlay of a `StorageDevice`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotOverlay {
+    pub generation: u32,
+    pub path: String,
+}
+
+impl StorageDevice {
+    /// Path of the overlay with the given generation.
+    ///
+    /// Generation 0 lives at `snapshot_file` itself so single-overlay setups keep working,
+    /// later generations get a `.N` suffix.
+    pub fn snapshot_path(&self, generation: u32) -> Option<String> {
+        self.snapshot_file.as_ref().map(|s| match generation {
+            0 => s.clone(),
+            n => format!("{}.{}", s, n),
+        })
+    }
+
+    /// All snapshot overlays that currently exist on disk, oldest first.
+    pub fn snapshot_chain(&self) -> Vec<SnapshotOverlay> {
+        let snap = match self.snapshot_file {
+            Some(ref s) => Path::new(s),
+            None => return Vec::new(),
+        };
+        let mut chain = Vec::new();
+        if snap.exists() {
+            chain.push(SnapshotOverlay { generation: 0, path: snap.to_string_lossy().into_owned() });
+        }
+
+        let prefix = match snap.file_name().and_then(|x| x.to_str()) {
+            Some(x) => format!("{}.", x),
+            None => return chain,
+        };
+        let dir = match snap.parent() {
+            Some(x) if x.as_os_str().is_empty() => Path::new("."),
+            Some(x) => x,
+            None => return chain,
+        };
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.filter_map(Result::ok) {
+                let name = entry.file_name();
+                let generation = name.to_str()
+                    .and_then(|n| n.strip_prefix(prefix.as_str()))
+                    .and_then(|n| n.parse().ok());
+                if let Some(generation) = generation {
+                    chain.push(SnapshotOverlay {
+                        generation,
+                        path: self.snapshot_path(generation).unwrap(),
+                    });
+                }
+            }
+        }
+        chain.sort_by_key(|x| x.generation);
+        chain
+    }
+
+    /// The overlay that entering backup mode would create next.
+    pub fn next_snapshot(&self) -> Option<SnapshotOverlay> {
+        let generation = self.snapshot_chain().last().map(|x| x.generation + 1).unwrap_or(0);
+        self.snapshot_path(generation).map(|path| SnapshotOverlay { generation, path })
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct VfioDevice {
     pub resettable: bool,
@@ -312,6 +673,14 @@ impl PartialEq<UsbBinding> for UsbPort {
     }
 }
 
+impl Display for UsbBinding {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            UsbBinding::ById(ref id) => write!(f, "id {}", id),
+            UsbBinding::ByPort(ref port) => write!(f, "port {}", port),
+        }
+    }
+}
 impl Display for PciId {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         write!(f, "{:04x}:{:04x}", self.vendor, self.device)
@@ -337,7 +706,10 @@ impl Config {
         writeln!(file, "{}", contents).expect("Failed to write config file");
     }
 
-    pub fn load<P: AsRef<Path>>(path: P) -> Option<Config> {
+    /// Loads the config at `path`, upgrading an old-style TOML config along the way.
+    ///
+    /// `None` if there is no config yet, errors say what is wrong with it, e.g. which key names are misspelled.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Config>, String> {
         let yaml_path = path.as_ref().with_extension("yml");
         let file_path;
         let needs_upgrade = !yaml_path.exists();
@@ -348,22 +720,112 @@ impl Config {
         }
 
         if !file_path.exists() {
-            return None;
+            return Ok(None);
         }
 
         let mut config = String::new();
         {
-            let mut config_file = File::open(file_path).expect("Failed to open config file");
-            config_file.read_to_string(&mut config).expect("Failed to read config file");
+            let mut config_file = File::open(&file_path)
+                .map_err(|e| format!("can't open {}: {}", file_path.display(), e))?;
+            config_file.read_to_string(&mut config)
+                .map_err(|e| format!("can't read {}: {}", file_path.display(), e))?;
         }
 
-        Some(if needs_upgrade {
+        let cfg: Config = if needs_upgrade {
+            toml::from_str(&config).map_err(|e| format!("{}: {}", file_path.display(), e))?
+        } else {
+            serde_yaml::from_str(&config).map_err(|e| format!("{}: {}", file_path.display(), e))?
+        };
+        cfg.validate().map_err(|e| format!("{}: {}", file_path.display(), e))?;
+        if needs_upgrade {
             // old-style toml config - upgrade it
-            let cfg: Config = toml::from_str(&config).expect("Failed to decode old-style TOML config");
             cfg.save(yaml_path);
-            cfg
-        } else {
-            serde_yaml::from_str(&config).expect("Failed to decode config")
-        })
+        }
+        Ok(Some(cfg))
+    }
+
+    /// Checks what deserializing alone doesn't.
+    fn validate(&self) -> Result<(), String> {
+        // the newest overlay is the one Windows writes to, it can't be committed away
+        if self.backup.keep_overlays < 1 {
+            return Err(format!("backup.keep_overlays must be at least 1, got {}", self.backup.keep_overlays));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    fn disk(snapshot_file: Option<String>) -> StorageDevice {
+        StorageDevice {
+            path: "/dev/tank/windows".to_owned(),
+            cache: "none".to_owned(),
+            format: "raw".to_owned(),
+            snapshot_file,
+        }
+    }
+
+    #[test]
+    fn snapshot_chain() {
+        let dir = env::temp_dir().join(format!("windows-gaming-snapshots-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let snap = dir.join("windows.qcow2").to_string_lossy().into_owned();
+        let disk = disk(Some(snap.clone()));
+
+        assert!(disk.snapshot_chain().is_empty());
+        assert_eq!(disk.next_snapshot(), Some(SnapshotOverlay { generation: 0, path: snap.clone() }));
+
+        // generations sort by number, not by name, and other files don't count
+        for name in &["windows.qcow2", "windows.qcow2.10", "windows.qcow2.2", "windows.qcow2.bak", "windows.qcow2.3x",
+                      "other.qcow2.4"] {
+            File::create(dir.join(name)).unwrap();
+        }
+        let generations: Vec<_> = disk.snapshot_chain().iter().map(|o| o.generation).collect();
+        assert_eq!(generations, [0, 2, 10]);
+        assert_eq!(disk.snapshot_chain()[1].path, format!("{}.2", snap));
+        assert_eq!(disk.next_snapshot(), Some(SnapshotOverlay { generation: 11, path: format!("{}.11", snap) }));
+
+        // the oldest ones were committed already
+        fs::remove_file(&snap).unwrap();
+        fs::remove_file(format!("{}.2", snap)).unwrap();
+        let generations: Vec<_> = disk.snapshot_chain().iter().map(|o| o.generation).collect();
+        assert_eq!(generations, [10]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_errors() {
+        let dir = env::temp_dir().join(format!("windows-gaming-config-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config");
+
+        assert_eq!(Config::load(&path).map(|cfg| cfg.is_none()), Ok(true));
+        fs::write(path.with_extension("yml"), "machine: [").unwrap();
+        let e = Config::load(&path).map(|_| ()).unwrap_err();
+        assert!(e.contains("config.yml"), "{}", e);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keep_overlays() {
+        let mut cfg = Config::default();
+        assert_eq!(cfg.validate(), Ok(()));
+        cfg.backup.keep_overlays = 0;
+        let e = cfg.validate().unwrap_err();
+        assert!(e.contains("backup.keep_overlays"), "{}", e);
+    }
+
+    #[test]
+    fn no_snapshots() {
+        let disk = disk(None);
+        assert!(disk.snapshot_chain().is_empty());
+        assert_eq!(disk.next_snapshot(), None);
     }
 }
@@ -137,6 +137,119 @@ const KEY_PLAYPAUSE: u32 = 164;
 const KEY_PREVIOUSSONG: u32 = 165;
 const KEY_STOPCD: u32 = 166;
 
+/// Names of the keys above as in `KEY_*`, lowercase and without the prefix.
+pub const NAMES: &[(&str, u32)] = &[
+    ("esc", KEY_ESC),
+    ("1", KEY_1),
+    ("2", KEY_2),
+    ("3", KEY_3),
+    ("4", KEY_4),
+    ("5", KEY_5),
+    ("6", KEY_6),
+    ("7", KEY_7),
+    ("8", KEY_8),
+    ("9", KEY_9),
+    ("0", KEY_0),
+    ("minus", KEY_MINUS),
+    ("equal", KEY_EQUAL),
+    ("backspace", KEY_BACKSPACE),
+    ("tab", KEY_TAB),
+    ("q", KEY_Q),
+    ("w", KEY_W),
+    ("e", KEY_E),
+    ("r", KEY_R),
+    ("t", KEY_T),
+    ("y", KEY_Y),
+    ("u", KEY_U),
+    ("i", KEY_I),
+    ("o", KEY_O),
+    ("p", KEY_P),
+    ("leftbrace", KEY_LEFTBRACE),
+    ("rightbrace", KEY_RIGHTBRACE),
+    ("enter", KEY_ENTER),
+    ("leftctrl", KEY_LEFTCTRL),
+    ("a", KEY_A),
+    ("s", KEY_S),
+    ("d", KEY_D),
+    ("f", KEY_F),
+    ("g", KEY_G),
+    ("h", KEY_H),
+    ("j", KEY_J),
+    ("k", KEY_K),
+    ("l", KEY_L),
+    ("semicolon", KEY_SEMICOLON),
+    ("apostrophe", KEY_APOSTROPHE),
+    ("grave", KEY_GRAVE),
+    ("leftshift", KEY_LEFTSHIFT),
+    ("backslash", KEY_BACKSLASH),
+    ("z", KEY_Z),
+    ("x", KEY_X),
+    ("c", KEY_C),
+    ("v", KEY_V),
+    ("b", KEY_B),
+    ("n", KEY_N),
+    ("m", KEY_M),
+    ("comma", KEY_COMMA),
+    ("dot", KEY_DOT),
+    ("slash", KEY_SLASH),
+    ("rightshift", KEY_RIGHTSHIFT),
+    ("kpasterisk", KEY_KPASTERISK),
+    ("leftalt", KEY_LEFTALT),
+    ("space", KEY_SPACE),
+    ("capslock", KEY_CAPSLOCK),
+    ("f1", KEY_F1),
+    ("f2", KEY_F2),
+    ("f3", KEY_F3),
+    ("f4", KEY_F4),
+    ("f5", KEY_F5),
+    ("f6", KEY_F6),
+    ("f7", KEY_F7),
+    ("f8", KEY_F8),
+    ("f9", KEY_F9),
+    ("f10", KEY_F10),
+    ("numlock", KEY_NUMLOCK),
+    ("scrolllock", KEY_SCROLLLOCK),
+    ("kp7", KEY_KP7),
+    ("kp8", KEY_KP8),
+    ("kp9", KEY_KP9),
+    ("kpminus", KEY_KPMINUS),
+    ("kp4", KEY_KP4),
+    ("kp5", KEY_KP5),
+    ("kp6", KEY_KP6),
+    ("kpplus", KEY_KPPLUS),
+    ("kp1", KEY_KP1),
+    ("kp2", KEY_KP2),
+    ("kp3", KEY_KP3),
+    ("kp0", KEY_KP0),
+    ("kpdot", KEY_KPDOT),
+    ("102nd", KEY_102ND),
+    ("f11", KEY_F11),
+    ("f12", KEY_F12),
+    ("kpenter", KEY_KPENTER),
+    ("rightctrl", KEY_RIGHTCTRL),
+    ("kpslash", KEY_KPSLASH),
+    ("sysrq", KEY_SYSRQ),
+    ("rightalt", KEY_RIGHTALT),
+    ("home", KEY_HOME),
+    ("up", KEY_UP),
+    ("pageup", KEY_PAGEUP),
+    ("left", KEY_LEFT),
+    ("right", KEY_RIGHT),
+    ("end", KEY_END),
+    ("down", KEY_DOWN),
+    ("pagedown", KEY_PAGEDOWN),
+    ("insert", KEY_INSERT),
+    ("delete", KEY_DELETE),
+    ("pause", KEY_PAUSE),
+    ("leftmeta", KEY_LEFTMETA),
+    ("rightmeta", KEY_RIGHTMETA),
+    ("compose", KEY_COMPOSE),
+    ("nextsong", KEY_NEXTSONG),
+    ("playpause", KEY_PLAYPAUSE),
+    ("previoussong", KEY_PREVIOUSSONG),
+    ("stopcd", KEY_STOPCD),
+];
+
 pub fn key_convert(code: u32) -> Option<Key> {
     Some(match code {
         KEY_LEFTSHIFT => Key::LShiftKey,
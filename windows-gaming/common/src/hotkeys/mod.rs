@@ -3,6 +3,9 @@ mod keys;
 pub use self::keys::Keys as Key;
 mod linux;
 mod qcode;
+mod names;
+
+use std::time::{Duration, Instant};
 
 #[repr(u32)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -14,6 +17,12 @@ pub enum Modifier {
 }
 const NOREPEAT: u32 = 0x4000;
 
+/// How long the next step of a sequence may take.
+const SEQUENCE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a binding that repeats has to be held until it does, like a key would.
+const REPEAT_DELAY: Duration = Duration::from_millis(500);
+const REPEAT_INTERVAL: Duration = Duration::from_millis(100);
+
 impl Key {
     fn modifier(&self) -> Option<Modifier> {
         Some(match *self {
@@ -26,69 +35,385 @@ impl Key {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct KeyBinding {
+/// Keys that are held together, e.g. `Ctrl+Alt+Insert`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Chord {
+    /// Either side will do
     modifiers: Vec<Modifier>,
-    no_repeat: bool, // FIXME: implement this
-    key: Key,
+    /// Modifier keys that only count on their side, like `RCtrl`
+    sided: Vec<Key>,
+    /// Never empty, whichever of them is pressed last triggers the chord
+    keys: Vec<Key>,
+}
+
+impl Chord {
+    /// Whether the chord is complete if the keys in `held` are down.
+    ///
+    /// When `exact`, nothing else may be held.
+    fn held(&self, held: &[Key], exact: bool) -> bool {
+        let wanted = |k: &Key| self.keys.contains(k) || self.sided.contains(k)
+            || k.modifier().is_some_and(|m| self.modifiers.contains(&m));
+        self.keys.iter().chain(&self.sided).all(|k| held.contains(k))
+            && self.modifiers.iter().all(|&m| held.iter().any(|k| k.modifier() == Some(m)))
+            && (!exact || held.iter().all(wanted))
+    }
+}
+
+/// Written as e.g. `Ctrl+Alt+Insert` in the config, see `names`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyBinding {
+    /// Pressed one after another, like `ScrollLock ScrollLock`
+    steps: Vec<Chord>,
+    /// Otherwise it fires again and again while held
+    no_repeat: bool,
+    /// Nothing but the chord may be held, otherwise extra modifiers and keys don't matter
+    exact: bool,
+    /// Fire when the key is released without anything pressed in between, e.g. for tapping `RCtrl`
+    on_release: bool,
 }
 
 impl KeyBinding {
+    /// A binding the way they used to be, it doesn't mind extra modifiers and keys.
     pub fn new(modifiers: Vec<Modifier>, key: Key, no_repeat: bool) -> KeyBinding {
-        KeyBinding { modifiers, no_repeat, key }
+        KeyBinding {
+            steps: vec![Chord { modifiers, sided: Vec::new(), keys: vec![key] }],
+            no_repeat,
+            exact: false,
+            on_release: false,
+        }
     }
 
-    pub fn matches(&self, modifiers: &[Modifier], key: Key) -> bool {
-        key == self.key && self.modifiers.iter().all(|x| modifiers.contains(x))
+    /// Whether pressing `key` while `held` (`key` included) completes step `step`.
+    fn triggers(&self, step: usize, key: Key, held: &[Key]) -> bool {
+        let chord = &self.steps[step];
+        chord.keys.contains(&key) && chord.held(held, self.exact)
     }
 
-    pub fn to_windows(&self) -> (u32, u32) {
+    /// What to register with Windows, if it can catch this binding at all.
+    ///
+    /// Windows always wants exactly the modifiers, but it can't tell left from right,
+    /// nor do chords, sequences or releases.
+    pub fn to_windows(&self) -> Option<(u32, u32)> {
+        if self.steps.len() != 1 || self.on_release {
+            return None;
+        }
+        let chord = &self.steps[0];
+        if !chord.sided.is_empty() || chord.keys.len() != 1 || chord.keys[0].modifier().is_some() {
+            return None;
+        }
         let base = if self.no_repeat { NOREPEAT } else { 0 };
-        (self.modifiers.iter().fold(base, |sum, &x| (sum | (x as u32))), self.key as u32)
+        Some((chord.modifiers.iter().fold(base, |sum, &x| sum | (x as u32)), chord.keys[0] as u32))
     }
 }
 
 pub struct KeyResolution {
+    /// The bindings that fired
     pub hotkeys: Vec<usize>,
+    /// Whether the key press belongs to a binding, so it shouldn't go anywhere else
+    pub consumed: bool,
     pub qcode: Option<&'static str>,
 }
 
 pub struct KeyboardState<'a> {
-    modifiers: Vec<Modifier>,
     bindings: &'a [KeyBinding],
+    held: Vec<Key>,
+    /// Bindings in the middle of a sequence: the step they're at and until when it has to come
+    sequences: Vec<(usize, usize, Instant)>,
+    /// Bindings that fire once the key is released
+    armed: Vec<(usize, Key)>,
+    /// Bindings that repeat while the key stays held and when they do next
+    repeating: Option<(Vec<usize>, Key, Instant)>,
 }
 
 impl<'a> KeyboardState<'a> {
-    pub fn new(bindings: &'a [KeyBinding]) -> KeyboardState {
+    pub fn new(bindings: &'a [KeyBinding]) -> KeyboardState<'a> {
         KeyboardState {
-            modifiers: Vec::new(),
             bindings,
+            held: Vec::new(),
+            sequences: Vec::new(),
+            armed: Vec::new(),
+            repeating: None,
         }
     }
 
     pub fn input_linux(&mut self, code: u32, down: bool) -> Option<KeyResolution> {
-        linux::key_convert(code).map(|k| {
-            let mut bindings = Vec::new();
-            if let Some(m) = k.modifier() {
-                if down {
-                    if !self.modifiers.contains(&m) {
-                        self.modifiers.push(m);
-                    }
-                } else {
-                    if let Some(i) = self.modifiers.iter().position(|&x| x == m) {
-                        self.modifiers.swap_remove(i);
-                    }
-                }
-            } else if down {
-                bindings.extend(self.bindings.iter().enumerate()
-                                .filter(|&(_, b)| b.matches(&self.modifiers, k))
-                                .map(|(i, _)| i));
-            }
+        self.input_linux_at(code, down, Instant::now())
+    }
 
+    /// Like `input_linux`, for input that happened at `now`.
+    pub fn input_linux_at(&mut self, code: u32, down: bool, now: Instant) -> Option<KeyResolution> {
+        linux::key_convert(code).map(|k| {
+            let hotkeys = if down { self.press(k, now) } else { self.release(k) };
             KeyResolution {
-                hotkeys: bindings,
+                // releases aren't, whoever got the press needs them
+                consumed: down && !hotkeys.is_empty(),
+                hotkeys,
                 qcode: qcode::key_convert(k),
             }
         })
     }
+
+    /// Forgets keys that aren't among the Linux keycodes `down` anymore.
+    ///
+    /// For when someone else grabbed the keyboard and we missed the releases.
+    pub fn sync_linux(&mut self, down: &[u32]) {
+        let down: Vec<_> = down.iter().filter_map(|&code| linux::key_convert(code)).collect();
+        self.held.retain(|k| down.contains(k));
+        self.armed.retain(|&(_, k)| down.contains(&k));
+        if self.repeating.as_ref().is_some_and(|&(_, k, _)| !down.contains(&k)) {
+            self.repeating = None;
+        }
+    }
+
+    /// When held bindings fire again, see `repeat`.
+    pub fn next_repeat(&self) -> Option<Instant> {
+        self.repeating.as_ref().map(|&(_, _, at)| at)
+    }
+
+    /// The bindings held long enough to fire again by `now`.
+    pub fn repeat(&mut self, now: Instant) -> Vec<usize> {
+        if let Some((ref bindings, _, ref mut at)) = self.repeating {
+            if *at <= now {
+                *at = now + REPEAT_INTERVAL;
+                return bindings.clone();
+            }
+        }
+        Vec::new()
+    }
+
+    fn press(&mut self, key: Key, now: Instant) -> Vec<usize> {
+        if self.held.contains(&key) {
+            // the keyboard repeating, we do that ourselves
+            return Vec::new();
+        }
+        self.held.push(key);
+        self.armed.clear();
+        self.repeating = None;
+
+        let bindings = self.bindings;
+        let mut fired = Vec::new();
+        for (i, binding) in bindings.iter().enumerate() {
+            let pending = self.sequences.iter().position(|&(b, _, _)| b == i)
+                .map(|pos| self.sequences.swap_remove(pos))
+                .filter(|&(_, _, until)| now <= until);
+            let step = match pending {
+                Some((_, step, _)) if binding.triggers(step, key, &self.held) => step,
+                Some((_, step, until)) if key.modifier().is_some() => {
+                    // probably part of the next step
+                    self.sequences.push((i, step, until));
+                    continue;
+                }
+                // anything else starts the sequence over
+                _ if binding.triggers(0, key, &self.held) => 0,
+                _ => continue,
+            };
+
+            if step + 1 < binding.steps.len() {
+                self.sequences.push((i, step + 1, now + SEQUENCE_TIMEOUT));
+            } else if binding.on_release {
+                self.armed.push((i, key));
+            } else {
+                fired.push(i);
+            }
+        }
+
+        let repeating: Vec<_> = fired.iter().cloned().filter(|&i| !bindings[i].no_repeat).collect();
+        if !repeating.is_empty() {
+            self.repeating = Some((repeating, key, now + REPEAT_DELAY));
+        }
+        fired
+    }
+
+    fn release(&mut self, key: Key) -> Vec<usize> {
+        self.held.retain(|&k| k != key);
+        if self.repeating.as_ref().is_some_and(|&(_, k, _)| k == key) {
+            self.repeating = None;
+        }
+        let fired = self.armed.iter().filter(|&&(_, k)| k == key).map(|&(i, _)| i).collect();
+        self.armed.retain(|&(_, k)| k != key);
+        fired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn code(name: &str) -> u32 {
+        linux::NAMES.iter().find(|&&(n, _)| n == name).unwrap().1
+    }
+
+    fn bindings(bindings: &[&str]) -> Vec<KeyBinding> {
+        bindings.iter().map(|b| b.parse().unwrap()).collect()
+    }
+
+    /// Presses or releases `key`, returning the bindings that fired.
+    fn input(state: &mut KeyboardState, key: &str, down: bool, at: Instant) -> Vec<usize> {
+        state.input_linux_at(code(key), down, at).unwrap().hotkeys
+    }
+
+    /// Presses the keys in order, then releases them in reverse, returning what fired along the way.
+    fn tap(state: &mut KeyboardState, keys: &[&str], at: Instant) -> Vec<usize> {
+        let mut fired = Vec::new();
+        for key in keys {
+            fired.extend(input(state, key, true, at));
+        }
+        for key in keys.iter().rev() {
+            fired.extend(input(state, key, false, at));
+        }
+        fired
+    }
+
+    #[test]
+    fn exact_modifiers() {
+        let bindings = bindings(&["Ctrl+Alt+Insert", "Insert"]);
+        let mut state = KeyboardState::new(&bindings);
+        let now = Instant::now();
+        assert_eq!(tap(&mut state, &["leftctrl", "leftalt", "insert"], now), [0]);
+        assert_eq!(tap(&mut state, &["rightctrl", "rightalt", "insert"], now), [0]);
+        assert_eq!(tap(&mut state, &["insert"], now), [1]);
+        // too many modifiers, or too few
+        assert!(tap(&mut state, &["leftshift", "leftctrl", "leftalt", "insert"], now).is_empty());
+        assert!(tap(&mut state, &["leftctrl", "insert"], now).is_empty());
+        // nothing got stuck
+        assert_eq!(tap(&mut state, &["insert"], now), [1]);
+    }
+
+    #[test]
+    fn any_other_modifiers() {
+        let mut bindings = bindings(&["Ctrl+Insert"]);
+        bindings[0].exact = false;
+        let mut state = KeyboardState::new(&bindings);
+        let now = Instant::now();
+        assert_eq!(tap(&mut state, &["leftshift", "leftctrl", "insert"], now), [0]);
+        assert_eq!(tap(&mut state, &["a", "leftctrl", "insert"], now), [0]);
+        assert!(tap(&mut state, &["leftshift", "insert"], now).is_empty());
+    }
+
+    #[test]
+    fn left_and_right() {
+        let bindings = bindings(&["RCtrl+Insert", "LShift+Ctrl+Home"]);
+        let mut state = KeyboardState::new(&bindings);
+        let now = Instant::now();
+        assert_eq!(tap(&mut state, &["rightctrl", "insert"], now), [0]);
+        assert!(tap(&mut state, &["leftctrl", "insert"], now).is_empty());
+        assert_eq!(tap(&mut state, &["leftshift", "rightctrl", "home"], now), [1]);
+        assert!(tap(&mut state, &["rightshift", "rightctrl", "home"], now).is_empty());
+    }
+
+    #[test]
+    fn consumed_presses() {
+        let bindings = bindings(&["Ctrl+Insert"]);
+        let mut state = KeyboardState::new(&bindings);
+        let now = Instant::now();
+        let ctrl = state.input_linux_at(code("leftctrl"), true, now).unwrap();
+        assert!(!ctrl.consumed);
+        assert_eq!(ctrl.qcode, Some("ctrl"));
+        let insert = state.input_linux_at(code("insert"), true, now).unwrap();
+        assert!(insert.consumed);
+        assert_eq!(insert.hotkeys, [0]);
+        assert!(!state.input_linux_at(code("insert"), false, now).unwrap().consumed);
+        // keys we don't know about
+        assert!(state.input_linux_at(0x2ff, true, now).is_none());
+    }
+
+    #[test]
+    fn on_release() {
+        let mut bindings = bindings(&["RCtrl", "Ctrl+Insert"]);
+        bindings[0].on_release = true;
+        bindings[1].on_release = true;
+        let mut state = KeyboardState::new(&bindings);
+        let now = Instant::now();
+        assert!(input(&mut state, "rightctrl", true, now).is_empty());
+        assert_eq!(input(&mut state, "rightctrl", false, now), [0]);
+        // not when it was used for something else
+        assert!(tap(&mut state, &["rightctrl", "a"], now).is_empty());
+
+        assert!(input(&mut state, "leftctrl", true, now).is_empty());
+        let insert = state.input_linux_at(code("insert"), true, now).unwrap();
+        assert!(insert.hotkeys.is_empty() && !insert.consumed);
+        assert_eq!(input(&mut state, "insert", false, now), [1]);
+        assert!(input(&mut state, "leftctrl", false, now).is_empty());
+    }
+
+    #[test]
+    fn chords() {
+        let bindings = bindings(&["Ctrl+A+S"]);
+        let mut state = KeyboardState::new(&bindings);
+        let now = Instant::now();
+        assert_eq!(tap(&mut state, &["leftctrl", "a", "s"], now), [0]);
+        // in any order
+        assert_eq!(tap(&mut state, &["leftctrl", "s", "a"], now), [0]);
+        assert!(tap(&mut state, &["leftctrl", "s"], now).is_empty());
+        assert!(tap(&mut state, &["a", "s"], now).is_empty());
+        assert!(tap(&mut state, &["leftctrl", "d", "a", "s"], now).is_empty());
+    }
+
+    #[test]
+    fn sequences() {
+        let bindings = bindings(&["ScrollLock ScrollLock", "Ctrl+A Ctrl+B"]);
+        let mut state = KeyboardState::new(&bindings);
+        let now = Instant::now();
+        assert!(tap(&mut state, &["scrolllock"], now).is_empty());
+        assert_eq!(tap(&mut state, &["scrolllock"], now), [0]);
+        // the next one starts over
+        assert!(tap(&mut state, &["scrolllock"], now).is_empty());
+        // too late
+        assert!(tap(&mut state, &["scrolllock"], now + SEQUENCE_TIMEOUT * 2).is_empty());
+        // interrupted
+        assert!(tap(&mut state, &["a"], now + SEQUENCE_TIMEOUT * 2).is_empty());
+        assert!(tap(&mut state, &["scrolllock"], now + SEQUENCE_TIMEOUT * 2).is_empty());
+        assert_eq!(tap(&mut state, &["scrolllock"], now + SEQUENCE_TIMEOUT * 2), [0]);
+
+        // releasing and pressing the modifiers in between is fine
+        assert!(tap(&mut state, &["leftctrl", "a"], now).is_empty());
+        assert_eq!(tap(&mut state, &["leftctrl", "b"], now), [1]);
+        // so is keeping them held
+        assert!(input(&mut state, "leftctrl", true, now).is_empty());
+        assert!(tap(&mut state, &["a"], now).is_empty());
+        assert_eq!(tap(&mut state, &["b"], now), [1]);
+    }
+
+    #[test]
+    fn repeat() {
+        let mut bindings = bindings(&["Ctrl+Up", "Ctrl+Down"]);
+        bindings[0].no_repeat = false;
+        let mut state = KeyboardState::new(&bindings);
+        let now = Instant::now();
+        assert!(input(&mut state, "leftctrl", true, now).is_empty());
+        assert_eq!(input(&mut state, "up", true, now), [0]);
+        assert_eq!(state.next_repeat(), Some(now + REPEAT_DELAY));
+        assert!(state.repeat(now + REPEAT_DELAY / 2).is_empty());
+        assert_eq!(state.repeat(now + REPEAT_DELAY), [0]);
+        assert_eq!(state.next_repeat(), Some(now + REPEAT_DELAY + REPEAT_INTERVAL));
+        // the keyboard's own repeats don't count
+        assert!(input(&mut state, "up", true, now + REPEAT_DELAY).is_empty());
+        assert!(input(&mut state, "up", false, now + REPEAT_DELAY).is_empty());
+        assert_eq!(state.next_repeat(), None);
+
+        assert_eq!(input(&mut state, "down", true, now), [1]);
+        assert_eq!(state.next_repeat(), None);
+    }
+
+    #[test]
+    fn missed_releases() {
+        let bindings = bindings(&["Ctrl+Insert"]);
+        let mut state = KeyboardState::new(&bindings);
+        let now = Instant::now();
+        // someone grabbed the keyboard before these were released
+        input(&mut state, "leftshift", true, now);
+        input(&mut state, "leftctrl", true, now);
+        assert!(tap(&mut state, &["insert"], now).is_empty());
+        state.sync_linux(&[code("leftctrl")]);
+        assert_eq!(tap(&mut state, &["insert"], now), [0]);
+    }
+
+    #[test]
+    fn windows() {
+        let bindings = bindings(&["Ctrl+Alt+Insert", "RCtrl", "Ctrl+A+S"]);
+        let modifiers = NOREPEAT | Modifier::Ctrl as u32 | Modifier::Alt as u32;
+        assert_eq!(bindings[0].to_windows(), Some((modifiers, Key::Insert as u32)));
+        assert_eq!(bindings[1].to_windows(), None);
+        assert_eq!(bindings[2].to_windows(), None);
+    }
 }
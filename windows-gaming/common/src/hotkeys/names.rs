@@ -0,0 +1,391 @@
+// key bindings as text, like "Ctrl+Alt+Insert"
+use std::cmp;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, MapAccess, Visitor};
+use serde::de::value::MapAccessDeserializer;
+
+use super::{linux, qcode, Chord, Key, KeyBinding, Modifier};
+
+/// Names people are likely to write that none of the keycode tables use.
+const ALIASES: &[(&str, Key)] = &[
+    ("del", Key::Delete),
+    ("ins", Key::Insert),
+    ("return", Key::Enter),
+    ("pgdown", Key::PageDown),
+    ("print", Key::PrintScreen),
+    ("prtsc", Key::PrintScreen),
+    ("win", Key::LWin),
+    ("altgr", Key::RMenu),
+];
+
+/// Short names for modifier keys of one side, we also write them.
+const SIDED: &[(&str, Key)] = &[
+    ("LCtrl", Key::LControlKey),
+    ("RCtrl", Key::RControlKey),
+    ("LShift", Key::LShiftKey),
+    ("RShift", Key::RShiftKey),
+    ("LAlt", Key::LMenu),
+    ("RAlt", Key::RMenu),
+    ("LWin", Key::LWin),
+    ("RWin", Key::RWin),
+];
+
+/// Every name of every key we know, lowercase: Windows VK names, Linux `KEY_*` names and qemu qcodes.
+fn names() -> Vec<(String, Key)> {
+    let mut names = Vec::new();
+    for &(linux_name, code) in linux::NAMES {
+        if let Some(key) = linux::key_convert(code) {
+            names.push((format!("{:?}", key).to_lowercase(), key));
+            names.push((linux_name.to_owned(), key));
+            if let Some(qcode) = qcode::key_convert(key) {
+                names.push((qcode.to_owned(), key));
+            }
+        }
+    }
+    names.extend(ALIASES.iter().map(|&(name, key)| (name.to_owned(), key)));
+    names.extend(SIDED.iter().map(|&(name, key)| (name.to_lowercase(), key)));
+    names
+}
+
+fn key_name(key: Key) -> String {
+    match SIDED.iter().find(|&&(_, k)| k == key) {
+        Some(&(name, _)) => name.to_owned(),
+        None => format!("{:?}", key),
+    }
+}
+
+impl Key {
+    /// Looks up a key by any of its names, ignoring case and a `KEY_` prefix.
+    pub fn from_name(name: &str) -> Option<Key> {
+        let name = name.to_lowercase();
+        let name = name.strip_prefix("key_").unwrap_or(&name);
+        names().into_iter().find(|(n, _)| n == name).map(|(_, key)| key)
+    }
+
+    /// Keys with a name close to `name`, best match first.
+    fn suggestions(name: &str) -> Vec<Key> {
+        let name = name.to_lowercase();
+        let max_distance = cmp::max(1, name.len() / 3);
+        let mut candidates: Vec<_> = names().into_iter()
+            .filter_map(|(n, key)| {
+                let distance = distance(&name, &n);
+                if distance <= max_distance || (name.len() >= 2 && n.starts_with(&name)) {
+                    Some((distance, key))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        candidates.sort_by_key(|&(distance, _)| distance);
+        let mut keys = Vec::new();
+        for (_, key) in candidates {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        keys.truncate(3);
+        keys
+    }
+}
+
+impl Modifier {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Modifier::Alt => "Alt",
+            Modifier::Ctrl => "Ctrl",
+            Modifier::Shift => "Shift",
+            Modifier::Win => "Win",
+        }
+    }
+
+    /// Looks up a modifier by name, ignoring case.
+    ///
+    /// Names of modifier keys like `LeftCtrl` aren't modifiers, they only count on their side.
+    pub fn from_name(name: &str) -> Option<Modifier> {
+        Some(match &*name.to_lowercase() {
+            "alt" => Modifier::Alt,
+            "ctrl" | "control" => Modifier::Ctrl,
+            "shift" => Modifier::Shift,
+            "win" | "super" | "meta" | "logo" => Modifier::Win,
+            _ => return None,
+        })
+    }
+}
+
+/// Edit distance between `a` and `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = previous[j] + if ca == cb { 0 } else { 1 };
+            current.push(cmp::min(substitute, cmp::min(previous[j + 1], current[j]) + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn unknown_key(name: &str, binding: &str) -> String {
+    let mut message = format!("unknown key {:?} in {:?}", name, binding);
+    let suggestions: Vec<_> = Key::suggestions(name).into_iter().map(key_name).collect();
+    if !suggestions.is_empty() {
+        message += &format!(", did you mean {}?", suggestions.join(" or "));
+    }
+    message
+}
+
+fn unknown_modifier(name: &str, binding: &str) -> String {
+    let mut suggestions: Vec<_> = ["Ctrl", "Alt", "Shift", "Win"].iter()
+        .filter(|m| distance(&name.to_lowercase(), &m.to_lowercase()) <= 1)
+        .map(|&m| m.to_owned())
+        .collect();
+    suggestions.extend(Key::suggestions(name).into_iter().map(key_name));
+    if suggestions.is_empty() {
+        format!("{:?} in {:?} is not a modifier or key, try Ctrl, Alt, Shift or Win", name, binding)
+    } else {
+        format!("{:?} in {:?} is not a modifier or key, did you mean {}?", name, binding, suggestions.join(" or "))
+    }
+}
+
+/// Splits e.g. `Ctrl + A  B` into its steps `Ctrl+A` and `B`.
+fn steps(s: &str) -> Vec<String> {
+    let mut steps: Vec<String> = Vec::new();
+    for word in s.split_whitespace() {
+        let continues = steps.last().is_some_and(|step| step.ends_with('+')) || word.starts_with('+');
+        if continues && !steps.is_empty() {
+            steps.last_mut().unwrap().push_str(word);
+        } else {
+            steps.push(word.to_owned());
+        }
+    }
+    steps
+}
+
+fn parse_chord(s: &str, binding: &str) -> Result<Chord, String> {
+    let parts: Vec<&str> = s.split('+').collect();
+    if parts.iter().any(|part| part.is_empty()) {
+        return Err(format!("empty key name in {:?}", binding));
+    }
+    let (last, rest) = parts.split_last().unwrap();
+    let mut chord = Chord { modifiers: Vec::new(), sided: Vec::new(), keys: Vec::new() };
+    for part in rest {
+        if let Some(modifier) = Modifier::from_name(part) {
+            if !chord.modifiers.contains(&modifier) {
+                chord.modifiers.push(modifier);
+            }
+            continue;
+        }
+        let key = Key::from_name(part).ok_or_else(|| unknown_modifier(part, binding))?;
+        let keys = if key.modifier().is_some() { &mut chord.sided } else { &mut chord.keys };
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    // the last one is always the key, even `Ctrl` on its own
+    let key = Key::from_name(last).ok_or_else(|| unknown_key(last, binding))?;
+    chord.sided.retain(|&k| k != key);
+    if !chord.keys.contains(&key) {
+        chord.keys.push(key);
+    }
+    Ok(chord)
+}
+
+impl FromStr for KeyBinding {
+    type Err = String;
+
+    /// Parses bindings like `Ctrl+Alt+Insert`, `RCtrl+A+S` or `ScrollLock ScrollLock`.
+    ///
+    /// They don't repeat, match exactly and fire on press, the long form can change that.
+    fn from_str(s: &str) -> Result<KeyBinding, String> {
+        let chords = steps(s).iter().map(|step| parse_chord(step, s)).collect::<Result<Vec<_>, _>>()?;
+        if chords.is_empty() {
+            return Err("empty key binding".to_owned());
+        }
+        Ok(KeyBinding { steps: chords, no_repeat: true, exact: true, on_release: false })
+    }
+}
+
+impl Display for Chord {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for modifier in &self.modifiers {
+            write!(f, "{}+", modifier.name())?;
+        }
+        let keys: Vec<_> = self.sided.iter().chain(&self.keys).map(|&key| key_name(key)).collect();
+        write!(f, "{}", keys.join("+"))
+    }
+}
+
+impl Display for KeyBinding {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", step)?;
+        }
+        Ok(())
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// The long form for bindings that don't go with the defaults.
+///
+/// Configs from before the short form have `modifiers` and `key` instead of `keys`.
+/// Those keep matching like they used to, i.e. not exactly, unless they ask for `exact`.
+#[derive(Serialize, Deserialize)]
+struct Fields {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keys: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    modifiers: Option<Vec<Modifier>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<Key>,
+    #[serde(default = "default_true")]
+    no_repeat: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exact: Option<bool>,
+    #[serde(default)]
+    on_release: bool,
+}
+
+impl Fields {
+    fn into_binding(self) -> Result<KeyBinding, String> {
+        let mut binding: KeyBinding = match (self.keys, self.modifiers, self.key) {
+            (Some(keys), None, None) => keys.parse()?,
+            (None, modifiers, Some(key)) => KeyBinding::new(modifiers.unwrap_or_default(), key, true),
+            _ => return Err("key bindings need either keys or a key with modifiers".to_owned()),
+        };
+        binding.no_repeat = self.no_repeat;
+        if let Some(exact) = self.exact {
+            binding.exact = exact;
+        }
+        binding.on_release = self.on_release;
+        Ok(binding)
+    }
+}
+
+impl Serialize for KeyBinding {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.no_repeat && self.exact && !self.on_release {
+            return serializer.collect_str(self);
+        }
+        Fields {
+            keys: Some(self.to_string()),
+            modifiers: None,
+            key: None,
+            no_repeat: self.no_repeat,
+            exact: Some(self.exact),
+            on_release: self.on_release,
+        }.serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for KeyBinding {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<KeyBinding, D::Error> {
+        struct BindingVisitor;
+
+        impl<'de> Visitor<'de> for BindingVisitor {
+            type Value = KeyBinding;
+
+            fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+                f.write_str("a key binding like \"Ctrl+Alt+Insert\"")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<KeyBinding, E> {
+                s.parse().map_err(E::custom)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<KeyBinding, A::Error> {
+                Fields::deserialize(MapAccessDeserializer::new(map))?.into_binding().map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(BindingVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_yaml;
+
+    #[test]
+    fn parse_and_display() {
+        let binding: KeyBinding = "ctrl+ALT+insert".parse().unwrap();
+        let mut expected = KeyBinding::new(vec![Modifier::Ctrl, Modifier::Alt], Key::Insert, true);
+        expected.exact = true;
+        assert_eq!(binding, expected);
+        assert_eq!(binding.to_string(), "Ctrl+Alt+Insert");
+
+        // Linux and qcode names, modifier keys only count on their side
+        let binding: KeyBinding = "Super + LeftShift + KEY_F12".parse().unwrap();
+        assert_eq!(binding.to_string(), "Win+LShift+F12");
+        assert_eq!("Ctrl+Alt+del".parse::<KeyBinding>().unwrap().to_string(), "Ctrl+Alt+Delete");
+        assert_eq!("Shift+pgup".parse::<KeyBinding>().unwrap().to_string(), "Shift+PageUp");
+    }
+
+    #[test]
+    fn chords_and_sequences() {
+        let binding: KeyBinding = "rctrl+a+S".parse().unwrap();
+        assert_eq!(binding.to_string(), "RCtrl+A+S");
+        assert_eq!(binding.to_windows(), None);
+        let binding: KeyBinding = "ScrollLock  scroll_lock".parse().unwrap();
+        assert_eq!(binding.to_string(), "Scroll Scroll");
+        assert_eq!(binding.steps.len(), 2);
+        let binding: KeyBinding = "Ctrl + A Ctrl+B".parse().unwrap();
+        assert_eq!(binding.to_string(), "Ctrl+A Ctrl+B");
+        // a modifier key on its own
+        assert_eq!("altgr".parse::<KeyBinding>().unwrap().to_string(), "RAlt");
+        assert!("".parse::<KeyBinding>().is_err());
+    }
+
+    #[test]
+    fn long_form() {
+        let binding: KeyBinding = serde_yaml::from_str("keys: RCtrl\non_release: true").unwrap();
+        assert!(binding.on_release && binding.exact && binding.no_repeat);
+        let yaml = serde_yaml::to_string(&binding).unwrap();
+        assert!(yaml.contains("keys: RCtrl") && yaml.contains("on_release: true"), "{}", yaml);
+        assert_eq!(serde_yaml::from_str::<KeyBinding>(&yaml).unwrap(), binding);
+
+        let e = serde_yaml::from_str::<KeyBinding>("keys: A\nkey: B").unwrap_err();
+        assert!(e.to_string().contains("either keys or"), "{}", e);
+    }
+
+    #[test]
+    fn unknown_names() {
+        let e = "Ctrl+Alt+Insrt".parse::<KeyBinding>().unwrap_err();
+        assert!(e.contains("did you mean Insert"), "{}", e);
+        let e = "Ctl+Insert".parse::<KeyBinding>().unwrap_err();
+        assert!(e.contains("not a modifier"), "{}", e);
+        assert!("Ctrl+".parse::<KeyBinding>().is_err());
+    }
+
+    #[test]
+    fn old_form() {
+        let binding: KeyBinding = serde_yaml::from_str("modifiers: [Win]\nno_repeat: false\nkey: Insert").unwrap();
+        assert_eq!(binding, KeyBinding::new(vec![Modifier::Win], Key::Insert, false));
+        // only bindings that don't repeat have a short form
+        let yaml = serde_yaml::to_string(&binding).unwrap();
+        assert!(yaml.contains("no_repeat: false"), "{}", yaml);
+
+        // they don't mind extra modifiers, like before there were exact ones
+        let binding: KeyBinding = serde_yaml::from_str("modifiers: [Ctrl]\nkey: Insert").unwrap();
+        assert!(!binding.exact);
+        let yaml = serde_yaml::to_string(&binding).unwrap();
+        assert!(yaml.contains("keys: Ctrl+Insert") && yaml.contains("exact: false"), "{}", yaml);
+        assert_eq!(serde_yaml::from_str::<KeyBinding>(&yaml).unwrap(), binding);
+        let binding: KeyBinding = serde_yaml::from_str("modifiers: [Ctrl]\nkey: Insert\nexact: true").unwrap();
+        assert!(binding.exact);
+
+        let binding: KeyBinding = serde_yaml::from_str("Win+Insert").unwrap();
+        assert_eq!(serde_yaml::to_string(&binding).unwrap().trim_start_matches("---").trim(), "Win+Insert");
+    }
+}
@@ -22,3 +22,8 @@ pub fn usable_ports(bus: UsbBus) -> usize {
         UsbBus::Xhci => 15,
     }
 }
+
+/// Quotes `s` for use as a single word in /bin/sh
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
@@ -0,0 +1,282 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+use std::time::Duration;
+
+use common::config::{BackupConfig, MachineConfig, SnapshotOverlay, StorageDevice};
+use tokio::process::Command;
+use tokio::sync::oneshot;
+
+use crate::controller::Controller;
+use crate::monitor::QmpCommand;
+
+/// Name of the qemu block node for a snapshot overlay.
+pub fn overlay_node(disk_id: usize, generation: u32) -> String {
+    format!("disk{disk_id}_snap{generation}")
+}
+
+/// Builds the commit that rotates the oldest overlays of `chain` into the base image
+/// so that no more than `keep` overlays remain.
+///
+/// `chain` is ordered oldest first and its last entry is the active layer.
+pub fn rotation(disk_id: usize, chain: &[SnapshotOverlay], keep: usize) -> Option<(QmpCommand, oneshot::Receiver<()>)> {
+    let excess = chain.len().saturating_sub(keep);
+    if excess == 0 {
+        return None;
+    }
+
+    let active = chain.last()?;
+    let overlays: Vec<_> = chain[..excess].iter()
+        .map(|o| (overlay_node(disk_id, o.generation), o.path.clone()))
+        .collect();
+    // committing everything (including the active layer) is an active commit, anything else stops below the top
+    let top_node = match excess == chain.len() {
+        true => None,
+        false => overlays.last().map(|(node, _)| node.clone()),
+    };
+
+    let (tx, rx) = oneshot::channel();
+    Some((QmpCommand::CommitSnapshot {
+        disk_id,
+        active_node: overlay_node(disk_id, active.generation),
+        top_node,
+        overlays,
+        ack: tx,
+    }, rx))
+}
+
+fn qemu_img(args: &[&str]) -> io::Result<()> {
+    let status = std::process::Command::new("qemu-img").args(args).status()?;
+    if !status.success() {
+        return Err(io::Error::new(ErrorKind::Other, format!("qemu-img {} reported an error: {}", args[0], status)));
+    }
+    Ok(())
+}
+
+/// Commits the `count` oldest overlays of `chain` into the base image of `disk` using qemu-img.
+fn commit_offline(disk: &StorageDevice, chain: &[SnapshotOverlay], count: usize) -> io::Result<()> {
+    if count == 0 {
+        return Ok(());
+    }
+
+    // commit from the top of the range downwards so everything trickles into the base image
+    for overlay in chain[..count].iter().rev() {
+        qemu_img(&["commit", "-d", &overlay.path])?;
+    }
+    // the remaining overlays now have to point at the base image directly
+    // contents are identical after the commit, so an unsafe rebase is all we need
+    if let Some(next) = chain.get(count) {
+        qemu_img(&["rebase", "-u", "-b", &disk.path, "-F", &disk.format, &next.path])?;
+    }
+    for overlay in &chain[..count] {
+        fs::remove_file(&overlay.path)?;
+    }
+    Ok(())
+}
+
+/// Enters backup mode while qemu is down.
+///
+/// Creates a new overlay for every disk with a configured snapshot file and commits the oldest ones if there are
+/// more than `keep` afterwards.
+pub fn enter_offline(machine: &MachineConfig, keep: usize) -> io::Result<()> {
+    for disk in &machine.storage {
+        let next = match disk.next_snapshot() {
+            Some(x) => x,
+            None => continue,
+        };
+        let mut chain = disk.snapshot_chain();
+        let (backing, format) = match chain.last() {
+            Some(o) => (o.path.clone(), "qcow2".to_owned()),
+            None => (disk.path.clone(), disk.format.clone()),
+        };
+        // qemu-img create -f qcow2 -b /dev/tank/windows -F raw qemu-snaps/windows.qcow2
+        qemu_img(&["create", "-f", "qcow2", "-b", &backing, "-F", &format, &next.path])?;
+
+        chain.push(next);
+        let excess = chain.len().saturating_sub(keep);
+        commit_offline(disk, &chain, excess)?;
+    }
+    Ok(())
+}
+
+/// Leaves backup mode while qemu is down, committing all overlays into their base images.
+pub fn leave_offline(machine: &MachineConfig) -> io::Result<()> {
+    for disk in &machine.storage {
+        let chain = disk.snapshot_chain();
+        commit_offline(disk, &chain, chain.len())?;
+    }
+    Ok(())
+}
+
+fn parse_time_of_day(s: &str) -> Option<(i32, i32)> {
+    let (hour, minute) = s.trim().split_once(':')?;
+    let hour = hour.parse().ok().filter(|h| (0..24).contains(h))?;
+    let minute = minute.parse().ok().filter(|m| (0..60).contains(m))?;
+    Some((hour, minute))
+}
+
+/// Time until the next occurrence of `hour:minute` in local time.
+fn until_next(hour: i32, minute: i32) -> Duration {
+    unsafe {
+        let now = libc::time(ptr::null_mut());
+        let mut tm: libc::tm = mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm.tm_hour = hour;
+        tm.tm_min = minute;
+        tm.tm_sec = 0;
+        tm.tm_isdst = -1;
+        let mut next = libc::mktime(&mut tm);
+        if next <= now {
+            tm.tm_mday += 1;
+            tm.tm_isdst = -1;
+            next = libc::mktime(&mut tm);
+        }
+        // mktime fails with -1, which would be long ago
+        Duration::from_secs((next - now).max(0) as u64)
+    }
+}
+
+async fn run_hook_and_wait(hook: &str) -> bool {
+    match Command::new("/bin/sh").arg("-c").arg(hook).status().await {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            warn!("Backup hook `{}` failed with {}", hook, status);
+            false
+        }
+        Err(e) => {
+            warn!("Error spawning backup hook: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Runs the configured backup schedule for as long as the driver is up.
+pub async fn scheduler(config: BackupConfig, controller: Rc<RefCell<Controller>>) {
+    let schedule = match config.schedule.as_ref() {
+        Some(x) => x,
+        None => return,
+    };
+    let (hour, minute) = match parse_time_of_day(schedule) {
+        Some(x) => x,
+        None => {
+            error!("Invalid backup schedule {:?}, expected HH:MM. Scheduled backups are disabled.", schedule);
+            return;
+        }
+    };
+
+    loop {
+        let wait = until_next(hour, minute);
+        debug!("Next scheduled backup in {:?}", wait);
+        tokio::time::sleep(wait).await;
+
+        info!("Starting scheduled backup");
+        if let Some(ref hook) = config.pre_hook {
+            if !run_hook_and_wait(hook).await {
+                warn!("Backup pre hook failed, skipping scheduled backup");
+                continue;
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        controller.borrow_mut().enter_backup_mode(tx);
+        if rx.await.is_err() {
+            error!("Failed to enter backup mode, skipping scheduled backup");
+            continue;
+        }
+
+        if let Some(ref hook) = config.post_hook {
+            if !run_hook_and_wait(hook).await {
+                warn!("Backup post hook failed");
+            }
+        }
+
+        if config.leave_after_post_hook {
+            let (tx, rx) = oneshot::channel();
+            controller.borrow_mut().leave_backup_mode(tx);
+            if rx.await.is_err() {
+                error!("Failed to leave backup mode after scheduled backup");
+                continue;
+            }
+        }
+        info!("Scheduled backup done");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chain(generations: &[u32]) -> Vec<SnapshotOverlay> {
+        generations.iter()
+            .map(|&generation| SnapshotOverlay { generation, path: format!("snap.{generation}") })
+            .collect()
+    }
+
+    #[test]
+    fn rotate_below_the_top() {
+        let (cmd, _) = rotation(1, &chain(&[0, 1, 2]), 2).unwrap();
+        match cmd {
+            QmpCommand::CommitSnapshot { disk_id, active_node, top_node, overlays, .. } => {
+                assert_eq!(disk_id, 1);
+                assert_eq!(active_node, "disk1_snap2");
+                assert_eq!(top_node.as_deref(), Some("disk1_snap0"));
+                assert_eq!(overlays, [("disk1_snap0".to_owned(), "snap.0".to_owned())]);
+            }
+            _ => panic!("expected a commit"),
+        }
+    }
+
+    #[test]
+    fn rotate_everything() {
+        let (cmd, _) = rotation(0, &chain(&[3, 4]), 0).unwrap();
+        match cmd {
+            QmpCommand::CommitSnapshot { active_node, top_node, overlays, .. } => {
+                assert_eq!(active_node, "disk0_snap4");
+                // an active commit
+                assert_eq!(top_node, None);
+                let nodes: Vec<_> = overlays.iter().map(|(node, _)| node.as_str()).collect();
+                assert_eq!(nodes, ["disk0_snap3", "disk0_snap4"]);
+            }
+            _ => panic!("expected a commit"),
+        }
+    }
+
+    #[test]
+    fn nothing_to_rotate() {
+        assert!(rotation(0, &chain(&[0, 1]), 2).is_none());
+        assert!(rotation(0, &chain(&[0]), 3).is_none());
+        assert!(rotation(0, &[], 0).is_none());
+    }
+
+    #[test]
+    fn time_of_day() {
+        assert_eq!(parse_time_of_day("03:30"), Some((3, 30)));
+        assert_eq!(parse_time_of_day(" 23:59 "), Some((23, 59)));
+        assert_eq!(parse_time_of_day("0:5"), Some((0, 5)));
+        assert_eq!(parse_time_of_day("24:00"), None);
+        assert_eq!(parse_time_of_day("12:60"), None);
+        assert_eq!(parse_time_of_day("-1:00"), None);
+        assert_eq!(parse_time_of_day("noon"), None);
+        assert_eq!(parse_time_of_day("12"), None);
+    }
+
+    #[test]
+    fn next_occurrence() {
+        let tm = unsafe {
+            let now = libc::time(ptr::null_mut());
+            let mut tm: libc::tm = mem::zeroed();
+            libc::localtime_r(&now, &mut tm);
+            tm
+        };
+        // the current minute started already, so it's tomorrow
+        let wait = until_next(tm.tm_hour, tm.tm_min);
+        assert!(wait > Duration::from_secs(22 * 3600) && wait <= Duration::from_secs(25 * 3600), "{:?}", wait);
+        for hour in 0..24 {
+            let wait = until_next(hour, 0);
+            assert!(wait <= Duration::from_secs(25 * 3600), "{:?}", wait);
+        }
+    }
+}
@@ -5,7 +5,6 @@ pub use self::codec::{GaCmdOut, ClipboardMessage, ClipboardType, ClipboardTypes,
 use std::io::{Error, ErrorKind};
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::time::Duration;
 
 use futures::unsync::mpsc::{self, UnboundedSender};
 use futures::{Stream, Future};
@@ -66,14 +65,7 @@ impl Clientpipe {
                     info!("client is now alive!");
 
                     if controller.ga_hello() {
-                        let controller = controller_rc.clone();
-                        let timer = IntervalStream::new(time::interval(Duration::new(5, 0)))
-                            .map(|a| Ok::<_, ()>(a)).compat()
-                            .for_each(move |_| match controller.borrow_mut().ga_ping() {
-                                true => Ok(()),
-                                false => Err(()),
-                            });
-                        tokio::task::spawn_local(timer.compat());
+                        start_pinging(&controller, controller_rc.clone());
                     }
                 }
                 GaCmdIn::Suspending(()) => {
@@ -81,7 +73,7 @@ impl Clientpipe {
                     controller.ga_suspending();
                 }
                 GaCmdIn::Pong(()) => controller.ga_pong(),
-                GaCmdIn::HotKey(id) => controller.ga_hotkey(id),
+                GaCmdIn::HotKey(id) => controller.hotkey(id),
                 GaCmdIn::HotKeyBindingFailed(s) => warn!("HotKeyBinding failed: {}", s),
                 GaCmdIn::Clipboard(c) => match c.message {
                     Some(ClipboardMessage::GrabClipboard(types)) => controller.grab_x11_clipboard(types),
@@ -102,3 +94,15 @@ impl Clientpipe {
         Box::new(handler)
     }
 }
+
+/// Pings the GA in the configured interval until it stops answering.
+pub fn start_pinging(controller: &Controller, controller_rc: Rc<RefCell<Controller>>) {
+    let interval = controller.ga_ping_interval();
+    let timer = IntervalStream::new(time::interval(interval))
+        .map(|a| Ok::<_, ()>(a)).compat()
+        .for_each(move |_| match controller_rc.borrow_mut().ga_ping() {
+            true => Ok(()),
+            false => Err(()),
+        });
+    tokio::task::spawn_local(timer.compat());
+}
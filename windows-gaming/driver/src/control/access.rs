@@ -0,0 +1,211 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+use common::config::{AccessConfig, Permission};
+
+use super::ControlCmdIn;
+
+/// Who is on the other end of a control connection, as told by the kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub pid: Option<libc::pid_t>,
+    pub uid: u32,
+    /// Primary and supplementary groups
+    pub gids: Vec<u32>,
+}
+
+/// `SO_PEERGROUPS` from `<asm-generic/socket.h>`, which libc doesn't have
+const SO_PEERGROUPS: libc::c_int = 59;
+
+impl Peer {
+    /// The peer of the connected `socket`, with the credentials `SO_PEERCRED` told.
+    pub fn new(socket: RawFd, pid: Option<libc::pid_t>, uid: u32, gid: u32) -> Peer {
+        let mut gids = vec![gid];
+        // SO_PEERCRED only tells the primary group
+        match peer_groups(socket) {
+            Ok(groups) => gids.extend(groups),
+            Err(e) => warn!("Can't look up the groups of uid {}, going by its primary group only: {}", uid, e),
+        }
+        Peer { pid, uid, gids }
+    }
+}
+
+impl Peer {
+    /// A peer we only know the uid of, e.g. from the D-Bus daemon. Its groups come from the user database.
+    pub fn from_uid(uid: u32) -> Peer {
+        let gids = user_groups(uid).unwrap_or_else(|| {
+            warn!("uid {} isn't in the user database, going by no groups", uid);
+            Vec::new()
+        });
+        Peer { pid: None, uid, gids }
+    }
+}
+
+/// The primary and supplementary groups of `uid` according to the user database.
+fn user_groups(uid: u32) -> Option<Vec<u32>> {
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf: Vec<libc::c_char> = vec![0; 4096];
+    let mut found = ptr::null_mut();
+    loop {
+        let res = unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+        match res {
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            0 if !found.is_null() => break,
+            _ => return None,
+        }
+    }
+
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let res = unsafe { libc::getgrouplist(passwd.pw_name, passwd.pw_gid, groups.as_mut_ptr(), &mut count) };
+        if res >= 0 {
+            groups.truncate(count as usize);
+            return Some(groups);
+        }
+        // too small, `count` is how many there are
+        groups.resize((count as usize).max(groups.len() * 2), 0);
+    }
+}
+
+/// The supplementary groups the peer of `socket` had when it connected.
+///
+/// Unlike anything looked up by pid, this can't be mixed up with a process that got the same pid later.
+fn peer_groups(socket: RawFd) -> io::Result<Vec<u32>> {
+    let size = mem::size_of::<libc::gid_t>();
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut len = (groups.len() * size) as libc::socklen_t;
+        let buf = groups.as_mut_ptr() as *mut libc::c_void;
+        let res = unsafe { libc::getsockopt(socket, libc::SOL_SOCKET, SO_PEERGROUPS, buf, &mut len) };
+        if res == 0 {
+            groups.truncate(len as usize / size);
+            return Ok(groups);
+        }
+        let err = io::Error::last_os_error();
+        // too small, the kernel tells how much room it needs
+        if err.raw_os_error() == Some(libc::ERANGE) && len as usize > groups.len() * size {
+            groups.resize(len as usize / size, 0);
+            continue;
+        }
+        return Err(err);
+    }
+}
+
+/// The permission needed to send `cmd`.
+pub fn required(cmd: &ControlCmdIn) -> Permission {
+    match *cmd {
+        ControlCmdIn::Status | ControlCmdIn::Subscribe | ControlCmdIn::QemuEvents => Permission::Status,
+        ControlCmdIn::IoEntry | ControlCmdIn::TryIoEntry | ControlCmdIn::LightEntry | ControlCmdIn::ForceIoEntry
+            | ControlCmdIn::IoExit | ControlCmdIn::TemporaryLightEntry { .. } => Permission::Attach,
+        ControlCmdIn::Suspend | ControlCmdIn::Pause | ControlCmdIn::Resume => Permission::Suspend,
+        ControlCmdIn::Shutdown | ControlCmdIn::Save => Permission::Shutdown,
+        ControlCmdIn::EnterBackupMode | ControlCmdIn::LeaveBackupMode => Permission::Backup,
+        ControlCmdIn::Qmp { .. } | ControlCmdIn::QmpEvents => Permission::Qmp,
+    }
+}
+
+/// Whether `command` is one of the allowed raw QMP commands.
+pub fn qmp_allowed(config: &AccessConfig, command: &str) -> bool {
+    config.qmp_commands.iter().any(|allowed| match allowed.strip_suffix('*') {
+        Some(prefix) => command.starts_with(prefix),
+        None => command == allowed,
+    })
+}
+
+/// Whether `peer` has `permission`. `owner` is the uid of the driver.
+pub fn allowed(config: &AccessConfig, owner: u32, peer: &Peer, permission: Permission) -> bool {
+    if peer.uid == 0 || peer.uid == owner {
+        return true;
+    }
+    config.rules.iter()
+        .filter(|rule| rule.uid.map_or(true, |uid| uid == peer.uid))
+        .filter(|rule| rule.gid.map_or(true, |gid| peer.gids.contains(&gid)))
+        .any(|rule| rule.permissions.contains(&permission))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use common::config::AccessRule;
+
+    fn peer(uid: u32, gids: &[u32]) -> Peer {
+        Peer { pid: None, uid, gids: gids.to_vec() }
+    }
+
+    fn config() -> AccessConfig {
+        AccessConfig {
+            qmp_commands: vec!["query-*".to_owned(), "device_del".to_owned()],
+            rules: vec![
+                AccessRule { uid: Some(1001), gid: None, permissions: vec![Permission::Attach, Permission::Status] },
+                AccessRule { uid: None, gid: Some(50), permissions: vec![Permission::Status] },
+                AccessRule { uid: Some(1002), gid: Some(60), permissions: vec![Permission::Backup] },
+            ],
+        }
+    }
+
+    #[test]
+    fn groups_of_the_peer() {
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let mut expected = vec![0; 256];
+        let count = unsafe { libc::getgroups(expected.len() as libc::c_int, expected.as_mut_ptr()) };
+        assert!(count >= 0);
+        expected.truncate(count as usize);
+
+        let mut groups = peer_groups(ours.as_raw_fd()).unwrap();
+        groups.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(groups, expected);
+        drop(theirs);
+    }
+
+    #[test]
+    fn groups_of_a_uid() {
+        let root = Peer::from_uid(0);
+        assert!(root.gids.contains(&0), "{:?}", root);
+    }
+
+    #[test]
+    fn owner_and_root() {
+        let empty = AccessConfig::default();
+        assert!(allowed(&empty, 1000, &peer(1000, &[1000]), Permission::Shutdown));
+        assert!(allowed(&empty, 1000, &peer(0, &[0]), Permission::Shutdown));
+        assert!(!allowed(&empty, 1000, &peer(1001, &[1001]), Permission::Status));
+    }
+
+    #[test]
+    fn rules() {
+        let cfg = config();
+        assert!(allowed(&cfg, 1000, &peer(1001, &[1001]), Permission::Attach));
+        assert!(!allowed(&cfg, 1000, &peer(1001, &[1001]), Permission::Shutdown));
+        // by supplementary group
+        assert!(allowed(&cfg, 1000, &peer(1003, &[1003, 50]), Permission::Status));
+        assert!(!allowed(&cfg, 1000, &peer(1003, &[1003, 50]), Permission::Attach));
+        // both uid and gid have to match
+        assert!(!allowed(&cfg, 1000, &peer(1002, &[1002]), Permission::Backup));
+        assert!(allowed(&cfg, 1000, &peer(1002, &[1002, 60]), Permission::Backup));
+    }
+
+    #[test]
+    fn permissions() {
+        assert_eq!(required(&ControlCmdIn::TemporaryLightEntry { x: 1, y: 2 }), Permission::Attach);
+        assert_eq!(required(&ControlCmdIn::Save), Permission::Shutdown);
+        assert_eq!(required(&ControlCmdIn::Subscribe), Permission::Status);
+    }
+
+    #[test]
+    fn qmp_commands() {
+        let cfg = config();
+        assert!(qmp_allowed(&cfg, "query-status"));
+        assert!(qmp_allowed(&cfg, "device_del"));
+        assert!(!qmp_allowed(&cfg, "device_del2"));
+        assert!(!qmp_allowed(&cfg, "quit"));
+        assert!(qmp_allowed(&AccessConfig { qmp_commands: vec!["*".to_owned()], ..cfg }, "quit"));
+    }
+}
@@ -1,8 +1,14 @@
 use std::io;
 
 use bytes::{BytesMut, BufMut, Buf};
+use serde_json::Value;
 use tokio_util::codec::{Encoder, Decoder};
 
+use windows_gaming_client::protocol::{AttachMode, ClientMessage, Command, DriverMessage, Error, ErrorKind, Event,
+                                      QemuEvent, Reply, Status, MAGIC, MAX_FRAME_LEN};
+
+use crate::shutdown::ShutdownStage;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ControlCmdOut {
     MouseEdged {
@@ -12,6 +18,15 @@ pub enum ControlCmdOut {
     TemporaryLightAttached,
     TemporaryLightDetached,
     Ack,
+    ShutdownProgress(ShutdownStage),
+    Error(ErrorKind, String),
+    /// Handshake reply with the negotiated version
+    Hello(u32),
+    Status { status: Status, uptime: u64 },
+    StatusChanged(Status),
+    QmpReturn(Value),
+    QmpEvent(Value),
+    QemuEvent(QemuEvent),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -29,98 +44,350 @@ pub enum ControlCmdIn {
     },
     EnterBackupMode,
     LeaveBackupMode,
+    Pause,
+    Resume,
+    Save,
+    Status,
+    Subscribe,
+    Qmp {
+        execute: String,
+        arguments: Option<Value>,
+    },
+    QmpEvents,
+    QemuEvents,
+}
+
+/// Which protocol a connection speaks, decided by its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Sniffing,
+    Legacy,
+    Framed,
+}
+
+/// A request as decoded from either protocol.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Incoming {
+    Hello { version: u32 },
+    /// `id` is `None` for legacy requests, which get no replies unless they always did
+    Command { id: Option<u64>, cmd: ControlCmdIn },
+    /// A request we couldn't make sense of
+    Invalid { id: Option<u64>, error: String },
 }
 
-pub struct Codec;
+/// Something to tell a client, along with the id of the request it answers (if any).
+pub type Outgoing = (Option<u64>, ControlCmdOut);
+
+pub struct Codec {
+    mode: Mode,
+}
+
+impl Codec {
+    pub fn new() -> Codec {
+        Codec { mode: Mode::Sniffing }
+    }
+}
 
 impl Decoder for Codec {
-    type Item = ControlCmdIn;
+    type Item = Incoming;
     type Error = io::Error;
 
-    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<ControlCmdIn>> {
-        let mut size = 1;
-        let ret = match buf.get(0).cloned() {
-            Some(1) => ControlCmdIn::IoEntry,
-            Some(2) => ControlCmdIn::Shutdown,
-            Some(3) => ControlCmdIn::ForceIoEntry,
-            Some(4) => ControlCmdIn::IoExit,
-            Some(5) => ControlCmdIn::Suspend,
-            Some(6) => ControlCmdIn::TryIoEntry,
-            Some(7) => ControlCmdIn::LightEntry,
-            Some(8) if buf.len() < 9 => return Ok(None),
-            Some(8) => {
-                let mut bbuf = buf.as_ref();
-                bbuf.advance(1); // skip cmd
-                let x = bbuf.get_i32_le();
-                let y = bbuf.get_i32_le();
-                size += 8;
-                ControlCmdIn::TemporaryLightEntry { x, y }
-            }
-            Some(9) => ControlCmdIn::EnterBackupMode,
-            Some(10) => ControlCmdIn::LeaveBackupMode,
-            Some(x) => {
-                warn!("control sent invalid request {}", x);
-                // no idea how to proceed as the request might have payload
-                // this essentially just hangs the connection forever
-                return Ok(None);
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Incoming>> {
+        match self.mode {
+            Mode::Sniffing => {
+                let len = buf.len().min(MAGIC.len());
+                if buf[..len] != MAGIC[..len] {
+                    self.mode = Mode::Legacy;
+                } else if len < MAGIC.len() {
+                    return Ok(None);
+                } else {
+                    buf.advance(MAGIC.len());
+                    self.mode = Mode::Framed;
+                }
+                self.decode(buf)
             }
-            None => return Ok(None),
-        };
-        buf.advance(size);
-        Ok(Some(ret))
+            Mode::Legacy => Ok(decode_legacy(buf)),
+            Mode::Framed => decode_framed(buf),
+        }
     }
 }
 
-impl Encoder<ControlCmdOut> for Codec {
-    type Error = io::Error;
+fn decode_legacy(buf: &mut BytesMut) -> Option<Incoming> {
+    let mut size = 1;
+    let ret = match buf.get(0).cloned() {
+        Some(1) => ControlCmdIn::IoEntry,
+        Some(2) => ControlCmdIn::Shutdown,
+        Some(3) => ControlCmdIn::ForceIoEntry,
+        Some(4) => ControlCmdIn::IoExit,
+        Some(5) => ControlCmdIn::Suspend,
+        Some(6) => ControlCmdIn::TryIoEntry,
+        Some(7) => ControlCmdIn::LightEntry,
+        Some(8) if buf.len() < 9 => return None,
+        Some(8) => {
+            let mut bbuf = buf.as_ref();
+            bbuf.advance(1); // skip cmd
+            let x = bbuf.get_i32_le();
+            let y = bbuf.get_i32_le();
+            size += 8;
+            ControlCmdIn::TemporaryLightEntry { x, y }
+        }
+        Some(9) => ControlCmdIn::EnterBackupMode,
+        Some(10) => ControlCmdIn::LeaveBackupMode,
+        Some(11) => ControlCmdIn::Pause,
+        Some(12) => ControlCmdIn::Resume,
+        Some(13) => ControlCmdIn::Save,
+        Some(x) => {
+            warn!("control sent invalid request {}", x);
+            // no idea how long the request is, so drop everything we got so far and hope for the best
+            buf.clear();
+            return Some(Incoming::Invalid { id: None, error: format!("unknown request {}", x) });
+        }
+        None => return None,
+    };
+    buf.advance(size);
+    Some(Incoming::Command { id: None, cmd: ret })
+}
+
+fn decode_framed(buf: &mut BytesMut) -> io::Result<Option<Incoming>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let len = (&buf[..4]).get_u32();
+    if len > MAX_FRAME_LEN {
+        // we can't skip that without buffering it, so give up on the connection
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("control frame of {} bytes is too large", len)));
+    }
+    if buf.len() < 4 + len as usize {
+        buf.reserve(4 + len as usize - buf.len());
+        return Ok(None);
+    }
+    buf.advance(4);
+    let frame = buf.split_to(len as usize);
+
+    Ok(Some(match serde_json::from_slice(&frame) {
+        Ok(ClientMessage::Hello { version }) => Incoming::Hello { version },
+        Ok(ClientMessage::Request { id, command }) => Incoming::Command { id: Some(id), cmd: command.into() },
+        Err(e) => {
+            warn!("control sent invalid request: {}", e);
+            // still try to answer the right request
+            let id = serde_json::from_slice::<serde_json::Value>(&frame).ok()
+                .and_then(|v| v.get("id").and_then(|id| id.as_u64()));
+            Incoming::Invalid { id, error: format!("invalid request: {}", e) }
+        }
+    }))
+}
 
-    fn encode(&mut self, cmd: ControlCmdOut, buf: &mut BytesMut) -> io::Result<()> {
-        buf.reserve(1);
+impl From<Command> for ControlCmdIn {
+    fn from(cmd: Command) -> ControlCmdIn {
         match cmd {
-            ControlCmdOut::MouseEdged { x, y } => {
-                buf.put_u8(1);
-                buf.reserve(8);
-                buf.put_i32_le(x);
-                buf.put_i32_le(y);
+            Command::Attach { mode: AttachMode::Auto } => ControlCmdIn::IoEntry,
+            Command::Attach { mode: AttachMode::Try } => ControlCmdIn::TryIoEntry,
+            Command::Attach { mode: AttachMode::Force } => ControlCmdIn::ForceIoEntry,
+            Command::Attach { mode: AttachMode::Light } => ControlCmdIn::LightEntry,
+            Command::Detach => ControlCmdIn::IoExit,
+            Command::Shutdown => ControlCmdIn::Shutdown,
+            Command::Suspend => ControlCmdIn::Suspend,
+            Command::Pause => ControlCmdIn::Pause,
+            Command::Resume => ControlCmdIn::Resume,
+            Command::Save => ControlCmdIn::Save,
+            Command::EnterBackupMode => ControlCmdIn::EnterBackupMode,
+            Command::LeaveBackupMode => ControlCmdIn::LeaveBackupMode,
+            Command::TemporaryLightEntry { x, y } => ControlCmdIn::TemporaryLightEntry { x, y },
+            Command::Status => ControlCmdIn::Status,
+            Command::Subscribe => ControlCmdIn::Subscribe,
+            Command::Qmp { execute, arguments } => ControlCmdIn::Qmp { execute, arguments },
+            Command::QmpEvents => ControlCmdIn::QmpEvents,
+            Command::QemuEvents => ControlCmdIn::QemuEvents,
+        }
+    }
+}
+
+impl Encoder<Outgoing> for Codec {
+    type Error = io::Error;
+
+    fn encode(&mut self, (id, cmd): Outgoing, buf: &mut BytesMut) -> io::Result<()> {
+        match self.mode {
+            Mode::Framed => encode_framed(id, cmd, buf),
+            _ => {
+                encode_legacy(cmd, buf);
+                Ok(())
             }
-            ControlCmdOut::TemporaryLightAttached => buf.put_u8(2),
-            ControlCmdOut::TemporaryLightDetached => buf.put_u8(3),
-            ControlCmdOut::Ack => buf.put_u8(4),
         }
-        Ok(())
     }
 }
 
-/*
+fn encode_legacy(cmd: ControlCmdOut, buf: &mut BytesMut) {
+    buf.reserve(1);
+    match cmd {
+        ControlCmdOut::MouseEdged { x, y } => {
+            buf.put_u8(1);
+            buf.reserve(8);
+            buf.put_i32_le(x);
+            buf.put_i32_le(y);
+        }
+        ControlCmdOut::TemporaryLightAttached => buf.put_u8(2),
+        ControlCmdOut::TemporaryLightDetached => buf.put_u8(3),
+        ControlCmdOut::Ack => buf.put_u8(4),
+        // legacy clients wouldn't understand anything the old protocol didn't send
+        ControlCmdOut::ShutdownProgress(stage) => debug!("Not telling legacy control client about shutdown stage {:?}", stage),
+        ControlCmdOut::Error(kind, msg) => warn!("Legacy control client request failed: {:?}: {}", kind, msg),
+        // legacy clients never say hello nor ask for any of these
+        ControlCmdOut::Hello(_) | ControlCmdOut::Status { .. } | ControlCmdOut::StatusChanged(_)
+            | ControlCmdOut::QmpReturn(_) | ControlCmdOut::QmpEvent(_) | ControlCmdOut::QemuEvent(_) => (),
+    }
+}
+
+fn encode_framed(id: Option<u64>, cmd: ControlCmdOut, buf: &mut BytesMut) -> io::Result<()> {
+    let event = |event| DriverMessage::Event { event };
+    let msg = match (id, cmd) {
+        (_, ControlCmdOut::Hello(version)) => DriverMessage::Hello { version },
+        (Some(id), ControlCmdOut::Ack) => DriverMessage::Result { id, result: Ok(Reply::Done) },
+        (None, ControlCmdOut::Ack) => return Ok(()),
+        (Some(id), ControlCmdOut::Error(kind, message)) =>
+            DriverMessage::Result { id, result: Err(Error::new(kind, message)) },
+        (None, ControlCmdOut::Error(kind, message)) => DriverMessage::Error { error: Error::new(kind, message) },
+        (_, ControlCmdOut::MouseEdged { x, y }) => event(Event::MouseEdged { x, y }),
+        (_, ControlCmdOut::TemporaryLightAttached) => event(Event::TemporaryLightAttached),
+        (_, ControlCmdOut::TemporaryLightDetached) => event(Event::TemporaryLightDetached),
+        (_, ControlCmdOut::ShutdownProgress(stage)) => event(Event::ShutdownProgress { stage: stage.into() }),
+        (_, ControlCmdOut::StatusChanged(status)) => event(Event::Status { status }),
+        (Some(id), ControlCmdOut::Status { status, uptime }) =>
+            DriverMessage::Result { id, result: Ok(Reply::Status { status, uptime }) },
+        (None, ControlCmdOut::Status { .. }) => return Ok(()),
+        (_, ControlCmdOut::QmpEvent(qmp)) => event(Event::Qmp { qmp }),
+        (_, ControlCmdOut::QemuEvent(qemu)) => event(Event::Qemu { qemu }),
+        (Some(id), ControlCmdOut::QmpReturn(result)) => DriverMessage::Result { id, result: Ok(Reply::Qmp { result }) },
+        (None, ControlCmdOut::QmpReturn(_)) => return Ok(()),
+    };
+    let json = serde_json::to_vec(&msg)?;
+    buf.reserve(4 + json.len());
+    buf.put_u32(json.len() as u32);
+    buf.put_slice(&json);
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use bytes::BytesMut;
-    use tokio_io::codec::Decoder;
 
-    fn verify(data: &[u8], expected: Option<ControlCmdIn>, remaining: usize) {
+    fn verify(data: &[u8], expected: Option<Incoming>, remaining: usize) {
         let mut bytes = BytesMut::new();
-        bytes.extend(data);
-        assert_eq!(Codec.decode(&mut bytes).unwrap(), expected);
+        bytes.extend_from_slice(data);
+        assert_eq!(Codec::new().decode(&mut bytes).unwrap(), expected);
         assert_eq!(bytes.len(), remaining);
     }
 
+    fn legacy(cmd: ControlCmdIn) -> Option<Incoming> {
+        Some(Incoming::Command { id: None, cmd })
+    }
+
+    fn frame(json: &str) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&(json.len() as u32).to_be_bytes());
+        data.extend_from_slice(json.as_bytes());
+        data
+    }
+
     #[test] fn none() { verify(&[], None, 0); }
-    #[test] fn invalid() { verify(&[0], None, 1); }
-    #[test] fn io_entry() { verify(&[1], Some(ControlCmdIn::IoEntry), 0); }
-    #[test] fn shutdown() { verify(&[2], Some(ControlCmdIn::Shutdown), 0); }
-    #[test] fn force_io_entry() { verify(&[3], Some(ControlCmdIn::ForceIoEntry), 0); }
-    #[test] fn io_exit() { verify(&[4], Some(ControlCmdIn::IoExit), 0); }
-    #[test] fn suspend() { verify(&[5], Some(ControlCmdIn::Suspend), 0); }
+    #[test] fn invalid() { verify(&[0, 1], Some(Incoming::Invalid { id: None, error: "unknown request 0".to_owned() }), 0); }
+    #[test] fn io_entry() { verify(&[1], legacy(ControlCmdIn::IoEntry), 0); }
+    #[test] fn shutdown() { verify(&[2], legacy(ControlCmdIn::Shutdown), 0); }
+    #[test] fn force_io_entry() { verify(&[3], legacy(ControlCmdIn::ForceIoEntry), 0); }
+    #[test] fn io_exit() { verify(&[4], legacy(ControlCmdIn::IoExit), 0); }
+    #[test] fn suspend() { verify(&[5], legacy(ControlCmdIn::Suspend), 0); }
+    #[test] fn temporary_entry_partial() { verify(&[8, 1, 0], None, 3); }
+    #[test] fn temporary_entry() {
+        verify(&[8, 1, 0, 0, 0, 2, 0, 0, 0], legacy(ControlCmdIn::TemporaryLightEntry { x: 1, y: 2 }), 0);
+    }
 
     #[test]
     fn multiple() {
+        let mut codec = Codec::new();
         let mut bytes = BytesMut::new();
-        bytes.extend(&[1,2]);
-        assert_eq!(Codec.decode(&mut bytes).unwrap(), Some(ControlCmdIn::IoEntry));
+        bytes.extend_from_slice(&[1,2]);
+        assert_eq!(codec.decode(&mut bytes).unwrap(), legacy(ControlCmdIn::IoEntry));
         assert_eq!(bytes.len(), 1);
-        assert_eq!(Codec.decode(&mut bytes).unwrap(), Some(ControlCmdIn::Shutdown));
+        assert_eq!(codec.decode(&mut bytes).unwrap(), legacy(ControlCmdIn::Shutdown));
         assert_eq!(bytes.len(), 0);
     }
+
+    #[test] fn partial_magic() { verify(&MAGIC[..2], None, 2); }
+    #[test] fn partial_frame() {
+        let data = frame(r#"{"type":"hello","version":1}"#);
+        verify(&data[..data.len() - 1], None, data.len() - 1 - MAGIC.len());
+    }
+    #[test] fn hello() { verify(&frame(r#"{"type":"hello","version":1}"#), Some(Incoming::Hello { version: 1 }), 0); }
+    #[test] fn request() {
+        verify(&frame(r#"{"type":"request","id":3,"command":{"command":"attach","mode":"force"}}"#),
+               Some(Incoming::Command { id: Some(3), cmd: ControlCmdIn::ForceIoEntry }), 0);
+    }
+    #[test] fn qmp() {
+        verify(&frame(r#"{"type":"request","id":5,"command":{"command":"qmp","execute":"query-block","arguments":{"flat":true}}}"#),
+               Some(Incoming::Command { id: Some(5), cmd: ControlCmdIn::Qmp {
+                   execute: "query-block".to_owned(),
+                   arguments: Some(serde_json::json!({ "flat": true })),
+               } }), 0);
+        verify(&frame(r#"{"type":"request","id":6,"command":{"command":"qmp","execute":"query-status"}}"#),
+               Some(Incoming::Command { id: Some(6), cmd: ControlCmdIn::Qmp {
+                   execute: "query-status".to_owned(),
+                   arguments: None,
+               } }), 0);
+    }
+    #[test] fn unknown_command() {
+        match Codec::new().decode(&mut BytesMut::from(&frame(r#"{"type":"request","id":4,"command":{"command":"fly"}}"#)[..])) {
+            Ok(Some(Incoming::Invalid { id: Some(4), .. })) => (),
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+    #[test] fn oversized_frame() {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&(MAX_FRAME_LEN + 1).to_be_bytes());
+        assert!(Codec::new().decode(&mut BytesMut::from(&data[..])).is_err());
+    }
+
+    #[test]
+    fn encode_result() {
+        let mut codec = Codec::new();
+        let mut bytes = BytesMut::from(&frame(r#"{"type":"hello","version":1}"#)[..]);
+        codec.decode(&mut bytes).unwrap();
+
+        let mut out = BytesMut::new();
+        codec.encode((Some(7), ControlCmdOut::Ack), &mut out).unwrap();
+        let json = br#"{"type":"result","id":7,"result":{"Ok":{"reply":"done"}}}"#;
+        assert_eq!(&out[..4], &(json.len() as u32).to_be_bytes());
+        assert_eq!(&out[4..], &json[..]);
+    }
+
+    #[test]
+    fn encode_qemu_event() {
+        let mut codec = Codec::new();
+        codec.decode(&mut BytesMut::from(&frame(r#"{"type":"hello","version":1}"#)[..])).unwrap();
+
+        let mut out = BytesMut::new();
+        let reset = QemuEvent::Reset { guest: true, reason: "guest-reset".to_owned() };
+        codec.encode((None, ControlCmdOut::QemuEvent(reset)), &mut out).unwrap();
+        let json = br#"{"type":"event","event":{"event":"qemu","qemu":{"name":"RESET","guest":true,"reason":"guest-reset"}}}"#;
+        assert_eq!(&out[4..], &json[..]);
+    }
+
+    #[test]
+    fn encode_legacy() {
+        let mut codec = Codec::new();
+        codec.decode(&mut BytesMut::from(&[13][..])).unwrap();
+
+        let mut out = BytesMut::new();
+        for cmd in vec![
+            ControlCmdOut::MouseEdged { x: 1, y: -2 },
+            ControlCmdOut::TemporaryLightAttached,
+            ControlCmdOut::ShutdownProgress(ShutdownStage::Acpi),
+            ControlCmdOut::TemporaryLightDetached,
+            ControlCmdOut::Error(ErrorKind::Refused, "no".to_owned()),
+            ControlCmdOut::Ack,
+        ] {
+            codec.encode((None, cmd), &mut out).unwrap();
+        }
+        // exactly what the old driver sent
+        assert_eq!(&out[..], &[1, 1, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff, 2, 3, 4]);
+    }
 }
-*/
@@ -1,8 +1,10 @@
 mod codec;
+pub mod access;
 
 pub use self::codec::{ControlCmdOut, ControlCmdIn};
 
 use std::io::Error;
+use std::os::unix::io::AsRawFd;
 use std::rc::Rc;
 use std::cell::RefCell;
 
@@ -11,23 +13,81 @@ use futures::unsync::mpsc;
 use futures03::{SinkExt, StreamExt, TryStreamExt};
 use futures03::compat::Future01CompatExt;
 
+use common::config::AccessConfig;
+use tokio::sync::broadcast;
+use windows_gaming_client::protocol::{ErrorKind, VERSION};
+
 use crate::controller::Controller;
 use tokio::net::UnixListener;
 use tokio_stream::wrappers::UnixListenerStream;
 use tokio_util::codec::Decoder;
-use self::codec::Codec;
+use self::codec::{Codec, Incoming, Outgoing};
+use self::access::Peer;
 
 type Handler<'a> = Box<dyn Future<Item=(), Error=Error> + 'a>;
+type Replies = Rc<RefCell<mpsc::UnboundedSender<Outgoing>>>;
 
-pub fn create<'a>(socket: UnixListener, controller: Rc<RefCell<Controller>>) -> Handler<'a> {
+pub fn create<'a>(socket: UnixListener, controller: Rc<RefCell<Controller>>, access: AccessConfig,
+                  qmp_events: broadcast::Sender<serde_json::Value>) -> Handler<'a> {
+    let access = Rc::new(access);
+    let owner = unsafe { libc::getuid() };
     let handler = UnixListenerStream::new(socket).compat().for_each(move |socket| {
-        let (writer, reader) = Codec.framed(socket).split();
+        // the socket is world writable, so everyone has to be checked
+        let peer = match socket.peer_cred() {
+            Ok(cred) => Peer::new(socket.as_raw_fd(), cred.pid(), cred.uid(), cred.gid()),
+            Err(e) => {
+                warn!("Can't tell who connected to the control socket, hanging up: {}", e);
+                return Ok(());
+            }
+        };
+        debug!("Control connection from {:?}", peer);
+        let access = access.clone();
+        let qmp_events = qmp_events.clone();
+        let (writer, reader) = Codec::new().framed(socket).split();
         let (sender, recv) = mpsc::unbounded();
         let sender = Rc::new(RefCell::new(sender));
         let writer = writer.compat().sink_map_err(|_| ()).send_all(recv).map_err(|_| ()).map(|_| ());
         let controller_rc = controller.clone();
         let mut temp_entry = false;
+        let mut handshake_done = false;
         let reader = reader.compat().map_err(|_| ()).for_each(move |req| {
+            let (id, req) = match req {
+                Incoming::Hello { version } => {
+                    let reply = match version {
+                        0 => ControlCmdOut::Error(ErrorKind::UnsupportedVersion,
+                                                  format!("protocol version {} is not supported", version)),
+                        _ => {
+                            handshake_done = true;
+                            ControlCmdOut::Hello(version.min(VERSION))
+                        }
+                    };
+                    reply_to(&sender, None, reply);
+                    return Box::new(future::ok(())) as Box<dyn Future<Item=_, Error=_>>;
+                }
+                Incoming::Invalid { id, error } => {
+                    reply_to(&sender, id, ControlCmdOut::Error(ErrorKind::InvalidRequest, error));
+                    return Box::new(future::ok(()));
+                }
+                Incoming::Command { id: Some(id), .. } if !handshake_done => {
+                    reply_to(&sender, Some(id), ControlCmdOut::Error(ErrorKind::InvalidRequest,
+                                                                     "say hello first".to_owned()));
+                    return Box::new(future::ok(()));
+                }
+                Incoming::Command { id, cmd } => (id, cmd),
+            };
+
+            let permission = access::required(&req);
+            if !access::allowed(&access, owner, &peer, permission) {
+                warn!("Denied {:?} to uid {} (pid {:?})", req, peer.uid, peer.pid);
+                if id.is_none() {
+                    // legacy clients can't be told, so they just get hung up on
+                    return Box::new(future::err(()));
+                }
+                reply_to(&sender, id, ControlCmdOut::Error(ErrorKind::PermissionDenied,
+                                                           format!("permission denied, this needs the {:?} permission", permission)));
+                return Box::new(future::ok(()));
+            }
+
             let mut controller = controller_rc.borrow_mut();
             info!("Control request: {:?}", req);
             if temp_entry {
@@ -35,45 +95,200 @@ pub fn create<'a>(socket: UnixListener, controller: Rc<RefCell<Controller>>) ->
                     ControlCmdIn::IoExit => {
                         controller.temporary_exit();
                         temp_entry = false;
+                        ack(&sender, id);
                     }
                     _ => {
                         controller.temporary_exit();
-                        return Box::new(future::err(())) as Box<dyn Future<Item=_, Error=_>>;
+                        return Box::new(future::err(()));
                     }
                 }
                 return Box::new(future::ok(()));
             }
             match req {
-                ControlCmdIn::IoEntry => controller.io_attach(),
-                ControlCmdIn::Shutdown => controller.shutdown(),
-                ControlCmdIn::ForceIoEntry => controller.io_force_attach(),
-                ControlCmdIn::IoExit => controller.io_detach(),
-                ControlCmdIn::Suspend => return controller.suspend(),
-                ControlCmdIn::TryIoEntry => controller.try_attach(),
+                ControlCmdIn::IoEntry => {
+                    controller.io_attach();
+                    ack_when_usb_done(&sender, id, &controller);
+                    return Box::new(future::ok(()));
+                }
+                ControlCmdIn::Shutdown => {
+                    controller.shutdown();
+                    // keep the client posted on how the shutdown is going
+                    let mut progress = controller.shutdown_progress();
+                    let sender = sender.clone();
+                    tokio::task::spawn_local(async move {
+                        loop {
+                            let stage = *progress.borrow();
+                            if let Some(stage) = stage {
+                                if sender.borrow().unbounded_send((None, ControlCmdOut::ShutdownProgress(stage))).is_err() {
+                                    return;
+                                }
+                            }
+                            if progress.changed().await.is_err() {
+                                return;
+                            }
+                        }
+                    });
+                }
+                ControlCmdIn::ForceIoEntry => {
+                    controller.io_force_attach();
+                    ack_when_usb_done(&sender, id, &controller);
+                    return Box::new(future::ok(()));
+                }
+                ControlCmdIn::IoExit => {
+                    controller.io_detach();
+                    ack_when_usb_done(&sender, id, &controller);
+                    return Box::new(future::ok(()));
+                }
+                ControlCmdIn::Suspend => {
+                    let sender = sender.clone();
+                    return Box::new(controller.suspend().then(move |res| {
+                        match res {
+                            Ok(()) => ack(&sender, id),
+                            Err(()) if id.is_some() => reply_to(&sender, id, ControlCmdOut::Error(
+                                ErrorKind::Failed, "Windows didn't suspend".to_owned())),
+                            Err(()) => (),
+                        }
+                        Ok(())
+                    }));
+                }
+                ControlCmdIn::TryIoEntry => {
+                    controller.try_attach();
+                    ack_when_usb_done(&sender, id, &controller);
+                    return Box::new(future::ok(()));
+                }
                 ControlCmdIn::LightEntry => controller.light_attach(),
                 ControlCmdIn::TemporaryLightEntry { x, y } => {
                     let (send, receiver) = mpsc::unbounded();
                     let res = controller.temporary_entry(send, x, y);
                     if !res {
-                        warn!("Temporary entry failed, closing connection");
-                        return Box::new(future::err(()));
+                        warn!("Temporary entry failed");
+                        if id.is_none() {
+                            // legacy clients can't be told, so they just get hung up on
+                            return Box::new(future::err(()));
+                        }
+                        reply_to(&sender, id, ControlCmdOut::Error(ErrorKind::Refused,
+                                                                   "input is attached already".to_owned()));
+                        return Box::new(future::ok(()));
                     }
-                    (&*sender.borrow()).unbounded_send(ControlCmdOut::TemporaryLightAttached).unwrap();
+                    reply_to(&sender, None, ControlCmdOut::TemporaryLightAttached);
                     temp_entry = true;
-                    let receiver = receiver.map_err(|_| ());
+                    let receiver = receiver.map(|data| (None, data)).map_err(|_| ());
                     let controller = controller_rc.clone();
                     let sender = sender.clone();
                     let sender2 = sender.clone();
                     tokio::task::spawn_local(receiver.for_each(move |data| (&*sender.borrow()).unbounded_send(data).map_err(|_| ()))
                         .then(move |_| {
                             controller.borrow_mut().temporary_exit();
-                            let _ = (&*sender2.borrow()).unbounded_send(ControlCmdOut::TemporaryLightDetached);
+                            let _ = (&*sender2.borrow()).unbounded_send((None, ControlCmdOut::TemporaryLightDetached));
                             Ok::<(), ()>(())
                         }).compat());
                 }
-                ControlCmdIn::EnterBackupMode => controller.enter_backup_mode(send_ack_when_ready(sender.clone())),
-                ControlCmdIn::LeaveBackupMode => controller.leave_backup_mode(send_ack_when_ready(sender.clone())),
+                // these always got an ack, even in the legacy protocol
+                ControlCmdIn::EnterBackupMode => {
+                    controller.enter_backup_mode(send_ack_when_ready(sender.clone(), id));
+                    return Box::new(future::ok(()));
+                }
+                ControlCmdIn::LeaveBackupMode => {
+                    controller.leave_backup_mode(send_ack_when_ready(sender.clone(), id));
+                    return Box::new(future::ok(()));
+                }
+                ControlCmdIn::Pause => controller.pause(),
+                ControlCmdIn::Resume => controller.resume(),
+                ControlCmdIn::Status => {
+                    let status = controller.status().borrow().clone();
+                    let uptime = controller.uptime().as_secs();
+                    reply_to(&sender, id, ControlCmdOut::Status { status, uptime });
+                    return Box::new(future::ok(()));
+                }
+                ControlCmdIn::Subscribe => {
+                    let mut status = controller.status();
+                    let sender = sender.clone();
+                    tokio::task::spawn_local(async move {
+                        loop {
+                            let current = status.borrow().clone();
+                            if sender.borrow().unbounded_send((None, ControlCmdOut::StatusChanged(current))).is_err() {
+                                return;
+                            }
+                            if status.changed().await.is_err() {
+                                return;
+                            }
+                        }
+                    });
+                }
+                ControlCmdIn::Qmp { execute, arguments } => {
+                    if !access::qmp_allowed(&access, &execute) {
+                        warn!("Denied QMP command {} to uid {} (pid {:?})", execute, peer.uid, peer.pid);
+                        reply_to(&sender, id, ControlCmdOut::Error(ErrorKind::PermissionDenied,
+                                                                   format!("{} is not in qmp_commands", execute)));
+                        return Box::new(future::ok(()));
+                    }
+                    let (tx, rx) = tokio::sync::oneshot::channel();
+                    controller.qmp(execute, arguments, tx);
+                    let sender = sender.clone();
+                    tokio::task::spawn_local(async move {
+                        let reply = match rx.await {
+                            Ok(Ok(result)) => ControlCmdOut::QmpReturn(result),
+                            Ok(Err(e)) => ControlCmdOut::Error(ErrorKind::Failed, e),
+                            Err(_) => ControlCmdOut::Error(ErrorKind::Failed, "qemu went away".to_owned()),
+                        };
+                        reply_to(&sender, id, reply);
+                    });
+                    return Box::new(future::ok(()));
+                }
+                ControlCmdIn::QmpEvents => {
+                    let mut events = qmp_events.subscribe();
+                    let sender = sender.clone();
+                    tokio::task::spawn_local(async move {
+                        loop {
+                            let event = match events.recv().await {
+                                Ok(event) => event,
+                                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                                    warn!("A QMP event subscriber missed {} events", missed);
+                                    continue;
+                                }
+                                Err(broadcast::error::RecvError::Closed) => return,
+                            };
+                            if sender.borrow().unbounded_send((None, ControlCmdOut::QmpEvent(event))).is_err() {
+                                return;
+                            }
+                        }
+                    });
+                }
+                ControlCmdIn::QemuEvents => {
+                    let mut events = controller.qemu_events();
+                    let sender = sender.clone();
+                    tokio::task::spawn_local(async move {
+                        loop {
+                            let event = match events.recv().await {
+                                Ok(event) => event,
+                                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                                    warn!("A qemu event subscriber missed {} events", missed);
+                                    continue;
+                                }
+                                Err(broadcast::error::RecvError::Closed) => return,
+                            };
+                            if sender.borrow().unbounded_send((None, ControlCmdOut::QemuEvent(event))).is_err() {
+                                return;
+                            }
+                        }
+                    });
+                }
+                ControlCmdIn::Save => {
+                    let (tx, rx) = tokio::sync::oneshot::channel();
+                    controller.save(tx);
+                    let sender = sender.clone();
+                    tokio::task::spawn_local(async move {
+                        let reply = match rx.await {
+                            Ok(Ok(())) => ControlCmdOut::Ack,
+                            Ok(Err(e)) => ControlCmdOut::Error(ErrorKind::Refused, e),
+                            Err(_) => return,
+                        };
+                        reply_to(&sender, id, reply);
+                    });
+                    return Box::new(future::ok(()));
+                }
             }
+            ack(&sender, id);
             Box::new(future::ok(()))
         }).then(|_| Ok(()));
 
@@ -82,12 +297,191 @@ pub fn create<'a>(socket: UnixListener, controller: Rc<RefCell<Controller>>) ->
     });
     Box::new(handler)
 }
-fn send_ack_when_ready(sender: Rc<RefCell<futures::unsync::mpsc::UnboundedSender<ControlCmdOut>>>) -> tokio::sync::oneshot::Sender<()> {
+
+/// Acknowledges a framed request, legacy requests never got acks for this.
+fn ack(sender: &Replies, id: Option<u64>) {
+    if id.is_some() {
+        reply_to(sender, id, ControlCmdOut::Ack);
+    }
+}
+
+/// Acknowledges a framed request once qemu attached or removed the USB devices, or reports the ones it didn't.
+fn ack_when_usb_done(sender: &Replies, id: Option<u64>, controller: &Controller) {
+    if id.is_none() {
+        return;
+    }
+    let done = controller.usb_changes();
+    let sender = sender.clone();
+    tokio::task::spawn_local(async move {
+        let reply = match done.await {
+            Ok(()) => ControlCmdOut::Ack,
+            Err(e) => ControlCmdOut::Error(ErrorKind::Failed, e),
+        };
+        reply_to(&sender, id, reply);
+    });
+}
+
+fn reply_to(sender: &Replies, id: Option<u64>, reply: ControlCmdOut) {
+    let _ = sender.borrow().unbounded_send((id, reply));
+}
+
+fn send_ack_when_ready(sender: Replies, id: Option<u64>) -> tokio::sync::oneshot::Sender<()> {
     let (tx, rx) = tokio::sync::oneshot::channel();
     tokio::task::spawn_local(async move {
-        if rx.await.is_ok() {
-            let _ = sender.borrow().unbounded_send(ControlCmdOut::Ack);
+        match rx.await {
+            Ok(()) => reply_to(&sender, id, ControlCmdOut::Ack),
+            // e.g. a blocking hook said no, legacy clients are left waiting like they always were
+            Err(_) if id.is_some() => reply_to(&sender, id, ControlCmdOut::Error(ErrorKind::Failed,
+                                                                               "the driver gave up".to_owned())),
+            Err(_) => (),
         }
     });
     tx
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    use futures::Stream as _;
+    use windows_gaming_client::Client;
+    use windows_gaming_client::protocol::{AttachMode, Command, Event, GaStatus, IoMode, QemuEvent, Reply};
+
+    use crate::controller::testing;
+    use crate::dbus::testing::block_on;
+    use crate::monitor::QmpCommand;
+
+    /// Runs the control handler on a fresh socket and passes its path and controller to `test`.
+    fn with_handler<F, T>(name: &str, test: F)
+        where F: FnOnce(PathBuf, Rc<RefCell<Controller>>, futures::unsync::mpsc::UnboundedReceiver<QmpCommand>) -> T,
+              T: std::future::Future<Output=()>
+    {
+        let dir = std::env::temp_dir().join(format!("windows-gaming-control-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("control.sock");
+        block_on(async {
+            let (controller, monitor_rx) = testing::fake();
+            let handler = create(UnixListener::bind(&path).unwrap(), controller.clone(), AccessConfig::default(),
+                                 broadcast::channel(1).0).compat();
+            tokio::select! {
+                _ = handler => panic!("control handler went down"),
+                _ = test(path.clone(), controller, monitor_rx) => (),
+            }
+        });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn request_and_status() {
+        with_handler("request", |path, controller, monitor_rx| async move {
+            let mut client = Client::connect(&path).await.unwrap().expect("handler isn't listening");
+            assert_eq!(client.version, VERSION);
+
+            let status = match client.request(Command::Status).await.unwrap().unwrap() {
+                Reply::Status { status, .. } => status,
+                other => panic!("unexpected reply {:?}", other),
+            };
+            assert_eq!((status.ga, status.io, status.paused), (GaStatus::Down, IoMode::Detached, false));
+
+            assert_eq!(client.request(Command::Pause).await.unwrap(), Ok(Reply::Done));
+            assert!(matches!(monitor_rx.wait().next(), Some(Ok(QmpCommand::Stop))));
+            // only qemu stopping the VM makes it paused
+            match client.request(Command::Status).await.unwrap().unwrap() {
+                Reply::Status { status, .. } => assert!(!status.paused),
+                other => panic!("unexpected reply {:?}", other),
+            }
+            controller.borrow_mut().qemu_event(QemuEvent::Stop);
+
+            match client.request(Command::Status).await.unwrap().unwrap() {
+                Reply::Status { status, .. } => assert!(status.paused),
+                other => panic!("unexpected reply {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn subscribe() {
+        with_handler("subscribe", |path, controller, _monitor_rx| async move {
+            let mut subscriber = Client::connect(&path).await.unwrap().unwrap();
+            subscriber.subscribe().await.unwrap().unwrap();
+            match subscriber.next_event().await.unwrap() {
+                Some(Event::Status { status }) => assert!(!status.paused),
+                other => panic!("unexpected event {:?}", other),
+            }
+
+            // changes made by someone else show up as well
+            let mut client = Client::connect(&path).await.unwrap().unwrap();
+            assert_eq!(client.request(Command::Pause).await.unwrap(), Ok(Reply::Done));
+            controller.borrow_mut().qemu_event(QemuEvent::Stop);
+            match subscriber.next_event().await.unwrap() {
+                Some(Event::Status { status }) => assert!(status.paused),
+                other => panic!("unexpected event {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn attach_without_guest_agent() {
+        with_handler("attach", |path, _controller, _monitor_rx| async move {
+            let mut client = Client::connect(&path).await.unwrap().unwrap();
+            // without the guest agent, trying to attach does nothing
+            let reply = client.request(Command::Attach { mode: AttachMode::Try }).await.unwrap();
+            assert_eq!(reply, Ok(Reply::Done));
+            match client.request(Command::Status).await.unwrap().unwrap() {
+                Reply::Status { status, .. } => assert_eq!(status.io, IoMode::Detached),
+                other => panic!("unexpected reply {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn qmp_allow_list() {
+        with_handler("qmp", |path, _controller, monitor_rx| async move {
+            let mut client = Client::connect(&path).await.unwrap().unwrap();
+            let quit = Command::Qmp { execute: "quit".to_owned(), arguments: None };
+            match client.request(quit).await.unwrap() {
+                Err(e) => assert_eq!(e.kind, ErrorKind::PermissionDenied),
+                other => panic!("unexpected reply {:?}", other),
+            }
+
+            // allowed commands go to the monitor
+            let query = Command::Qmp { execute: "query-status".to_owned(), arguments: None };
+            let reply = tokio::task::spawn_local(async move { client.request(query).await.unwrap() });
+            match futures03::compat::Stream01CompatExt::compat(monitor_rx).next().await {
+                Some(Ok(QmpCommand::Raw { execute, ack, .. })) => {
+                    assert_eq!(execute, "query-status");
+                    ack.send(Ok(serde_json::json!({ "status": "running" }))).unwrap();
+                }
+                _ => panic!("expected a raw QMP command"),
+            }
+            let result = serde_json::json!({ "status": "running" });
+            assert_eq!(reply.await.unwrap(), Ok(Reply::Qmp { result }));
+        });
+    }
+
+    #[test]
+    fn legacy_clients() {
+        with_handler("legacy", |path, controller, monitor_rx| async move {
+            use tokio::io::AsyncWriteExt;
+
+            // the old CLI just wrote the opcode and hung up
+            let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+            stream.write_all(&[11]).await.unwrap(); // pause
+            drop(stream);
+
+            let stop = futures03::compat::Stream01CompatExt::compat(monitor_rx).next().await;
+            assert!(matches!(stop, Some(Ok(QmpCommand::Stop))));
+            controller.borrow_mut().qemu_event(QemuEvent::Stop);
+
+            let mut client = Client::connect(&path).await.unwrap().unwrap();
+            match client.request(Command::Status).await.unwrap().unwrap() {
+                Reply::Status { status, .. } => assert!(status.paused),
+                other => panic!("unexpected reply {:?}", other),
+            }
+        });
+    }
+}
@@ -1,24 +1,40 @@
+use std::borrow::Cow;
 use std::mem;
-use std::path::Path;
-use std::rc::Rc;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
 use std::ffi::OsStr;
 use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use clientpipe_proto::ClipboardTypes;
 use itertools::Itertools;
-use libudev::{Result as UdevResult, Context, Enumerator};
+use libudev::{Context, Enumerator};
 use futures::unsync::mpsc::UnboundedSender;
 use futures::unsync::oneshot::{self, Sender};
 use futures::Future;
 use futures::future;
+use futures03::compat::Future01CompatExt as _;
+use futures03::future::{FutureExt as _, LocalBoxFuture, Shared};
+use futures03::TryFutureExt as _;
 
-use common::config::{Action, HooksConfig, HotKeyAction, MachineConfig, UsbBinding, UsbId, UsbPort};
+use common::config::{Action, BackupConfig, HotKeyAction, MachineConfig, ShutdownConfig, UsbBinding, UsbId, UsbPort,
+                     WatchdogConfig};
 use common::util;
 use tokio::process::Command;
+use tokio::sync::{broadcast, watch};
 use crate::clientpipe::{GaCmdOut, ClipboardMessage, ClipboardType, RegisterHotKey, Point};
 use crate::control::ControlCmdOut;
-use crate::monitor::{QmpCommand};
+use crate::monitor::{InputEvent, KeyValue, QmpCommand};
 use crate::sd_notify;
+use crate::Outcome;
+use crate::backup;
+use crate::hooks::{Hook, Hooks};
+use crate::shutdown::{self, ShutdownStage};
+use windows_gaming_client::protocol::{GaStatus, QemuEvent, Status, UsbDevice};
+pub use windows_gaming_client::protocol::IoMode;
+use crate::instance::{AttachedUsb, InstanceState};
 use crate::libinput::Input;
 use crate::clipboard::{ClipboardRequestEvent, ClipboardRequestResponse};
 
@@ -40,6 +56,19 @@ enum State {
     Resuming(bool), // true = full, false = light
 }
 
+/// A batch of USB device changes, resolving to the devices that couldn't be attached or removed.
+pub type UsbChanges = Shared<LocalBoxFuture<'static, Result<(), String>>>;
+
+/// A host USB device to be added to qemu.
+struct UsbHost {
+    id: String,
+    binding: UsbBinding,
+    bus: String,
+    port: usize,
+    hostbus: u64,
+    hostaddr: u64,
+}
+
 #[derive(Clone)]
 enum IoState {
     Detached,
@@ -51,12 +80,45 @@ enum IoState {
 
 pub struct Controller {
     machine_config: MachineConfig,
-    hooks_config: HooksConfig,
+    hooks: Rc<Hooks>,
+    backup_config: BackupConfig,
+    shutdown_config: ShutdownConfig,
+    watchdog_config: WatchdogConfig,
 
     ga: State,
+    paused: bool,
+    backup_mode: bool,
+    // USB devices attached by full entry
+    attached_usb: Vec<AttachedUsb>,
+    // removals waiting for qemu's DEVICE_DELETED, by device id
+    pending_removals: HashMap<String, tokio::sync::oneshot::Sender<()>>,
+    // the latest USB changes, every batch waits for the previous one so ids are free again before they're reused
+    usb_changes: UsbChanges,
+    status: watch::Sender<Status>,
+    status_rx: watch::Receiver<Status>,
+    // pings in a row the GA didn't answer
+    missed_pings: u32,
     io_state: IoState,
+    io_mode: watch::Sender<IoMode>,
+    // receivers are kept around so sending never fails for lack of subscribers
+    io_mode_rx: watch::Receiver<IoMode>,
     // senders to be sent to when windows finished suspending
     suspend_senders: Vec<Sender<()>>,
+    shutdown_progress: Rc<watch::Sender<Option<ShutdownStage>>>,
+    shutdown_progress_rx: watch::Receiver<Option<ShutdownStage>>,
+    // where the VM state goes on save
+    save_file: PathBuf,
+    // whether qemu is restoring from save_file
+    restoring: bool,
+    // whether windows is going down because we told it to
+    stop_requested: bool,
+    guest_crashed: bool,
+    // persisted so a new driver can take over if we die
+    instance: InstanceState,
+    runtime_dir: PathBuf,
+    qemu_events: broadcast::Sender<QemuEvent>,
+    // for picking up where we left off once a blocking hook is done
+    this: Weak<RefCell<Controller>>,
 
     input: Rc<RefCell<Input>>,
 
@@ -72,24 +134,74 @@ pub struct Controller {
 
 impl Controller {
     fn write_ga<C: Into<GaCmdOut>>(&mut self, cmd: C) {
-        (&self.clientpipe).unbounded_send(cmd.into()).unwrap();
+        if (&self.clientpipe).unbounded_send(cmd.into()).is_err() {
+            warn!("Can't talk to the guest agent anymore, dropping message");
+        }
+    }
+
+    fn write_monitor(&self, cmd: QmpCommand) {
+        if (&self.monitor).unbounded_send(cmd).is_err() {
+            warn!("Qemu is gone, dropping command");
+        }
     }
 
     pub fn new(machine_config: MachineConfig,
-               hooks_config: HooksConfig,
+               hooks: Rc<Hooks>,
+               backup_config: BackupConfig,
+               shutdown_config: ShutdownConfig,
+               watchdog_config: WatchdogConfig,
+               save_file: PathBuf,
+               restoring: bool,
+               runtime_dir: PathBuf,
+               instance: InstanceState,
                monitor: UnboundedSender<QmpCommand>,
                clientpipe: UnboundedSender<GaCmdOut>,
                input: Rc<RefCell<Input>>,
                x11_clipboard: UnboundedSender<ClipboardRequestResponse>,
                x11_clipboard_grabber: UnboundedSender<ClipboardTypes>,
-               x11_clipboard_reader: UnboundedSender<ClipboardType>) -> Controller {
+               x11_clipboard_reader: UnboundedSender<ClipboardType>,
+               this: Weak<RefCell<Controller>>) -> Controller {
+        let (io_mode, io_mode_rx) = watch::channel(IoMode::Detached);
+        let (shutdown_progress, shutdown_progress_rx) = watch::channel(None);
+        let backup_mode = machine_config.storage.iter().any(|d| !d.snapshot_chain().is_empty());
+        let (status, status_rx) = watch::channel(Status {
+            ga: GaStatus::Down,
+            io: IoMode::Detached,
+            usb_devices: Vec::new(),
+            paused: false,
+            backup: backup_mode,
+            shutting_down: false,
+        });
         Controller {
             machine_config,
-            hooks_config,
+            hooks,
+            backup_config,
+            shutdown_config,
+            watchdog_config,
 
             ga: State::Down,
+            paused: false,
+            backup_mode,
+            attached_usb: Vec::new(),
+            pending_removals: HashMap::new(),
+            usb_changes: futures03::future::ready(Ok(())).boxed_local().shared(),
+            status,
+            status_rx,
+            missed_pings: 0,
             io_state: IoState::Detached,
+            io_mode,
+            io_mode_rx,
             suspend_senders: Vec::new(),
+            shutdown_progress: Rc::new(shutdown_progress),
+            shutdown_progress_rx,
+            save_file,
+            restoring,
+            stop_requested: false,
+            guest_crashed: false,
+            instance,
+            runtime_dir,
+            qemu_events: broadcast::channel(64).0,
+            this,
 
             monitor,
             clientpipe,
@@ -102,13 +214,169 @@ impl Controller {
         }
     }
 
+    fn set_io_state(&mut self, state: IoState) {
+        let mode = match state {
+            IoState::Detached => IoMode::Detached,
+            IoState::LightEntry | IoState::TemporaryLightEntry(_) | IoState::AwaitingUpgrade => IoMode::LightEntry,
+            IoState::FullEntry => IoMode::FullEntry,
+        };
+        self.io_state = state;
+        if *self.io_mode_rx.borrow() != mode {
+            let _ = self.io_mode.send(mode);
+        }
+        self.publish_status();
+    }
+
+    fn set_ga(&mut self, state: State) {
+        let previous = mem::replace(&mut self.ga, state);
+        self.publish_status();
+        self.ga_changed(previous);
+    }
+
+    /// Runs the hooks for the GA coming up or going down.
+    fn ga_changed(&mut self, previous: State) {
+        let up = |state| match state {
+            State::Up | State::Pinging => true,
+            _ => false,
+        };
+        if !up(previous) && up(self.ga) {
+            self.fire_hook(Hook::GaUp, serde_json::Value::Null);
+        } else if up(previous) && self.ga == State::Down {
+            self.fire_hook(Hook::GaDown, serde_json::Value::Null);
+        }
+    }
+
+    /// Runs the hooks for `hook`, `details` tells them what happened beyond what's in the status.
+    fn run_hook(&self, hook: Hook, details: serde_json::Value) -> LocalBoxFuture<'static, Result<(), String>> {
+        self.hooks.run(hook, &self.status_rx.borrow(), details).boxed_local()
+    }
+
+    /// Runs the hooks for `hook` in the background.
+    fn fire_hook(&self, hook: Hook, details: serde_json::Value) {
+        self.hooks.fire(hook, &self.status_rx.borrow(), details);
+    }
+
+    /// Runs `then` once the blocking hooks for `hook` succeeded.
+    fn after_hook<T: 'static, F>(&self, hook: Hook, then: F) -> LocalBoxFuture<'static, Result<T, String>>
+        where F: FnOnce(&mut Controller) -> T + 'static
+    {
+        let run = self.run_hook(hook, serde_json::Value::Null);
+        let this = self.this.clone();
+        async move {
+            if let Err(e) = run.await {
+                error!("The blocking {} hook failed, not going ahead: {}", hook.name(), e);
+                return Err(format!("the {} hook failed: {}", hook.name(), e));
+            }
+            let this = this.upgrade().ok_or_else(|| "the driver is going down".to_owned())?;
+            let res = then(&mut this.borrow_mut());
+            Ok(res)
+        }.boxed_local()
+    }
+
+    fn publish_status(&mut self) {
+        let status = Status {
+            ga: match self.ga {
+                State::Down => GaStatus::Down,
+                State::Up | State::Pinging => GaStatus::Up,
+                State::Suspending => GaStatus::Suspending,
+                State::Suspended => GaStatus::Suspended,
+                State::Resuming(_) => GaStatus::Resuming,
+            },
+            io: *self.io_mode_rx.borrow(),
+            usb_devices: self.attached_usb.iter()
+                .map(|dev| UsbDevice { id: dev.id.clone(), binding: dev.binding.to_string() }).collect(),
+            paused: self.paused,
+            backup: self.backup_mode,
+            shutting_down: self.shutdown_progress_rx.borrow().is_some(),
+        };
+        if *self.status_rx.borrow() != status {
+            debug!("Status: {}", status);
+            self.persist_instance(&status);
+            let _ = self.status.send(status);
+        }
+    }
+
+    fn persist_instance(&mut self, status: &Status) {
+        let instance = InstanceState {
+            ga_up: status.ga != GaStatus::Down,
+            io_mode: status.io,
+            attached_usb: self.attached_usb.clone(),
+            ..self.instance.clone()
+        };
+        if instance != self.instance {
+            if let Err(e) = instance.save(&self.runtime_dir) {
+                warn!("Failed to save the instance state: {}", e);
+            }
+            self.instance = instance;
+        }
+    }
+
+    /// Picks up where the driver that started qemu left off.
+    ///
+    /// Light entry is not restored since its input grabs died with the previous driver.
+    /// Returns whether the guest agent was up, in which case it needs to be pinged again.
+    pub fn reattach(&mut self) -> bool {
+        info!("Reattaching to {:?}", self.instance);
+        // publishing overwrites the persisted state, so read it all first
+        let previous = self.instance.clone();
+        match previous.io_mode {
+            IoMode::FullEntry => {
+                self.attached_usb = previous.attached_usb.clone();
+                self.set_io_state(IoState::FullEntry);
+            }
+            // keys that were down when the previous driver died would stay stuck otherwise
+            IoMode::LightEntry => self.write_monitor(QmpCommand::ReleaseAllKeys),
+            IoMode::Detached => (),
+        }
+        if previous.ga_up {
+            self.set_ga(State::Up);
+        }
+        self.publish_status();
+        previous.ga_up
+    }
+
+    /// Subscribes to changes of the driver status.
+    pub fn status(&self) -> watch::Receiver<Status> {
+        self.status_rx.clone()
+    }
+
+    /// When qemu was started, in seconds since the epoch.
+    pub fn started(&self) -> u64 {
+        self.instance.started
+    }
+
+    /// How long qemu has been running.
+    pub fn uptime(&self) -> Duration {
+        let started = UNIX_EPOCH + Duration::from_secs(self.started());
+        SystemTime::now().duration_since(started).unwrap_or_default()
+    }
+
+    /// Subscribes to changes of the IO mode.
+    pub fn io_mode(&self) -> watch::Receiver<IoMode> {
+        self.io_mode_rx.clone()
+    }
+
+    /// How often the GA should be pinged
+    pub fn ga_ping_interval(&self) -> Duration {
+        Duration::from_secs(self.watchdog_config.ga_ping_interval.max(1))
+    }
+
     pub fn ga_ping(&mut self) -> bool {
         // the idea is that someone else (timer) calls this periodically
         match self.ga {
             State::Pinging => {
                 // the last ping wasn't even answered
+                self.missed_pings += 1;
+                if self.missed_pings < self.watchdog_config.ga_missed_pings {
+                    warn!("GA missed {} ping(s) in a row", self.missed_pings);
+                    self.write_ga(GaCmdOut::Ping(()));
+                    return true;
+                }
+
                 // we conclude that the ga has died
-                self.ga = State::Down;
+                warn!("GA missed {} ping(s) in a row, considering it dead", self.missed_pings);
+                self.missed_pings = 0;
+                self.set_ga(State::Down);
                 match self.io_state {
                     IoState::FullEntry => self.io_detach(),
                     IoState::TemporaryLightEntry(_) => self.temporary_exit(),
@@ -117,7 +385,7 @@ impl Controller {
                 false
             }
             State::Up => {
-                self.ga = State::Pinging;
+                self.set_ga(State::Pinging);
                 self.write_ga(GaCmdOut::Ping(()));
                 true
             }
@@ -126,8 +394,9 @@ impl Controller {
     }
 
     pub fn ga_pong(&mut self) {
+        self.missed_pings = 0;
         if self.ga == State::Pinging {
-            self.ga = State::Up;
+            self.set_ga(State::Up);
         }
     }
 
@@ -141,8 +410,10 @@ impl Controller {
 
         // send GA all hotkeys we want to register
         for (i, hotkey) in self.machine_config.hotkeys.clone().into_iter().enumerate() {
-            let (modifiers, key) = hotkey.key.to_windows();
-            self.write_ga(RegisterHotKey { id: i as u32, modifiers, key });
+            match hotkey.key.to_windows() {
+                Some((modifiers, key)) => self.write_ga(RegisterHotKey { id: i as u32, modifiers, key }),
+                None => info!("Windows can't catch {}, it only works in light entry", hotkey.key),
+            }
         }
 
         // Whenever a ga_hello message arrives, we know that the GA just started.
@@ -156,6 +427,12 @@ impl Controller {
         // exists in that case so it would be a bug to create a second one.
 
         let ga = mem::replace(&mut self.ga, State::Up);
+        self.missed_pings = 0;
+        self.publish_status();
+        self.ga_changed(ga);
+        if ga == State::Down {
+            self.fire_hook(Hook::Ready, serde_json::Value::Null);
+        }
 
         if let IoState::AwaitingUpgrade = self.io_state {
             self.io_attach();
@@ -177,34 +454,170 @@ impl Controller {
     }
 
     pub fn ga_suspending(&mut self) {
+        // suspends we asked for ran the hook already
+        if self.suspend_senders.is_empty() && self.ga != State::Suspending {
+            self.fire_hook(Hook::Suspend, serde_json::Value::Null);
+        }
         self.io_detach();
-        self.ga = State::Suspending;
+        self.set_ga(State::Suspending);
+    }
+
+    /// Reacts to `event`, runs its hooks and passes it on to subscribers.
+    pub fn qemu_event(&mut self, event: QemuEvent) {
+        match event {
+            QemuEvent::Suspend => self.qemu_suspended(),
+            QemuEvent::Stop => self.qemu_stopped(),
+            QemuEvent::Resume => self.qemu_resumed(),
+            QemuEvent::GuestPanicked { .. } => self.qemu_panicked(),
+            QemuEvent::Shutdown { guest, ref reason } => self.qemu_shutdown(guest, reason),
+            QemuEvent::Reset { guest, ref reason } => self.qemu_reset(guest, reason),
+            QemuEvent::Watchdog { ref action } => self.qemu_watchdog(action),
+            QemuEvent::DeviceDeleted { device: Some(ref id), .. } => {
+                if let Some(removed) = self.pending_removals.remove(id) {
+                    let _ = removed.send(());
+                }
+            }
+            QemuEvent::Wakeup => self.fire_hook(Hook::Resume, serde_json::Value::Null),
+            _ => (),
+        }
+
+        self.hooks.fire_event(&event, &self.status_rx.borrow());
+        // nobody listening is fine
+        let _ = self.qemu_events.send(event);
+    }
+
+    /// Subscribes to the qemu events the driver understands.
+    pub fn qemu_events(&self) -> broadcast::Receiver<QemuEvent> {
+        self.qemu_events.subscribe()
     }
 
     pub fn qemu_suspended(&mut self) {
         info!("Windows is now suspended");
-        self.ga = State::Suspended;
+        self.set_ga(State::Suspended);
         for sender in self.suspend_senders.drain(..) {
             let _ = sender.send(());
         }
     }
 
-    pub fn ga_hotkey(&mut self, index: u32) {
+    /// Does what hotkey `index` is bound to, no matter whether Windows or we caught it.
+    pub fn hotkey(&mut self, index: u32) {
+        if let Some(hotkey) = self.machine_config.hotkeys.get(index as usize) {
+            let details = serde_json::json!({ "index": index, "hotkey": hotkey });
+            self.fire_hook(Hook::Hotkey, details);
+        }
         match self.machine_config.hotkeys.get(index as usize).map(|h| h.action.clone()) {
             None => warn!("Client sent invalid hotkey id"),
-            Some(HotKeyAction::Action(action)) => {
+            Some(action) => self.hotkey_action(action),
+        }
+    }
+
+    /// Does what host hotkey `index` is bound to, but only while detached.
+    ///
+    /// Otherwise the keyboard belongs to Windows or light entry and their hotkeys apply.
+    pub fn host_hotkey(&mut self, index: usize) {
+        if !matches!(self.io_state, IoState::Detached) {
+            return;
+        }
+        match self.machine_config.host_hotkeys.get(index).cloned() {
+            None => warn!("Invalid host hotkey id {}", index),
+            Some(hotkey) => {
+                let details = serde_json::json!({ "index": index, "hotkey": hotkey, "host": true });
+                self.fire_hook(Hook::Hotkey, details);
+                self.hotkey_action(hotkey.action);
+            }
+        }
+    }
+
+    fn hotkey_action(&mut self, action: HotKeyAction) {
+        match action {
+            HotKeyAction::Action(action) => {
                 if let IoState::TemporaryLightEntry(_) = self.io_state {
                     info!("Got action-hotkey while in temporary light entry. Ignoring.");
                     return;
                 }
-                match action {
-                    Action::IoUpgrade => self.io_attach(),
-                    Action::IoEntryForced => self.io_force_attach(),
-                    Action::IoExit => self.io_detach(),
+                self.action(action);
+            }
+            HotKeyAction::Exec(cmd) => {
+                if let Err(e) = Command::new("/bin/sh").arg("-c").arg(&cmd).spawn() {
+                    error!("Failed to run hotkey command `{}`: {}", cmd, e);
                 }
             }
-            Some(HotKeyAction::Exec(cmd)) => {
-                Command::new("/bin/sh").arg("-c").arg(&cmd).spawn().unwrap();
+            HotKeyAction::Sequence(steps) => self.hotkey_sequence(steps.into_iter()),
+            // only delays the rest of a sequence
+            HotKeyAction::Delay(_) => (),
+        }
+    }
+
+    fn hotkey_sequence(&mut self, mut steps: std::vec::IntoIter<HotKeyAction>) {
+        while let Some(step) = steps.next() {
+            if let HotKeyAction::Delay(ms) = step {
+                let this = self.this.clone();
+                tokio::task::spawn_local(async move {
+                    tokio::time::sleep(Duration::from_millis(ms)).await;
+                    if let Some(this) = this.upgrade() {
+                        this.borrow_mut().hotkey_sequence(steps);
+                    }
+                });
+                return;
+            }
+            self.hotkey_action(step);
+        }
+    }
+
+    fn action(&mut self, action: Action) {
+        match action {
+            Action::IoUpgrade => self.io_attach(),
+            Action::IoEntryForced => self.io_force_attach(),
+            Action::IoExit => self.io_detach(),
+            Action::LightEntry => self.light_attach(),
+            Action::ToggleAttach => match self.io_state {
+                IoState::Detached => self.io_attach(),
+                _ => self.io_detach(),
+            },
+            Action::Suspend => {
+                let suspended = self.suspend().compat();
+                tokio::task::spawn_local(async move {
+                    if suspended.await.is_err() {
+                        error!("Windows didn't suspend");
+                    }
+                });
+            }
+            Action::Shutdown => self.shutdown(),
+            Action::Pause => self.pause(),
+            Action::Resume => self.resume(),
+            Action::Save => {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                self.save(tx);
+                tokio::task::spawn_local(async move {
+                    if let Ok(Err(e)) = rx.await {
+                        error!("Failed to save Windows: {}", e);
+                    }
+                });
+            }
+            Action::BackupStart => {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                self.enter_backup_mode(tx);
+                tokio::task::spawn_local(async move {
+                    if rx.await.is_err() {
+                        error!("Failed to enter backup mode");
+                    }
+                });
+            }
+            Action::BackupStop => {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                self.leave_backup_mode(tx);
+                tokio::task::spawn_local(async move {
+                    if rx.await.is_err() {
+                        error!("Failed to leave backup mode");
+                    }
+                });
+            }
+            Action::SendCtrlAltDel => {
+                let keys = ["ctrl", "alt", "delete"];
+                let events: Vec<_> = keys.iter().map(|&key| InputEvent::Key { key: KeyValue::Qcode(key), down: true })
+                    .chain(keys.iter().rev().map(|&key| InputEvent::Key { key: KeyValue::Qcode(key), down: false }))
+                    .collect();
+                self.write_monitor(QmpCommand::InputSendEvent { events: Cow::from(events) });
             }
         }
     }
@@ -215,13 +628,13 @@ impl Controller {
             State::Resuming(_) | State::Suspending => (),
             State::Suspended => {
                 // make them wake up
-                (&self.monitor).unbounded_send(QmpCommand::SystemWakeup).unwrap();
+                self.write_monitor(QmpCommand::SystemWakeup);
                 // can't enter now - gotta wait for GA to get ready
-                self.ga = State::Resuming(true);
+                self.set_ga(State::Resuming(true));
             },
             State::Down => {
                 self.light_attach();
-                self.io_state = IoState::AwaitingUpgrade;
+                self.set_io_state(IoState::AwaitingUpgrade);
             }
             State::Up | State::Pinging => self.io_force_attach(),
         }
@@ -240,7 +653,7 @@ impl Controller {
                 IoState::Detached => {
                     self.write_ga(GaCmdOut::SetMousePosition(Point { x, y }));
                     self.light_attach();
-                    self.io_state = IoState::TemporaryLightEntry(sender);
+                    self.set_io_state(IoState::TemporaryLightEntry(sender));
                     true
                 }
                 _ => false
@@ -263,9 +676,9 @@ impl Controller {
             State::Suspending => return, // this doesn't make sense to do
             State::Suspended => {
                 // make them wake up
-                (&self.monitor).unbounded_send(QmpCommand::SystemWakeup).unwrap();
+                self.write_monitor(QmpCommand::SystemWakeup);
                 // can't enter now - gotta wait for GA to get ready
-                self.ga = State::Resuming(false);
+                self.set_ga(State::Resuming(false));
                 return;
             }
             _ => (),
@@ -274,8 +687,9 @@ impl Controller {
         match self.io_state {
             IoState::Detached => {
                 self.prepare_entry();
+                self.fire_hook(Hook::Attach, serde_json::Value::Null);
                 self.input.borrow_mut().resume();
-                self.io_state = IoState::LightEntry;
+                self.set_io_state(IoState::LightEntry);
             }
             IoState::AwaitingUpgrade => self.io_state = IoState::LightEntry,
             IoState::LightEntry | IoState::FullEntry | IoState::TemporaryLightEntry(_) => (),
@@ -285,59 +699,148 @@ impl Controller {
     /// Attaches all configured devices regardless of GA state
     pub fn io_force_attach(&mut self) {
         debug!("full entry");
+        if let IoState::FullEntry = self.io_state {
+            return;
+        }
+
+        if self.hooks.blocks(Hook::Attach) {
+            let entered = self.after_hook(Hook::Attach, Controller::enter_fully);
+            self.queue_usb_changes(async move { entered.await?.await }.boxed_local());
+        } else {
+            self.fire_hook(Hook::Attach, serde_json::Value::Null);
+            let changes = self.enter_fully();
+            self.queue_usb_changes(changes);
+        }
+    }
 
+    /// Does the actual full entry, returns the USB changes this takes.
+    fn enter_fully(&mut self) -> LocalBoxFuture<'static, Result<(), String>> {
         // release light entry first so we don't mess things up
         match self.io_state {
             IoState::Detached => (),
             IoState::AwaitingUpgrade | IoState::LightEntry => {
                 debug!("from light entry, so releasing keys now");
                 self.input.borrow_mut().suspend();
-                (&self.monitor).unbounded_send(QmpCommand::ReleaseAllKeys).unwrap();
+                self.write_monitor(QmpCommand::ReleaseAllKeys);
             }
             IoState::TemporaryLightEntry(ref mut sender) => {
                 self.input.borrow_mut().suspend();
-                sender.unbounded_send(ControlCmdOut::TemporaryLightDetached).unwrap();
+                // the client may have hung up already
+                let _ = sender.unbounded_send(ControlCmdOut::TemporaryLightDetached);
             }
-            IoState::FullEntry => return,
+            // a blocking hook took long enough for someone else to enter
+            IoState::FullEntry => return futures03::future::ready(Ok(())).boxed_local(),
         }
 
         self.prepare_entry();
 
-        let mut udev = Context::new().expect("Failed to create udev context");
+        let changes = match Context::new() {
+            Ok(udev) => self.attach_usb_devices(&udev),
+            // still enter, the user may want to use the input devices anyways
+            Err(e) => {
+                error!("Failed to create udev context, can't attach USB devices: {}", e);
+                futures03::future::ready(Err(format!("can't attach USB devices: {}", e))).boxed_local()
+            }
+        };
+
+        self.set_io_state(IoState::FullEntry);
+        changes
+    }
 
+    fn attach_usb_devices(&mut self, udev: &Context) -> LocalBoxFuture<'static, Result<(), String>> {
+        let mut devices = Vec::new();
         let mut sorted = self.machine_config.usb_devices.iter().enumerate()
             .sorted_by(|&(_, a), &(_, b)| a.bus.cmp(&b.bus));
         let groups = sorted.drain(..).group_by(|&(_, dev)| dev.bus);
         for (port, (i, dev)) in groups.into_iter().flat_map(|(_, group)| group.enumerate())
                 .filter(|&(_, (_, ref dev))| !dev.permanent) {
-            if let Some((hostbus, hostaddr)) = udev_resolve_binding(&mut udev, &dev.binding)
-                    .expect("Failed to resolve usb binding") {
+            let resolved = match udev_resolve_binding(udev, &dev.binding) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Failed to resolve {:?}: {}", dev.binding, e);
+                    continue;
+                }
+            };
+            if let Some((hostbus, hostaddr)) = resolved {
                 let bus = dev.bus;
                 let usable_ports = util::usable_ports(bus);
-                (&self.monitor).unbounded_send(QmpCommand::DeviceAdd {
-                    driver: "usb-host",
+                devices.push(UsbHost {
+                    id: format!("usb{}", i),
+                    binding: dev.binding.clone(),
                     bus: format!("{}{}.0", bus, port / usable_ports),
                     port: (port % usable_ports) + 1,
-                    id: format!("usb{}", i),
-                    hostbus: hostbus,
-                    hostaddr: hostaddr,
-                }).unwrap();
+                    hostbus,
+                    hostaddr,
+                });
             }
         }
+        add_usb_devices(self.monitor.clone(), devices, self.this.clone()).boxed_local()
+    }
 
-        self.io_state = IoState::FullEntry;
+    /// Remembers that full entry added `device` to qemu.
+    fn usb_attached(&mut self, device: AttachedUsb) {
+        self.attached_usb.push(device);
+        self.publish_status();
+    }
+
+    /// Forgets about the USB devices full entry added, returns them with what tells when qemu removed them.
+    fn take_attached_usb(&mut self) -> Vec<(String, tokio::sync::oneshot::Receiver<()>)> {
+        let mut removals = Vec::new();
+        for device in mem::take(&mut self.attached_usb) {
+            let (removed, removed_rx) = tokio::sync::oneshot::channel();
+            self.pending_removals.insert(device.id.clone(), removed);
+            removals.push((device.id, removed_rx));
+        }
+        self.publish_status();
+        removals
+    }
+
+    /// Runs `changes` once the USB changes before them are done.
+    fn queue_usb_changes(&mut self, changes: LocalBoxFuture<'static, Result<(), String>>) {
+        let previous = self.usb_changes.clone();
+        let changes = async move {
+            // whoever asked for the previous changes got their result already
+            let _ = previous.await;
+            changes.await
+        }.boxed_local().shared();
+        // they have to happen even if nobody waits for them
+        tokio::task::spawn_local(changes.clone().map(|_| ()));
+        self.usb_changes = changes;
+    }
+
+    /// Resolves once all USB devices requested so far are attached or removed.
+    ///
+    /// Fails with the devices of the latest changes that qemu didn't attach or remove.
+    pub fn usb_changes(&self) -> UsbChanges {
+        self.usb_changes.clone()
     }
 
     pub fn prepare_entry(&mut self) {
         // release modifiers
         self.write_ga(GaCmdOut::ReleaseModifiers(()));
-        // launch entry hook
-        run_hook(&self.hooks_config.attach);
     }
 
     /// Suspends Windows
     pub fn suspend(&mut self) -> Box<dyn Future<Item=(), Error=()>> {
         info!("Suspending windows");
+        if self.ga == State::Suspended || self.ga == State::Suspending {
+            return self.suspend_now();
+        }
+
+        if self.hooks.blocks(Hook::Suspend) {
+            let suspended = self.after_hook(Hook::Suspend, Controller::suspend_now);
+            return Box::new(async move {
+                match suspended.await {
+                    Ok(suspended) => suspended.compat().await,
+                    Err(_) => Err(()),
+                }
+            }.boxed_local().compat());
+        }
+        self.fire_hook(Hook::Suspend, serde_json::Value::Null);
+        self.suspend_now()
+    }
+
+    fn suspend_now(&mut self) -> Box<dyn Future<Item=(), Error=()>> {
         if self.ga == State::Suspended {
             // we are already suspended, return a resolved future
             return Box::new(future::ok(()));
@@ -363,30 +866,237 @@ impl Controller {
             IoState::AwaitingUpgrade | IoState::LightEntry | IoState::TemporaryLightEntry(_) => {
                 debug!("detaching light entry");
                 self.input.borrow_mut().suspend();
-                (&self.monitor).unbounded_send(QmpCommand::ReleaseAllKeys).unwrap();
+                self.write_monitor(QmpCommand::ReleaseAllKeys);
             },
             IoState::FullEntry => {
                 debug!("detaching full entry");
-                for i in self.machine_config.usb_devices.iter().enumerate()
-                        .filter(|&(_, dev)| !dev.permanent).map(|(i, _)| i) {
-                    (&self.monitor).unbounded_send(QmpCommand::DeviceDel { id: format!("usb{}", i) }).unwrap();
-                }
+                self.set_io_state(IoState::Detached);
+
+                // the detach hook may want to use the devices, so it has to wait until qemu let go of them
+                let monitor = self.monitor.clone();
+                let timeout = self.machine_config.usb_detach_timeout.map(Duration::from_secs);
+                let hooks = self.hooks.clone();
+                let status = self.status_rx.clone();
+                let this = self.this.clone();
+                self.queue_usb_changes(async move {
+                    // only now the attaches queued before us are done adding devices
+                    let removals = match this.upgrade() {
+                        Some(this) => this.borrow_mut().take_attached_usb(),
+                        None => return Err("the driver is going down".to_owned()),
+                    };
+                    let res = remove_usb_devices(monitor, removals, timeout, this).await;
+                    hooks.fire(Hook::Detach, &status.borrow(), serde_json::Value::Null);
+                    res
+                }.boxed_local());
+                return;
             }
         }
 
-        self.io_state = IoState::Detached;
-        run_hook(&self.hooks_config.detach);
+        self.set_io_state(IoState::Detached);
+        self.fire_hook(Hook::Detach, serde_json::Value::Null);
     }
 
     pub fn shutdown(&mut self) {
+        if self.shutdown_progress_rx.borrow().is_some() {
+            info!("Windows is already shutting down");
+            return;
+        }
+
         info!("Initiating windows shutdown");
+        self.stop_requested = true;
         // if GA is up, use that to shut down instead of sending the ACPI message through qemu
-        match self.ga {
-            State::Up | State::Pinging => self.write_ga(GaCmdOut::Shutdown(())),
-            _ => (&self.monitor).unbounded_send(QmpCommand::SystemPowerdown).unwrap(),
+        let stage = match self.ga {
+            State::Up | State::Pinging => {
+                self.write_ga(GaCmdOut::Shutdown(()));
+                ShutdownStage::GuestAgent
+            }
+            _ => {
+                self.write_monitor(QmpCommand::SystemPowerdown);
+                ShutdownStage::Acpi
+            }
+        };
+        shutdown::report(&self.shutdown_progress, stage);
+        self.publish_status();
+
+        // escalate if windows doesn't react
+        tokio::task::spawn_local(shutdown::escalate(self.shutdown_config.clone(), stage, self.monitor.clone(),
+                                                    self.shutdown_progress.clone()));
+    }
+
+    /// Executes a raw QMP command on the passthrough connection, `ack` gets what qemu returned.
+    pub fn qmp(&mut self, execute: String, arguments: Option<serde_json::Value>,
+               ack: tokio::sync::oneshot::Sender<Result<serde_json::Value, String>>) {
+        info!("Passing QMP command {} through", execute);
+        self.write_monitor(QmpCommand::Raw { execute, arguments, ack });
+    }
+
+    /// Freezes the VM without any guest cooperation
+    pub fn pause(&mut self) {
+        info!("Pausing windows");
+        // nobody can use the devices while the VM is frozen anyways
+        self.io_detach();
+        self.write_monitor(QmpCommand::Stop);
+    }
+
+    /// Resumes a paused VM
+    pub fn resume(&mut self) {
+        info!("Resuming windows");
+        self.write_monitor(QmpCommand::Cont);
+    }
+
+    /// Qemu stopped running the VM, whoever asked for it
+    pub fn qemu_stopped(&mut self) {
+        self.paused = true;
+        self.publish_status();
+    }
+
+    /// Qemu started (or continued) running the VM
+    pub fn qemu_resumed(&mut self) {
+        self.paused = false;
+        self.publish_status();
+        if self.restoring {
+            // the VM runs again, so the disks are about to diverge from the saved state
+            // restoring it a second time would corrupt them
+            info!("Restored Windows from {}", self.save_file.display());
+            self.restoring = false;
+            if let Err(e) = fs::remove_file(&self.save_file) {
+                error!("Failed to remove saved state {}: {}. Remove it manually before the next start!",
+                       self.save_file.display(), e);
+            }
+        }
+    }
+
+    /// Windows bluescreened (reported through pvpanic)
+    pub fn qemu_panicked(&mut self) {
+        error!("Windows crashed");
+        self.crashed("panic");
+    }
+
+    /// Remembers that Windows crashed, qemu reports most crashes more than once so the hooks only hear about the first.
+    fn crashed(&mut self, cause: &str) {
+        if !self.guest_crashed {
+            self.guest_crashed = true;
+            self.fire_hook(Hook::GuestCrash, serde_json::json!({ "cause": cause }));
+        }
+    }
+
+    /// Qemu is about to go down
+    pub fn qemu_shutdown(&mut self, guest: bool, cause: &str) {
+        info!("Qemu is shutting down (guest initiated: {}, cause: {})", guest, cause);
+        if cause == "guest-panic" {
+            self.crashed("panic");
+        }
+        if cause == "host-qmp-quit" {
+            // we only ever quit qemu on purpose (saving, shutdown escalation)
+            self.stop_requested = true;
         }
     }
 
+    /// The emulated watchdog fired because Windows stopped petting it
+    pub fn qemu_watchdog(&mut self, action: &str) {
+        error!("Windows hangs, the watchdog fired (action: {})", action);
+        if action == "shutdown" || action == "poweroff" {
+            self.crashed("watchdog");
+        }
+    }
+
+    /// The VM was reset, Windows is booting again
+    pub fn qemu_reset(&mut self, guest: bool, cause: &str) {
+        if cause == "guest-panic" {
+            warn!("Windows crashed and is rebooting");
+        } else {
+            info!("Windows is rebooting (guest initiated: {}, cause: {})", guest, cause);
+        }
+    }
+
+    /// How this run of Windows ended, as far as we can tell from qemu's events
+    pub fn outcome(&self) -> Outcome {
+        if self.guest_crashed {
+            Outcome::Crashed
+        } else if self.stop_requested {
+            Outcome::Shutdown
+        } else {
+            Outcome::GuestShutdown
+        }
+    }
+
+    /// Reasons why the VM state can't be saved right now
+    fn save_blockers(&self) -> Vec<String> {
+        let mut blockers: Vec<_> = self.machine_config.pci_devices.iter()
+            .map(|dev| format!("PCI device {} is passed through with VFIO: qemu can't save the state of real hardware", dev.slot))
+            .collect();
+        blockers.extend(self.machine_config.usb_devices.iter().filter(|dev| dev.permanent)
+            .map(|dev| format!("USB device {:?} is permanently attached: host USB devices can't be migrated", dev.binding)));
+        if let IoState::FullEntry = self.io_state {
+            blockers.push("USB devices are attached: detach them first".to_owned());
+        }
+        blockers
+    }
+
+    /// Loading `save_file` failed, so it is set aside instead of being tried again on every start.
+    ///
+    /// Does nothing once the restored VM ran.
+    pub fn restore_failed(&mut self, reason: &str) {
+        if !self.restoring {
+            return;
+        }
+        self.restoring = false;
+        error!("Failed to restore Windows from {}: {}", self.save_file.display(), reason);
+        let failed = failed_save_file(&self.save_file);
+        match fs::rename(&self.save_file, &failed) {
+            Ok(()) => warn!("Moved the saved state to {}, Windows boots normally next time", failed.display()),
+            Err(e) => error!("Failed to move saved state {} away: {}. Remove it manually before the next start!",
+                             self.save_file.display(), e),
+        }
+    }
+
+    /// Saves the VM state to disk and quits qemu. The next start restores it.
+    pub fn save(&mut self, ack: tokio::sync::oneshot::Sender<Result<(), String>>) {
+        let blockers = self.save_blockers();
+        if !blockers.is_empty() {
+            let reason = blockers.join("; ");
+            warn!("Refusing to save Windows: {}", reason);
+            let _ = ack.send(Err(reason));
+            return;
+        }
+
+        info!("Saving windows to {}", self.save_file.display());
+        self.io_detach();
+        // write to a separate file first so a failed save never gets restored
+        let partial = partial_save_file(&self.save_file);
+        let uri = format!("exec:cat > {}", util::shell_quote(&partial.display().to_string()));
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.write_monitor(QmpCommand::Stop);
+        self.write_monitor(QmpCommand::Migrate { uri, ack: tx });
+
+        let monitor = self.monitor.clone();
+        let save_file = self.save_file.clone();
+        tokio::task::spawn_local(async move {
+            let res = match rx.await {
+                Ok(Ok(())) => fs::rename(&partial, &save_file).map_err(|e| format!("failed to store saved state: {}", e)),
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err("monitor went away".to_owned()),
+            };
+            match res {
+                Ok(()) => {
+                    info!("Saved windows, quitting qemu");
+                    let _ = monitor.unbounded_send(QmpCommand::Quit);
+                }
+                Err(_) => {
+                    // don't leave a half-written state around
+                    let _ = fs::remove_file(&partial);
+                    let _ = monitor.unbounded_send(QmpCommand::Cont);
+                }
+            }
+            let _ = ack.send(res);
+        });
+    }
+
+    /// Subscribes to the progress of a (future) shutdown.
+    pub fn shutdown_progress(&self) -> watch::Receiver<Option<ShutdownStage>> {
+        self.shutdown_progress_rx.clone()
+    }
+
     /// Windows told us to grab the keyboard with the given types
     pub fn grab_x11_clipboard(&mut self, types: ClipboardTypes) {
         (&self.x11_clipboard_grabber).unbounded_send(types).unwrap();
@@ -432,27 +1142,69 @@ impl Controller {
 
     pub fn mouse_edged(&mut self, x: i32, y: i32) {
         if let IoState::TemporaryLightEntry(ref mut sender) = self.io_state {
-            sender.unbounded_send(ControlCmdOut::MouseEdged { x, y }).unwrap();
+            // the client may have hung up already
+            let _ = sender.unbounded_send(ControlCmdOut::MouseEdged { x, y });
         }
     }
 
     pub fn enter_backup_mode(&mut self, ack: tokio::sync::oneshot::Sender<()>) {
-        // send a qemu snapshot command for all disks where we have a snapshot path configured and it doesn't exist yet
-        let acks: Vec<_> = self.machine_config.storage.iter()
+        if self.hooks.blocks(Hook::BackupStart) {
+            // a failed hook drops the ack
+            let started = self.after_hook(Hook::BackupStart, move |controller| controller.start_backup(ack));
+            tokio::task::spawn_local(started.map(|_| ()));
+        } else {
+            self.fire_hook(Hook::BackupStart, serde_json::Value::Null);
+            self.start_backup(ack);
+        }
+    }
+
+    fn start_backup(&mut self, ack: tokio::sync::oneshot::Sender<()>) {
+        // put a new snapshot overlay on top of every disk where we have a snapshot path configured
+        // and rotate the oldest ones into the base image if we now have more than we should keep
+        let keep = self.backup_config.keep_overlays;
+        let jobs: Vec<_> = self.machine_config.storage.iter()
             .enumerate()
-            .filter_map(|(i, d)| d.snapshot_file.as_ref().map(|s| (i, s)))
-            .filter(|(_, s)| !Path::new(s).exists())
-            .map(|(i, s)| {
+            .filter_map(|(i, d)| d.next_snapshot().map(|next| (i, d.snapshot_chain(), next)))
+            .map(|(i, mut chain, next)| {
+                let node = match chain.last() {
+                    Some(o) => backup::overlay_node(i, o.generation),
+                    None => format!("disk{i}"),
+                };
                 let (tx, rx) = tokio::sync::oneshot::channel();
-                self.monitor.unbounded_send(QmpCommand::TakeSnapshot { disk_id: i, snap_file: s.clone(), ack: tx }).unwrap();
-                rx
+                self.write_monitor(QmpCommand::TakeSnapshot {
+                    node,
+                    snapshot_node: backup::overlay_node(i, next.generation),
+                    snap_file: next.path.clone(),
+                    ack: tx,
+                });
+
+                chain.push(next);
+                (rx, backup::rotation(i, &chain, keep))
             })
             .collect();
 
         // wait for the qemu jobs to return success and then return the ack downstream
+        let monitor = self.monitor.clone();
+        let this = self.this.clone();
         tokio::task::spawn_local(async move {
-            for a in acks {
-                if a.await.is_err() {
+            let (snapshots, rotations): (Vec<_>, Vec<_>) = jobs.into_iter().unzip();
+            for snapshot in snapshots {
+                if snapshot.await.is_err() {
+                    return;
+                }
+            }
+            // only now every disk writes to a new overlay
+            if let Some(controller) = this.upgrade() {
+                let mut controller = controller.borrow_mut();
+                controller.backup_mode = true;
+                controller.publish_status();
+            }
+            for (cmd, commit) in rotations.into_iter().flatten() {
+                if monitor.unbounded_send(cmd).is_err() {
+                    warn!("Qemu is gone, dropping command");
+                    return;
+                }
+                if commit.await.is_err() {
                     return;
                 }
             }
@@ -460,33 +1212,50 @@ impl Controller {
         });
     }
     pub fn leave_backup_mode(&mut self, ack: tokio::sync::oneshot::Sender<()>) {
-        // send a qemu snapshot command for all disks where we have a snapshot path configured and it exists (i.e. is active)
+        // commit all active snapshot overlays back into the base image
+        self.backup_mode = false;
+        self.publish_status();
         let acks: Vec<_> = self.machine_config.storage.iter()
             .enumerate()
-            .filter_map(|(i, d)| d.snapshot_file.as_ref().map(|s| (i, s)))
-            .filter(|(_, s)| Path::new(s).exists())
-            .map(|(i, s)| {
-                let (tx, rx) = tokio::sync::oneshot::channel();
-                self.monitor.unbounded_send(QmpCommand::CommitSnapshot { disk_id: i, snap_file: s.clone(), ack: tx }).unwrap();
+            .filter_map(|(i, d)| backup::rotation(i, &d.snapshot_chain(), 0))
+            .map(|(cmd, rx)| {
+                self.write_monitor(cmd);
                 rx
             })
             .collect();
 
         // wait for the qemu jobs to return success and then return the ack downstream
+        let hooks = self.hooks.clone();
+        let status = self.status_rx.clone();
         tokio::task::spawn_local(async move {
             for a in acks {
                 if a.await.is_err() {
                     return;
                 }
             }
+            hooks.fire(Hook::BackupStop, &status.borrow(), serde_json::Value::Null);
             let _ = ack.send(());
         });
     }
 }
 
+/// Where a saved state that failed to restore is kept.
+fn failed_save_file(save_file: &Path) -> PathBuf {
+    let mut failed = save_file.as_os_str().to_owned();
+    failed.push(".failed");
+    PathBuf::from(failed)
+}
+
+/// Where a save in progress writes the VM state to before it is moved to `save_file`.
+pub fn partial_save_file(save_file: &Path) -> PathBuf {
+    let mut partial = save_file.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
 /// Resolves a `UsbBinding` to a (bus, addr) tuple.
 pub fn udev_resolve_binding(udev: &Context, binding: &UsbBinding)
-                        -> UdevResult<Option<(u64, u64)>> {
+                        -> Result<Option<(u64, u64)>, Box<dyn std::error::Error>> {
     fn parse_int(s: &str) -> std::result::Result<u64, std::num::ParseIntError> {
         if let Some(s) = s.strip_prefix("0x") {
             u64::from_str_radix(s, 16)
@@ -498,7 +1267,7 @@ pub fn udev_resolve_binding(udev: &Context, binding: &UsbBinding)
             u64::from_str_radix(s, 10)
         }
     }
-    let mut iter = Enumerator::new(udev).unwrap();
+    let mut iter = Enumerator::new(udev)?;
 
     iter.match_subsystem("usb")?;
     iter.match_property("DEVTYPE", "usb_device")?;
@@ -514,7 +1283,7 @@ pub fn udev_resolve_binding(udev: &Context, binding: &UsbBinding)
         }
     }
 
-    let mut scanner = iter.scan_devices().unwrap();
+    let mut scanner = iter.scan_devices()?;
     // FIXME: rust-lang/rust#42222
     return match scanner.next() {
         Some(dev) => {
@@ -523,9 +1292,9 @@ pub fn udev_resolve_binding(udev: &Context, binding: &UsbBinding)
             for attr in dev.attributes() {
                 if let Some(val) = attr.value().and_then(OsStr::to_str) {
                     if attr.name() == "busnum" {
-                        bus = Some(parse_int(val).unwrap());
+                        bus = Some(parse_int(val).map_err(|e| format!("bad busnum {:?}: {}", val, e))?);
                     } else if attr.name() == "devnum" {
-                        addr = Some(parse_int(val).unwrap());
+                        addr = Some(parse_int(val).map_err(|e| format!("bad devnum {:?}: {}", val, e))?);
                     }
                 }
             }
@@ -535,7 +1304,10 @@ pub fn udev_resolve_binding(udev: &Context, binding: &UsbBinding)
                  just like qemu would.", binding);
             }
 
-            Ok(Some((bus.unwrap(), addr.unwrap())))
+            match (bus, addr) {
+                (Some(bus), Some(addr)) => Ok(Some((bus, addr))),
+                _ => Err("udev doesn't know its bus number and address".into()),
+            }
         }
         None => {
             warn!("Didn't find any devices for {:?}", binding);
@@ -547,22 +1319,344 @@ pub fn udev_resolve_binding(udev: &Context, binding: &UsbBinding)
 /// Resolves a `UsbBinding` to a (bus, addr) tuple.
 ///
 /// This is just a wrapper around `udev_resolve_binding` creating a new udev context.
-pub fn resolve_binding(binding: &UsbBinding) -> UdevResult<Option<(u64, u64)>> {
-    let udev = Context::new().expect("Failed to create udev context");
+pub fn resolve_binding(binding: &UsbBinding) -> Result<Option<(u64, u64)>, Box<dyn std::error::Error>> {
+    let udev = Context::new()?;
     udev_resolve_binding(&udev, binding)
 }
 
-fn run_hook(hook: &Option<String>) {
-    if let Some(hook) = hook.as_ref() {
-        match Command::new("/bin/sh").arg("-c").arg(&hook).spawn() {
-            Ok(mut child) => {
-                tokio::spawn(async move {
-                    if let Err(e) = child.wait().await {
-                        warn!("Error waiting for hook to return: {:?}", e);
+/// Adds `devices` to qemu one after another, every device gets a second chance after a second.
+async fn add_usb_devices(monitor: UnboundedSender<QmpCommand>, devices: Vec<UsbHost>,
+                         controller: Weak<RefCell<Controller>>) -> Result<(), String> {
+    let mut failures = Vec::new();
+    for dev in devices {
+        let mut retried = false;
+        loop {
+            let (ack, ack_rx) = tokio::sync::oneshot::channel();
+            let add = QmpCommand::DeviceAdd {
+                driver: "usb-host",
+                id: dev.id.clone(),
+                bus: dev.bus.clone(),
+                port: dev.port,
+                hostbus: dev.hostbus,
+                hostaddr: dev.hostaddr,
+                ack,
+            };
+            if monitor.unbounded_send(add).is_err() {
+                return Err("qemu is gone".to_owned());
+            }
+            match ack_rx.await {
+                Ok(Ok(())) => {
+                    if let Some(controller) = controller.upgrade() {
+                        controller.borrow_mut().usb_attached(AttachedUsb { id: dev.id, binding: dev.binding });
                     }
-                });
+                    break;
+                }
+                Ok(Err(e)) if !retried => {
+                    warn!("Failed to attach USB device {:?}, retrying: {}", dev.binding, e);
+                    retried = true;
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                Ok(Err(e)) => {
+                    error!("Failed to attach USB device {:?}: {}", dev.binding, e);
+                    failures.push(format!("{:?}: {}", dev.binding, e));
+                    break;
+                }
+                Err(_) => return Err("qemu went away".to_owned()),
             }
-            Err(e) => warn!("Error spawning hook: {:?}", e),
         }
     }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("failed to attach {}", failures.join("; ")))
+    }
+}
+
+/// Removes the devices in `removals` from qemu and waits for qemu to confirm each removal.
+async fn remove_usb_devices(monitor: UnboundedSender<QmpCommand>,
+                            removals: Vec<(String, tokio::sync::oneshot::Receiver<()>)>,
+                            timeout: Option<Duration>, controller: Weak<RefCell<Controller>>) -> Result<(), String> {
+    // ask for all of them first, the guest gets to release them in parallel
+    let mut requested = Vec::new();
+    for (id, removed) in removals {
+        let (ack, ack_rx) = tokio::sync::oneshot::channel();
+        if monitor.unbounded_send(QmpCommand::DeviceDel { id: id.clone(), ack }).is_err() {
+            return Err("qemu is gone".to_owned());
+        }
+        requested.push((id, ack_rx, removed));
+    }
+
+    let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    let mut failures = Vec::new();
+    for (id, ack_rx, removed) in requested {
+        match ack_rx.await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => {
+                error!("Failed to detach USB device {}: {}", id, e);
+                // there won't be a DEVICE_DELETED for it
+                if let Some(controller) = controller.upgrade() {
+                    controller.borrow_mut().pending_removals.remove(&id);
+                }
+                failures.push(format!("{}: {}", id, e));
+                continue;
+            }
+            Err(_) => return Err("qemu went away".to_owned()),
+        }
+        // a dropped sender means that a later detach is waiting for this device now
+        let removed = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, removed).await.is_ok(),
+            None => { let _ = removed.await; true }
+        };
+        if !removed {
+            error!("Qemu didn't remove USB device {} in time", id);
+            failures.push(format!("{}: qemu didn't remove it in time", id));
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("failed to detach {}", failures.join("; ")))
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    use common::config::HooksConfig;
+    use futures::unsync::mpsc::{self, UnboundedReceiver};
+
+    /// A controller with default configs that isn't connected to any qemu.
+    ///
+    /// Everything it would send to qemu ends up in the returned receiver.
+    pub fn fake() -> (Rc<RefCell<Controller>>, UnboundedReceiver<QmpCommand>) {
+        fake_with(MachineConfig::default(), HooksConfig::default())
+    }
+
+    /// Like `fake`, but for the given machine and hooks.
+    pub fn fake_with(machine_config: MachineConfig, hooks_config: HooksConfig)
+                     -> (Rc<RefCell<Controller>>, UnboundedReceiver<QmpCommand>) {
+        let (monitor, monitor_rx) = mpsc::unbounded();
+        let (input, _) = Input::new(MachineConfig::default());
+        let instance = InstanceState {
+            qemu_pid: 0,
+            swtpm_pid: None,
+            monitor_socket: PathBuf::new(),
+            clientpipe_socket: PathBuf::new(),
+            ga_up: false,
+            io_mode: IoMode::Detached,
+            attached_usb: Vec::new(),
+            started: 1234,
+            pci_devices: Vec::new(),
+            usb_bindings: Vec::new(),
+        };
+        let hooks = Hooks::new(hooks_config, None);
+        let input = Rc::new(RefCell::new(input));
+        let controller = Rc::new_cyclic(|this| RefCell::new(Controller::new(
+            machine_config, hooks, BackupConfig::default(), ShutdownConfig::default(), WatchdogConfig::default(),
+            PathBuf::from("/nonexistent/saved-vm.state"), false, PathBuf::from("/nonexistent"), instance, monitor,
+            mpsc::unbounded().0, input, mpsc::unbounded().0, mpsc::unbounded().0, mpsc::unbounded().0, this.clone())));
+        (controller, monitor_rx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use common::config::{HooksConfig, HotKey, StorageDevice, UsbBus, UsbDevice};
+    use futures03::compat::Stream01CompatExt;
+    use futures03::StreamExt;
+
+    use crate::dbus::testing::block_on;
+
+    #[test]
+    fn detach_waits_for_device_deleted() {
+        let mut machine = MachineConfig::default();
+        for product in 0xfffe..=0xffff {
+            machine.usb_devices.push(UsbDevice {
+                // never plugged in, so attaching adds nothing
+                binding: UsbBinding::ById(UsbId { vendor: 0xffff, product }),
+                permanent: false,
+                bus: UsbBus::Xhci,
+            });
+        }
+        let (controller, monitor_rx) = testing::fake_with(machine, HooksConfig::default());
+        let mut monitor_rx = monitor_rx.compat();
+        block_on(async move {
+            controller.borrow_mut().io_force_attach();
+            let attached = controller.borrow().usb_changes();
+            // without udev this fails, which doesn't matter here
+            let _ = attached.await;
+            // as if the second one had been there
+            let binding = UsbBinding::ById(UsbId { vendor: 0xffff, product: 0xffff });
+            controller.borrow_mut().usb_attached(AttachedUsb { id: "usb1".to_owned(), binding });
+            controller.borrow_mut().io_detach();
+            let done = controller.borrow().usb_changes();
+
+            loop {
+                match monitor_rx.next().await {
+                    Some(Ok(QmpCommand::DeviceDel { id, ack })) => {
+                        assert_eq!(id, "usb1");
+                        ack.send(Ok(())).unwrap();
+                        break;
+                    }
+                    Some(Ok(_)) => (),
+                    _ => panic!("expected device_del"),
+                }
+            }
+            tokio::task::yield_now().await;
+            assert!(futures03::poll!(done.clone()).is_pending());
+
+            let event = QemuEvent::DeviceDeleted { device: Some("usb1".to_owned()), path: "/machine/peripheral/usb1".to_owned() };
+            controller.borrow_mut().qemu_event(event);
+            assert_eq!(done.await, Ok(()));
+
+            // the device that never got attached isn't removed either
+            while let std::task::Poll::Ready(Some(cmd)) = futures03::poll!(monitor_rx.next()) {
+                assert!(!matches!(cmd, Ok(QmpCommand::DeviceDel { .. })), "removed a device that isn't attached");
+            }
+            assert!(controller.borrow().status().borrow().usb_devices.is_empty());
+        });
+    }
+
+    #[test]
+    fn failed_detach_forgets_the_device() {
+        let (controller, monitor_rx) = testing::fake();
+        let mut monitor_rx = monitor_rx.compat();
+        block_on(async move {
+            controller.borrow_mut().io_force_attach();
+            let attached = controller.borrow().usb_changes();
+            // without udev this fails, which doesn't matter here
+            let _ = attached.await;
+            let binding = UsbBinding::ById(UsbId { vendor: 0xffff, product: 0xffff });
+            controller.borrow_mut().usb_attached(AttachedUsb { id: "usb0".to_owned(), binding });
+            controller.borrow_mut().io_detach();
+            let done = controller.borrow().usb_changes();
+
+            loop {
+                match monitor_rx.next().await {
+                    Some(Ok(QmpCommand::DeviceDel { ack, .. })) => {
+                        ack.send(Err("Device 'usb0' not found".to_owned())).unwrap();
+                        break;
+                    }
+                    Some(Ok(_)) => (),
+                    _ => panic!("expected device_del"),
+                }
+            }
+            assert!(done.await.unwrap_err().contains("usb0"));
+            assert!(controller.borrow().pending_removals.is_empty());
+        });
+    }
+
+    #[test]
+    fn failed_blocking_attach_hook() {
+        let hooks = HooksConfig {
+            attach: Some("exit 1".to_owned()),
+            blocking: vec!["attach".to_owned()],
+            ..HooksConfig::default()
+        };
+        let (controller, _monitor_rx) = testing::fake_with(MachineConfig::default(), hooks);
+        block_on(async move {
+            controller.borrow_mut().io_force_attach();
+            let done = controller.borrow().usb_changes();
+            let failed = done.await.unwrap_err();
+            assert!(failed.contains("attach hook failed"), "{}", failed);
+            assert_eq!(controller.borrow().status().borrow().io, IoMode::Detached);
+        });
+    }
+
+    #[test]
+    fn hotkey_sequence() {
+        use common::hotkeys::{Key, KeyBinding};
+
+        let mut machine = MachineConfig::default();
+        machine.hotkeys.push(HotKey {
+            key: KeyBinding::new(Vec::new(), Key::Pause, true),
+            action: HotKeyAction::Sequence(vec![
+                HotKeyAction::Action(Action::Pause),
+                HotKeyAction::Delay(50),
+                HotKeyAction::Action(Action::Resume),
+                HotKeyAction::Action(Action::SendCtrlAltDel),
+            ]),
+        });
+        let (controller, monitor_rx) = testing::fake_with(machine, HooksConfig::default());
+        let mut monitor_rx = monitor_rx.compat();
+        block_on(async move {
+            controller.borrow_mut().hotkey(0);
+            assert!(matches!(monitor_rx.next().await, Some(Ok(QmpCommand::Stop))));
+            let started = tokio::time::Instant::now();
+            assert!(matches!(monitor_rx.next().await, Some(Ok(QmpCommand::Cont))));
+            assert!(started.elapsed() >= Duration::from_millis(40));
+            match monitor_rx.next().await {
+                Some(Ok(QmpCommand::InputSendEvent { events })) => assert_eq!(events.len(), 6),
+                _ => panic!("expected ctrl+alt+del"),
+            }
+        });
+    }
+
+    #[test]
+    fn host_hotkey_only_while_detached() {
+        let mut machine = MachineConfig::default();
+        machine.host_hotkeys.push(HotKey {
+            key: "Ctrl+Alt+Insert".parse().unwrap(),
+            action: HotKeyAction::Action(Action::Pause),
+        });
+        let (controller, monitor_rx) = testing::fake_with(machine, HooksConfig::default());
+        let mut monitor_rx = monitor_rx.compat();
+        block_on(async move {
+            controller.borrow_mut().io_state = IoState::LightEntry;
+            controller.borrow_mut().host_hotkey(0);
+            controller.borrow_mut().io_state = IoState::Detached;
+            controller.borrow_mut().host_hotkey(0);
+            controller.borrow_mut().host_hotkey(1);
+            drop(controller);
+            assert!(matches!(monitor_rx.next().await, Some(Ok(QmpCommand::Stop))));
+            assert!(monitor_rx.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn paused_follows_qemu() {
+        let (controller, _monitor_rx) = testing::fake();
+        block_on(async move {
+            // e.g. a STOP sent through the QMP passthrough
+            controller.borrow_mut().qemu_event(QemuEvent::Stop);
+            assert!(controller.borrow().status().borrow().paused);
+            controller.borrow_mut().qemu_event(QemuEvent::Resume);
+            assert!(!controller.borrow().status().borrow().paused);
+
+            // asking for a pause doesn't mean qemu did it
+            controller.borrow_mut().pause();
+            assert!(!controller.borrow().status().borrow().paused);
+        });
+    }
+
+    #[test]
+    fn retry_failed_backup() {
+        let snap = std::env::temp_dir().join(format!("windows-gaming-retry-backup-{}.qcow2", std::process::id()));
+        let mut machine = MachineConfig::default();
+        machine.storage.push(StorageDevice {
+            path: "/nonexistent/windows.img".to_owned(),
+            cache: "none".to_owned(),
+            format: "raw".to_owned(),
+            snapshot_file: Some(snap.to_string_lossy().into_owned()),
+        });
+        let (controller, monitor_rx) = testing::fake_with(machine, HooksConfig::default());
+        let mut monitor_rx = monitor_rx.compat();
+        block_on(async move {
+            for succeed in [false, true] {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                controller.borrow_mut().enter_backup_mode(tx);
+                match monitor_rx.next().await {
+                    Some(Ok(QmpCommand::TakeSnapshot { ack, .. })) if succeed => ack.send(()).unwrap(),
+                    // qemu refused, so the ack is dropped
+                    Some(Ok(QmpCommand::TakeSnapshot { .. })) => (),
+                    _ => panic!("expected a snapshot"),
+                }
+                assert_eq!(rx.await.is_ok(), succeed);
+                assert_eq!(controller.borrow().status().borrow().backup, succeed);
+            }
+        });
+    }
 }
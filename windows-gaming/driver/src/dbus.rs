@@ -1,14 +1,17 @@
-use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::ops::DerefMut;
 use std::os::unix::prelude::RawFd;
+use std::rc::Rc;
 
-use futures03::Stream;
-use crate::libdbus::{Connection, ConnectionItem, Watch, WatchEvent};
+use futures03::{Stream, StreamExt};
+use crate::libdbus::{Connection, ConnectionItem, Error, Message, Watch, WatchEvent};
 use mio::Ready;
 use mio::unix::UnixReady;
 use futures::{Async};
 
 use tokio::io::unix::AsyncFd;
+use tokio::sync::{mpsc, oneshot};
 
 pub struct DBusItems<'a> {
     conn: &'a Connection,
@@ -143,3 +146,144 @@ fn to_ready(w: &Watch) -> Ready {
     }
     r
 }
+
+/// Async method calls and signal subscriptions on top of `DBusItems`.
+///
+/// Nothing happens unless `run` is being polled.
+pub struct Bus<'a> {
+    conn: &'a Connection,
+    pending_calls: RefCell<HashMap<u32, oneshot::Sender<Message>>>,
+    signal_subscribers: RefCell<Vec<mpsc::UnboundedSender<Rc<Message>>>>,
+    method_calls: RefCell<Option<mpsc::UnboundedSender<Message>>>,
+}
+
+impl<'a> Bus<'a> {
+    pub fn new(conn: &'a Connection) -> Bus<'a> {
+        Bus {
+            conn,
+            pending_calls: RefCell::new(HashMap::new()),
+            signal_subscribers: RefCell::new(Vec::new()),
+            method_calls: RefCell::new(None),
+        }
+    }
+
+    pub fn connection(&self) -> &'a Connection {
+        self.conn
+    }
+
+    /// Sends a method call and waits for the reply without blocking the event loop.
+    pub async fn call(&self, msg: Message) -> Result<Message, Error> {
+        let serial = self.conn.send(msg)
+            .map_err(|()| Error::new_custom("org.freedesktop.DBus.Error.NoMemory", "Failed to send message"))?;
+        let (tx, rx) = oneshot::channel();
+        self.pending_calls.borrow_mut().insert(serial, tx);
+
+        let mut reply = rx.await
+            .map_err(|_| Error::new_custom("org.freedesktop.DBus.Error.Disconnected", "Connection went away"))?;
+        reply.as_result()?;
+        Ok(reply)
+    }
+
+    /// Receives all signals that arrive from now on. Use `add_match` on the connection to get any.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<Rc<Message>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.signal_subscribers.borrow_mut().push(tx);
+        rx
+    }
+
+    /// Receives all method calls to object paths registered on the connection.
+    ///
+    /// There can only be one receiver, calls arriving while there is none are answered with an error.
+    pub fn method_calls(&self) -> mpsc::UnboundedReceiver<Message> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        *self.method_calls.borrow_mut() = Some(tx);
+        rx
+    }
+
+    /// Sends a reply or signal, logging failures.
+    pub fn send(&self, msg: Message) {
+        if self.conn.send(msg).is_err() {
+            warn!("Failed to send dbus message");
+        }
+    }
+
+    /// Dispatches replies, signals and method calls until the connection dies.
+    pub async fn run(&self) {
+        let mut items = DBusItems::new(self.conn);
+        while let Some(Ok(item)) = items.next().await {
+            match item {
+                ConnectionItem::MethodReturn(msg) => {
+                    let sender = msg.get_reply_serial().and_then(|s| self.pending_calls.borrow_mut().remove(&s));
+                    match sender {
+                        Some(sender) => { let _ = sender.send(msg); }
+                        None => trace!("dbus reply nobody waits for: {:?}", msg),
+                    }
+                }
+                ConnectionItem::Signal(msg) => {
+                    let msg = Rc::new(msg);
+                    self.signal_subscribers.borrow_mut().retain(|s| s.send(msg.clone()).is_ok());
+                }
+                ConnectionItem::MethodCall(msg) => {
+                    let unhandled = match *self.method_calls.borrow() {
+                        Some(ref calls) => calls.send(msg).err().map(|e| e.0),
+                        None => Some(msg),
+                    };
+                    if let Some(msg) = unhandled {
+                        if let Some(reply) = Message::new_error(&msg, "org.freedesktop.DBus.Error.UnknownObject",
+                                                                "Nobody is listening here") {
+                            self.send(reply);
+                        }
+                    }
+                }
+                item => trace!("unhandled dbus item: {:?}", item),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::env;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::{Mutex, MutexGuard};
+
+    use crate::libdbus::{BusType, Connection};
+
+    // tests have to take turns as the bus address is passed through the environment
+    static BUS_LOCK: Mutex<()> = Mutex::new(());
+
+    /// A dbus-daemon that only lives for the duration of a test.
+    pub struct PrivateBus(Child, MutexGuard<'static, ()>);
+
+    impl PrivateBus {
+        pub fn start() -> PrivateBus {
+            let lock = BUS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let mut daemon = Command::new("dbus-daemon")
+                .args(&["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("dbus-daemon is required for this test");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.as_mut().unwrap()).read_line(&mut address).unwrap();
+            // libdbus picks this up for BusType::Session
+            env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+            PrivateBus(daemon, lock)
+        }
+
+        pub fn connect() -> Connection {
+            Connection::get_private(BusType::Session).unwrap()
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+        }
+    }
+
+    pub fn block_on<F: std::future::Future<Output = ()>>(f: F) {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        tokio::task::LocalSet::new().block_on(&rt, f);
+    }
+}
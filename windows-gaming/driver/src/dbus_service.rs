@@ -0,0 +1,455 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use futures03::StreamExt;
+use futures03::compat::Future01CompatExt;
+use futures03::future::LocalBoxFuture;
+use futures03::stream::FuturesUnordered;
+use futures03::FutureExt;
+use tokio::sync::oneshot;
+
+use common::config::{AccessConfig, Permission};
+use windows_gaming_client::protocol::{GaStatus, IoMode, Status};
+
+use crate::control::access::{self, Peer};
+use crate::controller::Controller;
+use crate::dbus::Bus;
+use crate::libdbus::{Message, MessageItem, NameFlag};
+
+pub const NAME: &'static str = "org.windowsgaming.Driver1";
+pub const PATH: &'static str = "/org/windowsgaming/Driver1";
+pub const INTERFACE: &'static str = "org.windowsgaming.Driver1";
+
+const PROPERTIES: &'static str = "org.freedesktop.DBus.Properties";
+const INTROSPECTABLE: &'static str = "org.freedesktop.DBus.Introspectable";
+
+const INTROSPECTION: &'static str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.windowsgaming.Driver1">
+    <method name="Attach">
+      <!-- auto, try, force or light -->
+      <arg name="mode" type="s" direction="in"/>
+    </method>
+    <method name="Detach"/>
+    <method name="Shutdown"/>
+    <method name="Suspend"/>
+    <method name="Pause"/>
+    <method name="Resume"/>
+    <method name="Save"/>
+    <method name="EnterBackupMode"/>
+    <method name="LeaveBackupMode"/>
+    <!-- down, up, suspending, suspended or resuming -->
+    <property name="GaState" type="s" access="read"/>
+    <!-- detached, light_entry or full_entry -->
+    <property name="IoState" type="s" access="read"/>
+    <property name="UsbDevices" type="u" access="read"/>
+    <property name="Paused" type="b" access="read"/>
+    <property name="Backup" type="b" access="read"/>
+    <property name="ShuttingDown" type="b" access="read"/>
+    <!-- seconds since the epoch -->
+    <property name="Started" type="t" access="read">
+      <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="const"/>
+    </property>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="property" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface" type="s"/>
+      <arg name="changed_properties" type="a{sv}"/>
+      <arg name="invalidated_properties" type="as"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml_data" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+/// The bus name of the instance `name`.
+pub fn bus_name(instance: Option<&str>) -> String {
+    match instance {
+        None => NAME.to_owned(),
+        // bus name elements may neither contain - nor start with a digit
+        Some(name) => format!("{}.instance_{}", NAME, name.replace('-', "_")),
+    }
+}
+
+/// Exports the driver on `bus` for as long as the driver runs.
+///
+/// Changes of the driver status are announced through `PropertiesChanged`.
+/// On the system bus anyone can call us, so callers are checked against `access` like on the control socket.
+pub async fn serve(bus: &Bus<'_>, controller: Rc<RefCell<Controller>>, instance: Option<&str>,
+                   access: Option<&AccessConfig>) {
+    let name = bus_name(instance);
+    let conn = bus.connection();
+    if let Err(e) = conn.register_name(&name, NameFlag::DoNotQueue as u32) {
+        warn!("Can't own {} on dbus, running without dbus interface: {:?}", name, e);
+        return;
+    }
+    if let Err(e) = conn.register_object_path(PATH) {
+        warn!("Can't export {} on dbus, running without dbus interface: {:?}", PATH, e);
+        return;
+    }
+    debug!("Exported the driver on dbus as {}", name);
+
+    let owner = unsafe { libc::getuid() };
+    let mut calls = bus.method_calls();
+    let mut status = controller.borrow().status();
+    let mut last = status.borrow().clone();
+    let mut pending: FuturesUnordered<LocalBoxFuture<Message>> = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            call = calls.recv() => match call {
+                Some(call) => pending.push(match access {
+                    Some(access) => handle_allowed(bus, access, owner, controller.clone(), call).boxed_local(),
+                    None => handle(controller.clone(), call),
+                }),
+                None => return,
+            },
+            Some(reply) = pending.next(), if !pending.is_empty() => bus.send(reply),
+            changed = status.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                let current = status.borrow().clone();
+                let changed = status_properties(&current).into_iter()
+                    .zip(status_properties(&last))
+                    .filter(|&((_, ref new), (_, ref old))| new != old)
+                    .map(|(new, _)| new)
+                    .collect();
+                last = current;
+                bus.send(properties_changed(changed));
+            }
+        }
+    }
+}
+
+/// The properties that change along with the status.
+fn status_properties(status: &Status) -> Vec<(&'static str, MessageItem)> {
+    vec![
+        ("GaState", ga_state(status.ga).into()),
+        ("IoState", io_state(status.io).into()),
+        ("UsbDevices", MessageItem::UInt32(status.usb_devices.len() as u32)),
+        ("Paused", status.paused.into()),
+        ("Backup", status.backup.into()),
+        ("ShuttingDown", status.shutting_down.into()),
+    ]
+}
+
+fn properties(controller: &Controller) -> Vec<(&'static str, MessageItem)> {
+    let mut props = status_properties(&controller.status().borrow());
+    props.push(("Started", MessageItem::UInt64(controller.started())));
+    props
+}
+
+fn ga_state(ga: GaStatus) -> &'static str {
+    match ga {
+        GaStatus::Down => "down",
+        GaStatus::Up => "up",
+        GaStatus::Suspending => "suspending",
+        GaStatus::Suspended => "suspended",
+        GaStatus::Resuming => "resuming",
+    }
+}
+
+fn io_state(io: IoMode) -> &'static str {
+    match io {
+        IoMode::Detached => "detached",
+        IoMode::LightEntry => "light_entry",
+        IoMode::FullEntry => "full_entry",
+    }
+}
+
+fn to_dict(props: Vec<(&'static str, MessageItem)>) -> MessageItem {
+    MessageItem::from_dict::<(), _>(props.into_iter().map(|(k, v)| Ok((k.to_owned(), v)))).unwrap()
+}
+
+fn properties_changed(changed: Vec<(&'static str, MessageItem)>) -> Message {
+    with_items(Message::new_signal(PATH, PROPERTIES, "PropertiesChanged").unwrap(),
+               &[INTERFACE.into(), to_dict(changed), MessageItem::Array(Vec::new(), "s".into())])
+}
+
+fn with_items(mut msg: Message, items: &[MessageItem]) -> Message {
+    msg.append_items(items);
+    msg
+}
+
+fn error(call: &Message, name: &str, message: &str) -> Message {
+    Message::new_error(call, name, message).unwrap()
+}
+
+fn invalid_args(call: &Message, message: &str) -> Message {
+    error(call, "org.freedesktop.DBus.Error.InvalidArgs", message)
+}
+
+/// Replies to `call` once `done` completes.
+async fn reply_when_done<F>(call: Message, done: F) -> Message
+    where F: std::future::Future<Output=Result<Result<(), String>, ()>>
+{
+    match done.await {
+        Ok(Ok(())) => Message::new_method_return(&call).unwrap(),
+        Ok(Err(e)) => error(&call, "org.windowsgaming.Driver1.Error.Refused", &e),
+        Err(()) => error(&call, "org.windowsgaming.Driver1.Error.Failed", "The driver gave up"),
+    }
+}
+
+/// The permission needed to call `member`, `None` if anyone may.
+fn required(interface: &str, member: &str) -> Option<Permission> {
+    match (interface, member) {
+        (PROPERTIES, _) => Some(Permission::Status),
+        (INTERFACE, "Attach") | (INTERFACE, "Detach") => Some(Permission::Attach),
+        (INTERFACE, "Suspend") | (INTERFACE, "Pause") | (INTERFACE, "Resume") => Some(Permission::Suspend),
+        (INTERFACE, "Shutdown") | (INTERFACE, "Save") => Some(Permission::Shutdown),
+        (INTERFACE, "EnterBackupMode") | (INTERFACE, "LeaveBackupMode") => Some(Permission::Backup),
+        _ => None,
+    }
+}
+
+/// Asks the bus who sent `call`.
+async fn caller(bus: &Bus<'_>, call: &Message) -> Result<Peer, String> {
+    let sender = call.sender().as_deref().map(str::to_owned).ok_or("the call has no sender")?;
+    let mut msg = Message::new_method_call("org.freedesktop.DBus",
+                                           "/org/freedesktop/DBus",
+                                           "org.freedesktop.DBus",
+                                           "GetConnectionUnixUser").unwrap();
+    msg.append_items(&[sender.into()]);
+    let reply = bus.call(msg).await.map_err(|e| format!("{:?}", e))?;
+    let uid: u32 = reply.get1().ok_or("the bus didn't tell the uid")?;
+    Ok(Peer::from_uid(uid))
+}
+
+/// Like `handle`, but only if the caller has the permission the call needs.
+async fn handle_allowed(bus: &Bus<'_>, access: &AccessConfig, owner: u32, controller: Rc<RefCell<Controller>>,
+                        call: Message) -> Message {
+    let interface = call.interface().as_deref().unwrap_or("").to_owned();
+    let member = call.member().as_deref().unwrap_or("").to_owned();
+    let permission = match required(&interface, &member) {
+        Some(permission) => permission,
+        None => return handle(controller, call).await,
+    };
+    let peer = match caller(bus, &call).await {
+        Ok(peer) => peer,
+        Err(e) => {
+            warn!("Can't tell who called {}.{} on dbus, refusing: {}", interface, member, e);
+            return error(&call, "org.freedesktop.DBus.Error.AccessDenied", "Can't tell who you are");
+        }
+    };
+    if !access::allowed(access, owner, &peer, permission) {
+        info!("Refused dbus call {} to {:?}, it lacks the {:?} permission", member, peer, permission);
+        return error(&call, "org.freedesktop.DBus.Error.AccessDenied",
+                     &format!("You need the {:?} permission for this", permission));
+    }
+    handle(controller, call).await
+}
+
+fn handle(controller: Rc<RefCell<Controller>>, call: Message) -> LocalBoxFuture<'static, Message> {
+    let interface = call.interface().as_deref().unwrap_or("").to_owned();
+    let member = call.member().as_deref().unwrap_or("").to_owned();
+    let args = call.get_items();
+    trace!("dbus call {}.{}({:?})", interface, member, args);
+
+    let reply = match (&*interface, &*member) {
+        (INTROSPECTABLE, "Introspect") => {
+            with_items(Message::new_method_return(&call).unwrap(), &[INTROSPECTION.into()])
+        }
+        (PROPERTIES, "Get") => match (args.get(0), args.get(1)) {
+            (Some(&MessageItem::Str(ref iface)), Some(&MessageItem::Str(ref name))) if iface == INTERFACE => {
+                match properties(&controller.borrow()).into_iter().find(|&(k, _)| k == name) {
+                    Some((_, value)) => with_items(Message::new_method_return(&call).unwrap(),
+                                                   &[MessageItem::Variant(Box::new(value))]),
+                    None => invalid_args(&call, "No such property"),
+                }
+            }
+            _ => invalid_args(&call, "No such interface"),
+        },
+        (PROPERTIES, "GetAll") => match args.get(0) {
+            Some(&MessageItem::Str(ref iface)) if iface == INTERFACE => {
+                let props = to_dict(properties(&controller.borrow()));
+                with_items(Message::new_method_return(&call).unwrap(), &[props])
+            }
+            _ => invalid_args(&call, "No such interface"),
+        },
+        (PROPERTIES, "Set") => error(&call, "org.freedesktop.DBus.Error.PropertyReadOnly", "All properties are read only"),
+        (INTERFACE, method) => {
+            info!("dbus request: {}", method);
+            let mut controller = controller.borrow_mut();
+            match method {
+                "Attach" => {
+                    match args.get(0) {
+                        Some(&MessageItem::Str(ref mode)) => match &**mode {
+                            "auto" => controller.io_attach(),
+                            "try" => controller.try_attach(),
+                            "force" => controller.io_force_attach(),
+                            "light" => controller.light_attach(),
+                            _ => return future_reply(invalid_args(&call, "mode must be auto, try, force or light")),
+                        },
+                        _ => return future_reply(invalid_args(&call, "Expected the attach mode")),
+                    }
+                    return reply_when_done(call, controller.usb_changes().map(Ok)).boxed_local();
+                }
+                "Detach" => {
+                    controller.io_detach();
+                    return reply_when_done(call, controller.usb_changes().map(Ok)).boxed_local();
+                }
+                "Shutdown" => controller.shutdown(),
+                "Pause" => controller.pause(),
+                "Resume" => controller.resume(),
+                "Suspend" => {
+                    let suspended = controller.suspend().compat();
+                    return async move {
+                        match suspended.await {
+                            Ok(()) => Message::new_method_return(&call).unwrap(),
+                            Err(()) => error(&call, "org.windowsgaming.Driver1.Error.Failed", "Windows didn't suspend"),
+                        }
+                    }.boxed_local();
+                }
+                "Save" => {
+                    let (tx, rx) = oneshot::channel();
+                    controller.save(tx);
+                    return reply_when_done(call, rx.map(|res| res.map_err(|_| ()))).boxed_local();
+                }
+                "EnterBackupMode" | "LeaveBackupMode" => {
+                    let (tx, rx) = oneshot::channel();
+                    match method {
+                        "EnterBackupMode" => controller.enter_backup_mode(tx),
+                        _ => controller.leave_backup_mode(tx),
+                    }
+                    return reply_when_done(call, rx.map(|res| res.map(Ok).map_err(|_| ()))).boxed_local();
+                }
+                _ => error(&call, "org.freedesktop.DBus.Error.UnknownMethod", "No such method"),
+            }
+            Message::new_method_return(&call).unwrap()
+        }
+        _ => error(&call, "org.freedesktop.DBus.Error.UnknownMethod", "No such method"),
+    };
+    future_reply(reply)
+}
+
+fn future_reply(reply: Message) -> LocalBoxFuture<'static, Message> {
+    futures03::future::ready(reply).boxed_local()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::mpsc as std_mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::Stream;
+
+    use crate::controller::testing;
+    use crate::dbus::testing::{block_on, PrivateBus};
+    use crate::monitor::QmpCommand;
+
+    /// Calls `method` on the driver from another connection, like a client would.
+    fn call(method: &'static str, interface: &'static str, args: Vec<MessageItem>)
+            -> std_mpsc::Receiver<Result<Vec<MessageItem>, String>> {
+        let (tx, rx) = std_mpsc::channel();
+        thread::spawn(move || {
+            let conn = PrivateBus::connect();
+            let call = with_items(Message::new_method_call(NAME, PATH, interface, method).unwrap(), &args);
+            let reply = conn.send_with_reply_and_block(call, 2000)
+                .map(|reply| reply.get_items())
+                .map_err(|e| e.name().unwrap_or("").to_owned());
+            tx.send(reply).unwrap();
+        });
+        rx
+    }
+
+    async fn wait<T>(rx: &std_mpsc::Receiver<T>) -> T {
+        loop {
+            match rx.try_recv() {
+                Ok(x) => return x,
+                Err(std_mpsc::TryRecvError::Empty) => tokio::time::sleep(Duration::from_millis(10)).await,
+                Err(std_mpsc::TryRecvError::Disconnected) => panic!("client died"),
+            }
+        }
+    }
+
+    fn property<'a>(dict: &'a MessageItem, name: &str) -> &'a MessageItem {
+        match *dict {
+            MessageItem::Array(ref entries, _) => entries.iter().filter_map(|entry| match *entry {
+                MessageItem::DictEntry(ref k, ref v) if **k == MessageItem::Str(name.to_owned()) => match **v {
+                    MessageItem::Variant(ref v) => Some(&**v),
+                    _ => None,
+                },
+                _ => None,
+            }).next().expect("property missing"),
+            _ => panic!("not a dict: {:?}", dict),
+        }
+    }
+
+    #[test]
+    fn pause_and_properties() {
+        let _bus = PrivateBus::start();
+
+        // listens for PropertiesChanged before anything happens
+        let (signals_tx, signals) = std_mpsc::channel();
+        let (ready_tx, ready) = std_mpsc::channel();
+        thread::spawn(move || {
+            let conn = PrivateBus::connect();
+            conn.add_match(&format!("type='signal',path='{}',interface='{}'", PATH, PROPERTIES)).unwrap();
+            ready_tx.send(()).unwrap();
+            loop {
+                for item in conn.iter(50) {
+                    if let crate::libdbus::ConnectionItem::Signal(ref m) = item {
+                        if signals_tx.send(m.get_items()).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        ready.recv().unwrap();
+
+        block_on(async move {
+            let conn = PrivateBus::connect();
+            let bus = Bus::new(&conn);
+            let (controller, monitor_rx) = testing::fake();
+            let service = serve(&bus, controller.clone(), None, None);
+
+            let test = async {
+                // give the service a moment to own its name
+                tokio::time::sleep(Duration::from_millis(100)).await;
+
+                let all = wait(&call("GetAll", PROPERTIES, vec![INTERFACE.into()])).await.unwrap();
+                assert_eq!(property(&all[0], "Paused"), &MessageItem::Bool(false));
+                assert_eq!(property(&all[0], "GaState"), &MessageItem::Str("down".to_owned()));
+                assert_eq!(property(&all[0], "Started"), &MessageItem::UInt64(1234));
+
+                assert_eq!(wait(&call("Pause", INTERFACE, vec![])).await, Ok(vec![]));
+                assert!(matches!(monitor_rx.wait().next(), Some(Ok(QmpCommand::Stop))));
+                controller.borrow_mut().qemu_event(windows_gaming_client::protocol::QemuEvent::Stop);
+
+                let changed = wait(&signals).await;
+                assert_eq!(changed[0], MessageItem::Str(INTERFACE.to_owned()));
+                assert_eq!(property(&changed[1], "Paused"), &MessageItem::Bool(true));
+
+                let paused = wait(&call("Get", PROPERTIES, vec![INTERFACE.into(), "Paused".into()])).await.unwrap();
+                assert_eq!(paused, vec![MessageItem::Variant(Box::new(MessageItem::Bool(true)))]);
+
+                let bad = wait(&call("Attach", INTERFACE, vec!["sideways".into()])).await;
+                assert_eq!(bad, Err("org.freedesktop.DBus.Error.InvalidArgs".to_owned()));
+            };
+
+            tokio::select! {
+                _ = bus.run() => panic!("bus went down"),
+                _ = service => panic!("service went down"),
+                _ = test => (),
+            }
+        });
+    }
+}
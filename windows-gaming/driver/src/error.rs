@@ -0,0 +1,77 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that keeps the driver from running Windows.
+#[derive(Debug)]
+pub enum DriverError {
+    /// Another driver is running in the same runtime directory
+    AlreadyRunning(PathBuf),
+    /// Another instance is using a device we want
+    Conflict { instance: String, resource: String },
+    /// Preparing something on the host (directories, sockets, firmware, helpers) failed
+    Setup { what: String, error: io::Error },
+    /// The guest agent ISO isn't in the data directory
+    MissingGaIso(PathBuf),
+    /// Samba sharing is configured but samba isn't installed
+    SambaMissing,
+    InvalidConfig(String),
+    /// Querying udev failed
+    Udev(String),
+    /// Binding a PCI device to vfio failed
+    Vfio { slot: String, reason: String },
+    /// Starting or waiting for qemu failed
+    Qemu(io::Error),
+    /// Qemu didn't talk QMP to us the way it should
+    Monitor(String),
+}
+
+impl DriverError {
+    /// Wraps an `io::Error` that happened while doing `what`, for use with `map_err`.
+    pub fn setup<S: Into<String>>(what: S) -> impl FnOnce(io::Error) -> DriverError {
+        let what = what.into();
+        move |error| DriverError::Setup { what, error }
+    }
+
+    /// Exit code of the `windows-gaming` binary for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DriverError::AlreadyRunning(_) | DriverError::Conflict { .. } => 3,
+            DriverError::Setup { .. } | DriverError::MissingGaIso(_) | DriverError::SambaMissing
+                | DriverError::Udev(_) => 4,
+            DriverError::InvalidConfig(_) => 5,
+            DriverError::Vfio { .. } => 6,
+            DriverError::Qemu(_) | DriverError::Monitor(_) => 7,
+        }
+    }
+}
+
+impl Display for DriverError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            DriverError::AlreadyRunning(dir) => write!(f, "An instance of windows-gaming is already running in {}. \
+                Either quit that or select a different runtime directory.", dir.display()),
+            DriverError::Conflict { instance, resource } => write!(f, "The {} is already used by the running \
+                instance {}", resource, instance),
+            DriverError::Setup { what, error } => write!(f, "Failed {}: {}", what, error),
+            DriverError::MissingGaIso(path) => write!(f, "The guest agent ISO is missing at {}. \
+                Is windows-gaming installed correctly?", path.display()),
+            DriverError::SambaMissing => f.write_str("Samba sharing is configured but samba is not installed"),
+            DriverError::InvalidConfig(e) => write!(f, "Invalid configuration: {}", e),
+            DriverError::Udev(e) => write!(f, "Failed to query udev: {}", e),
+            DriverError::Vfio { slot, reason } => write!(f, "Failed to bind {} to vfio: {}", slot, reason),
+            DriverError::Qemu(e) => write!(f, "Failed to run qemu: {}", e),
+            DriverError::Monitor(e) => write!(f, "Failed to talk to qemu: {}", e),
+        }
+    }
+}
+
+impl Error for DriverError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DriverError::Setup { error, .. } | DriverError::Qemu(error) => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use common::hotkeys::{KeyBinding, KeyboardState, KeyResolution};
+use futures03::future::join_all;
+use tokio::io::unix::AsyncFd;
+
+use crate::controller::Controller;
+
+const EV_SYN: u16 = 0;
+const EV_KEY: u16 = 1;
+const SYN_REPORT: u16 = 0;
+const SYN_DROPPED: u16 = 3;
+const KEY_DOWN: i32 = 1;
+const KEY_REPEAT: i32 = 2;
+const KEY_CNT: usize = 0x300;
+/// `EVIOCGKEY(KEY_CNT / 8)`
+const EVIOCGKEY: libc::c_ulong = 0x8060_4518;
+
+/// How often we look for keyboards that aren't there, e.g. because Windows has them.
+const REOPEN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Watches the `keyboards` for the host hotkeys `bindings` and hands them to the controller.
+///
+/// Unlike light entry we only listen along, so the keyboards keep working for everyone else.
+pub async fn listener(keyboards: Vec<String>, bindings: Vec<KeyBinding>, controller: Rc<RefCell<Controller>>) {
+    if bindings.is_empty() {
+        if !keyboards.is_empty() {
+            warn!("host_keyboards are configured but there are no host_hotkeys");
+        }
+        return;
+    }
+    if keyboards.is_empty() {
+        warn!("host_hotkeys are configured but there are no host_keyboards to catch them on");
+        return;
+    }
+    join_all(keyboards.iter().map(|path| watch(path, &bindings, &controller))).await;
+}
+
+/// Listens on one keyboard forever, reopening it whenever it goes away.
+async fn watch(path: &str, bindings: &[KeyBinding], controller: &RefCell<Controller>) {
+    let mut complained = false;
+    loop {
+        match open(path) {
+            Ok(keyboard) => {
+                debug!("Listening for host hotkeys on {}", path);
+                complained = false;
+                match listen(&keyboard, bindings, controller).await {
+                    // it's normal for keyboards to disappear when they're attached to Windows
+                    Err(ref e) if e.raw_os_error() == Some(libc::ENODEV) => debug!("{} went away", path),
+                    Err(e) => warn!("Failed to read host hotkeys from {}: {}", path, e),
+                    Ok(()) => debug!("{} went away", path),
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => trace!("{} isn't there", path),
+            Err(e) if !complained => {
+                warn!("Can't open {} for host hotkeys: {}", path, e);
+                complained = true;
+            }
+            Err(_) => (),
+        }
+        tokio::time::sleep(REOPEN_INTERVAL).await;
+    }
+}
+
+fn open(path: &str) -> io::Result<AsyncFd<File>> {
+    let file = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(path)?;
+    AsyncFd::new(file)
+}
+
+/// Feeds key presses into a `KeyboardState` until the keyboard is gone.
+async fn listen(keyboard: &AsyncFd<File>, bindings: &[KeyBinding], controller: &RefCell<Controller>) -> io::Result<()> {
+    let mut state = KeyboardState::new(bindings);
+    // whatever is down already was pressed before we looked
+    state.sync_linux(&pressed_keys(keyboard.get_ref())?);
+    let mut dropped = false;
+    loop {
+        let repeat = state.next_repeat();
+        let mut guard = tokio::select! {
+            guard = keyboard.readable() => guard?,
+            _ = tokio::time::sleep_until(repeat.unwrap_or_else(Instant::now).into()), if repeat.is_some() => {
+                for index in state.repeat(Instant::now()) {
+                    controller.borrow_mut().host_hotkey(index);
+                }
+                continue;
+            }
+        };
+        let events = match guard.try_io(|file| read_events(file.get_ref())) {
+            Ok(events) => events?,
+            Err(_would_block) => continue,
+        };
+        if events.is_empty() {
+            return Ok(());
+        }
+        for index in handle_events(&mut state, &events, &mut dropped, || pressed_keys(keyboard.get_ref()))? {
+            controller.borrow_mut().host_hotkey(index);
+        }
+    }
+}
+
+/// Feeds the `events` of one read into `state` and returns the hotkeys they fire.
+///
+/// `dropped` carries over between reads whether the kernel dropped events and we wait for it to catch up.
+/// Afterwards, keys that `pressed` says aren't down anymore are forgotten, their releases went missing.
+fn handle_events<F>(state: &mut KeyboardState, events: &[libc::input_event], dropped: &mut bool, pressed: F)
+    -> io::Result<Vec<usize>> where F: FnOnce() -> io::Result<Vec<u32>> {
+    let mut fired = Vec::new();
+    for event in events {
+        match (event.type_, event.code) {
+            // up to the next report, what the kernel still has is incomplete, the resync below replaces it
+            (EV_SYN, SYN_DROPPED) => *dropped = true,
+            (EV_SYN, SYN_REPORT) => *dropped = false,
+            _ if *dropped => (),
+            // `KeyboardState` repeats hotkeys itself, if they want to
+            (EV_KEY, code) if event.value != KEY_REPEAT => {
+                if let Some(KeyResolution { hotkeys, .. }) = state.input_linux(code as u32, event.value == KEY_DOWN) {
+                    fired.extend(hotkeys);
+                }
+            }
+            _ => (),
+        }
+    }
+    // we neither see releases while light entry has the keyboard grabbed nor those the kernel dropped
+    state.sync_linux(&pressed()?);
+    Ok(fired)
+}
+
+/// The keys that are down right now, according to the kernel.
+fn pressed_keys(file: &File) -> io::Result<Vec<u32>> {
+    let mut bits = [0u8; KEY_CNT / 8];
+    if unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGKEY, bits.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((0..KEY_CNT).filter(|&code| bits[code / 8] & (1 << (code % 8)) != 0).map(|code| code as u32).collect())
+}
+
+/// Reads the events that are available, none means end of file.
+fn read_events(file: &File) -> io::Result<Vec<libc::input_event>> {
+    let mut events: [libc::input_event; 64] = unsafe { mem::zeroed() };
+    let size = mem::size_of::<libc::input_event>();
+    let read = unsafe {
+        libc::read(file.as_raw_fd(), events.as_mut_ptr() as *mut libc::c_void, size * events.len())
+    };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(events[..read as usize / size].to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY_RIGHTCTRL: u16 = 97;
+
+    fn event(type_: u16, code: u16, value: i32) -> libc::input_event {
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+        event.type_ = type_;
+        event.code = code;
+        event.value = value;
+        event
+    }
+
+    fn key(code: u16, value: i32) -> [libc::input_event; 2] {
+        [event(EV_KEY, code, value), event(EV_SYN, SYN_REPORT, 0)]
+    }
+
+    #[test]
+    fn release_in_a_later_read() {
+        let bindings: Vec<KeyBinding> = vec![serde_json::from_str(r#"{"keys": "RCtrl", "on_release": true}"#).unwrap()];
+        let mut state = KeyboardState::new(&bindings);
+        let mut dropped = false;
+
+        let fired = handle_events(&mut state, &key(KEY_RIGHTCTRL, 1), &mut dropped, || Ok(vec![KEY_RIGHTCTRL as u32]));
+        assert!(fired.unwrap().is_empty());
+        let fired = handle_events(&mut state, &key(KEY_RIGHTCTRL, 0), &mut dropped, || Ok(vec![]));
+        assert_eq!(fired.unwrap(), [0]);
+    }
+
+    #[test]
+    fn dropped_events() {
+        let bindings: Vec<KeyBinding> = vec![serde_json::from_str(r#"{"keys": "RCtrl", "on_release": true}"#).unwrap()];
+        let mut state = KeyboardState::new(&bindings);
+        let mut dropped = false;
+
+        let fired = handle_events(&mut state, &key(KEY_RIGHTCTRL, 1), &mut dropped, || Ok(vec![KEY_RIGHTCTRL as u32]));
+        assert!(fired.unwrap().is_empty());
+        // the release got lost, what came after it until the next report doesn't count
+        let events = [event(EV_SYN, SYN_DROPPED, 0), event(EV_KEY, KEY_RIGHTCTRL, 0)];
+        let fired = handle_events(&mut state, &events, &mut dropped, || Ok(vec![]));
+        assert!(fired.unwrap().is_empty());
+        assert!(dropped);
+        let fired = handle_events(&mut state, &[event(EV_SYN, SYN_REPORT, 0)], &mut dropped, || Ok(vec![]));
+        assert!(fired.unwrap().is_empty());
+        assert!(!dropped);
+
+        // it was forgotten, so pressing it again works as usual
+        handle_events(&mut state, &key(KEY_RIGHTCTRL, 1), &mut dropped, || Ok(vec![KEY_RIGHTCTRL as u32])).unwrap();
+        let fired = handle_events(&mut state, &key(KEY_RIGHTCTRL, 0), &mut dropped, || Ok(vec![]));
+        assert_eq!(fired.unwrap(), [0]);
+    }
+}
@@ -0,0 +1,356 @@
+use std::cell::RefCell;
+use std::fs;
+use std::mem;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::rc::Rc;
+use std::time::Duration;
+
+use common::config::HooksConfig;
+use futures03::future::{BoxFuture, FutureExt};
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use windows_gaming_client::protocol::{QemuEvent, Status};
+
+/// Something the user can hook into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    Ready,
+    Attach,
+    Detach,
+    Down,
+    GaUp,
+    GaDown,
+    Suspend,
+    Resume,
+    BackupStart,
+    BackupStop,
+    GuestCrash,
+    Hotkey,
+    QemuEvent,
+}
+
+impl Hook {
+    /// The name in `blocking`, `WG_HOOK` and the hook directory.
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::Ready => "ready",
+            Hook::Attach => "attach",
+            Hook::Detach => "detach",
+            Hook::Down => "down",
+            Hook::GaUp => "ga-up",
+            Hook::GaDown => "ga-down",
+            Hook::Suspend => "suspend",
+            Hook::Resume => "resume",
+            Hook::BackupStart => "backup-start",
+            Hook::BackupStop => "backup-stop",
+            Hook::GuestCrash => "guest-crash",
+            Hook::Hotkey => "hotkey",
+            Hook::QemuEvent => "qemu-event",
+        }
+    }
+
+    /// Whether the hook runs before what it is about, so failing can stop it.
+    pub fn can_block(self) -> bool {
+        match self {
+            Hook::Attach | Hook::Suspend | Hook::BackupStart => true,
+            _ => false,
+        }
+    }
+}
+
+/// What a hook is told on stdin.
+#[derive(Serialize)]
+struct Context<'a> {
+    hook: &'static str,
+    instance: Option<&'a str>,
+    status: &'a Status,
+    /// What happened beyond the status change, e.g. the qemu event
+    #[serde(skip_serializing_if = "Value::is_null")]
+    details: Value,
+}
+
+/// Runs the hooks of one instance.
+pub struct Hooks {
+    config: HooksConfig,
+    instance: Option<String>,
+    /// Hooks running in the background, see `fire`
+    fired: RefCell<Vec<JoinHandle<()>>>,
+}
+
+impl Hooks {
+    pub fn new(config: HooksConfig, instance: Option<&str>) -> Rc<Hooks> {
+        for name in &config.blocking {
+            match ALL.iter().find(|h| h.name() == name) {
+                Some(hook) if hook.can_block() => (),
+                Some(_) => warn!("The {} hook runs too late to stop anything, it won't block", name),
+                None => warn!("Unknown blocking hook {}", name),
+            }
+        }
+        Rc::new(Hooks { config, instance: instance.map(str::to_owned), fired: RefCell::new(Vec::new()) })
+    }
+
+    /// Whether the driver waits for `hook` and only goes ahead if it succeeded.
+    pub fn blocks(&self, hook: Hook) -> bool {
+        hook.can_block() && self.config.blocking.iter().any(|name| name == hook.name())
+    }
+
+    fn command(&self, hook: Hook) -> Option<&String> {
+        let c = &self.config;
+        match hook {
+            Hook::Ready => c.ready.as_ref(),
+            Hook::Attach => c.attach.as_ref(),
+            Hook::Detach => c.detach.as_ref(),
+            Hook::Down => c.down.as_ref(),
+            Hook::GaUp => c.ga_up.as_ref(),
+            Hook::GaDown => c.ga_down.as_ref(),
+            Hook::Suspend => c.suspend.as_ref(),
+            Hook::Resume => c.resume.as_ref(),
+            Hook::BackupStart => c.backup_start.as_ref(),
+            Hook::BackupStop => c.backup_stop.as_ref(),
+            Hook::GuestCrash => c.guest_crash.as_ref(),
+            Hook::Hotkey => c.hotkey.as_ref(),
+            // depends on the event, see `fire_event`
+            Hook::QemuEvent => None,
+        }
+    }
+
+    /// Runs the configured command for `hook` and then the executables in its directory, one after another.
+    ///
+    /// Stops at the first one that fails.
+    pub fn run(&self, hook: Hook, status: &Status, details: Value) -> BoxFuture<'static, Result<(), String>> {
+        self.run_with(hook, self.command(hook).cloned(), status, details, Vec::new())
+    }
+
+    /// Runs `hook` in the background, failures are just logged.
+    pub fn fire(&self, hook: Hook, status: &Status, details: Value) {
+        self.spawn_logged(hook, self.run(hook, status, details));
+    }
+
+    /// Runs the hooks for a qemu `event` in the background.
+    pub fn fire_event(&self, event: &QemuEvent, status: &Status) {
+        let command = self.config.events.get(event.name()).or_else(|| self.config.events.get("*")).cloned();
+        let details = serde_json::to_value(event).unwrap();
+        let env = vec![("WG_EVENT", event.name().to_owned()), ("WG_EVENT_JSON", details.to_string())];
+        self.spawn_logged(Hook::QemuEvent, self.run_with(Hook::QemuEvent, command, status, details, env));
+    }
+
+    fn spawn_logged(&self, hook: Hook, run: BoxFuture<'static, Result<(), String>>) {
+        let mut fired = self.fired.borrow_mut();
+        fired.retain(|task| !task.is_finished());
+        // not local, we run hooks before the driver's local tasks get going
+        fired.push(tokio::spawn(async move {
+            if let Err(e) = run.await {
+                warn!("The {} hook failed: {}", hook.name(), e);
+            }
+        }));
+    }
+
+    /// Waits for the hooks still running in the background, e.g. the detach hook after qemu went down.
+    ///
+    /// They would be cut off along with the runtime otherwise.
+    pub async fn finish_fired(&self) {
+        let fired = mem::take(&mut *self.fired.borrow_mut());
+        for task in fired {
+            let _ = task.await;
+        }
+    }
+
+    fn run_with(&self, hook: Hook, command: Option<String>, status: &Status, details: Value,
+                mut env: Vec<(&'static str, String)>) -> BoxFuture<'static, Result<(), String>> {
+        let mut commands = Vec::new();
+        if let Some(command) = command {
+            let mut sh = Command::new("/bin/sh");
+            sh.arg("-c").arg(&command);
+            commands.push((command, sh));
+        }
+        if let Some(ref dir) = self.config.directory {
+            let dir = Path::new(dir).join(format!("{}.d", hook.name()));
+            for path in executables(&dir) {
+                commands.push((path.display().to_string(), Command::new(path)));
+            }
+        }
+        if commands.is_empty() {
+            return futures03::future::ready(Ok(())).boxed();
+        }
+
+        debug!("Running the {} hook", hook.name());
+        let context = Context { hook: hook.name(), instance: self.instance.as_deref(), status, details };
+        let input = serde_json::to_vec(&context).unwrap();
+        env.push(("WG_HOOK", hook.name().to_owned()));
+        env.push(("WG_INSTANCE", self.instance.clone().unwrap_or_default()));
+        env.push(("WG_GA", name_of(&status.ga)));
+        env.push(("WG_IO_MODE", name_of(&status.io)));
+        env.push(("WG_USB_DEVICES", status.usb_devices.len().to_string()));
+        let timeout = self.config.timeout.map(Duration::from_secs);
+        async move {
+            for (name, mut command) in commands {
+                command.envs(env.iter().map(|&(k, ref v)| (k, v)));
+                run_one(&name, command, &input, timeout).await?;
+            }
+            Ok(())
+        }.boxed()
+    }
+}
+
+const ALL: [Hook; 13] = [Hook::Ready, Hook::Attach, Hook::Detach, Hook::Down, Hook::GaUp, Hook::GaDown, Hook::Suspend,
+                         Hook::Resume, Hook::BackupStart, Hook::BackupStop, Hook::GuestCrash, Hook::Hotkey,
+                         Hook::QemuEvent];
+
+/// How an enum value is called in the status JSON.
+fn name_of<T: serde::Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+/// The executables in `dir` sorted by name, skipping hidden files and backups like run-parts does.
+fn executables(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            warn!("Can't read hook directory {}: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+    let mut paths: Vec<_> = entries.filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            !name.starts_with('.') && !name.ends_with('~')
+        })
+        .map(|entry| entry.path())
+        .filter(|path| fs::metadata(path).map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false))
+        .collect();
+    paths.sort();
+    paths
+}
+
+async fn run_one(name: &str, mut command: Command, input: &[u8], timeout: Option<Duration>) -> Result<(), String> {
+    command.stdin(Stdio::piped());
+    let mut child = command.spawn().map_err(|e| format!("can't run `{}`: {}", name, e))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let run = async {
+        // hooks that don't care about the context may well exit without reading it
+        let _ = stdin.write_all(input).await;
+        drop(stdin);
+        child.wait().await
+    };
+    let status = match timeout {
+        Some(timeout) => {
+            let status = tokio::time::timeout(timeout, run).await;
+            match status {
+                Ok(status) => status,
+                Err(_) => {
+                    // only hooks that took too long are killed, the rest may outlive the driver
+                    let _ = child.kill().await;
+                    return Err(format!("`{}` didn't finish within {}s", name, timeout.as_secs()));
+                }
+            }
+        }
+        None => run.await,
+    };
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("`{}` failed with {}", name, status)),
+        Err(e) => Err(format!("error waiting for `{}`: {}", name, e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use windows_gaming_client::protocol::{GaStatus, IoMode, UsbDevice};
+
+    use crate::dbus::testing::block_on;
+
+    fn status() -> Status {
+        let usb_devices = vec![
+            UsbDevice { id: "usb0".to_owned(), binding: "id 046d:c52b".to_owned() },
+            UsbDevice { id: "usb2".to_owned(), binding: "port 3.1".to_owned() },
+        ];
+        Status { ga: GaStatus::Up, io: IoMode::FullEntry, usb_devices, paused: false, backup: false,
+                 shutting_down: false }
+    }
+
+    #[test]
+    fn command_then_directory() {
+        let dir = std::env::temp_dir().join(format!("windows-gaming-hooks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("attach.d")).unwrap();
+        let log = dir.join("log");
+        for (name, mode) in [("20-second", 0o755), ("10-first", 0o755), ("15-not-executable", 0o644)] {
+            let script = dir.join("attach.d").join(name);
+            fs::write(&script, format!("#!/bin/sh\necho {} >> {}\n", name, log.display())).unwrap();
+            fs::set_permissions(&script, fs::Permissions::from_mode(mode)).unwrap();
+        }
+
+        let config = HooksConfig {
+            attach: Some(format!("echo \"$WG_HOOK $WG_INSTANCE $WG_IO_MODE $WG_USB_DEVICES\" >> {0}; cat >> {0}; echo >> {0}",
+                                 log.display())),
+            directory: Some(dir.display().to_string()),
+            ..HooksConfig::default()
+        };
+        let hooks = Hooks::new(config, Some("gaming"));
+        block_on(async {
+            hooks.run(Hook::Attach, &status(), Value::Null).await.unwrap();
+        });
+
+        let log = fs::read_to_string(&log).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines[0], "attach gaming full_entry 2");
+        let context: Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(context["hook"], "attach");
+        assert_eq!(context["instance"], "gaming");
+        assert_eq!(context["status"]["usb_devices"][1]["id"], "usb2");
+        assert_eq!(context["status"]["usb_devices"][1]["binding"], "port 3.1");
+        assert_eq!(&lines[2..], ["10-first", "20-second"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failure_and_timeout() {
+        let config = HooksConfig {
+            suspend: Some("exit 3".to_owned()),
+            resume: Some("sleep 10".to_owned()),
+            timeout: Some(1),
+            blocking: vec!["suspend".to_owned(), "resume".to_owned()],
+            ..HooksConfig::default()
+        };
+        let hooks = Hooks::new(config, None);
+        assert!(hooks.blocks(Hook::Suspend));
+        // resume comes too late to stop anything
+        assert!(!hooks.blocks(Hook::Resume));
+        block_on(async {
+            let failed = hooks.run(Hook::Suspend, &status(), Value::Null).await.unwrap_err();
+            assert!(failed.contains("exit status: 3"), "{}", failed);
+            let timed_out = hooks.run(Hook::Resume, &status(), Value::Null).await.unwrap_err();
+            assert!(timed_out.contains("didn't finish"), "{}", timed_out);
+            // nothing configured is fine
+            hooks.run(Hook::Attach, &status(), Value::Null).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn fired_hooks_get_to_finish() {
+        let done = std::env::temp_dir().join(format!("windows-gaming-fired-{}", std::process::id()));
+        let _ = fs::remove_file(&done);
+        let config = HooksConfig {
+            detach: Some(format!("sleep 0.2; touch {}", done.display())),
+            ..HooksConfig::default()
+        };
+        let hooks = Hooks::new(config, None);
+        block_on(async {
+            hooks.fire(Hook::Detach, &status(), Value::Null);
+            hooks.finish_fired().await;
+        });
+        assert!(done.exists());
+        fs::remove_file(&done).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use common::config::{Config, UsbBinding};
+
+use crate::controller::IoMode;
+
+pub use windows_gaming_client::{dir_name, PREFIX};
+
+/// Checks that `name` is usable as part of a file name (and a systemd instance name).
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("instance names can't be empty".to_owned());
+    }
+    match name.chars().find(|&c| !(c.is_ascii_alphanumeric() || c == '-' || c == '_')) {
+        Some(c) => Err(format!("instance names may only contain letters, digits, - and _, not {:?}", c)),
+        None => Ok(()),
+    }
+}
+
+/// An instance found in the runtime directory `dir`.
+#[derive(Debug)]
+pub struct Instance {
+    /// `None` for the default instance
+    pub name: Option<String>,
+    pub dir: PathBuf,
+    /// Whether a driver is listening on the control socket
+    pub driver_running: bool,
+    /// The persisted state, if its qemu is running
+    pub state: Option<InstanceState>,
+}
+
+impl Instance {
+    pub fn display_name(&self) -> &str {
+        self.name.as_ref().map(String::as_str).unwrap_or("default")
+    }
+}
+
+/// Finds all instances with runtime directories in `parent` that have either a driver or qemu running.
+///
+/// Instances with a `runtime_directory_override` elsewhere can't be found.
+pub fn running(parent: &Path) -> io::Result<Vec<Instance>> {
+    let mut instances = Vec::new();
+    for entry in fs::read_dir(parent)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        let name = if file_name == PREFIX {
+            None
+        } else if file_name.starts_with(PREFIX) && file_name[PREFIX.len()..].starts_with('@') {
+            Some(file_name[PREFIX.len() + 1..].to_owned())
+        } else {
+            continue;
+        };
+
+        let dir = entry.path();
+        let driver_running = UnixStream::connect(dir.join("control.sock")).is_ok();
+        let state = InstanceState::load_live(&dir);
+        if driver_running || state.is_some() {
+            instances.push(Instance { name, dir, driver_running, state });
+        }
+    }
+    instances.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(instances)
+}
+
+/// Everything a driver needs to take over a qemu that outlived its previous driver.
+///
+/// Lives in the runtime directory and is rewritten whenever any of it changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstanceState {
+    pub qemu_pid: libc::pid_t,
+    pub swtpm_pid: Option<libc::pid_t>,
+    pub monitor_socket: PathBuf,
+    pub clientpipe_socket: PathBuf,
+    /// Whether the guest agent was up, it won't say hello again to a new driver
+    pub ga_up: bool,
+    pub io_mode: IoMode,
+    /// USB devices attached by full entry
+    #[serde(default)]
+    pub attached_usb: Vec<AttachedUsb>,
+    /// When qemu was started, in seconds since the epoch
+    #[serde(default)]
+    pub started: u64,
+    /// PCI slots passed through to qemu, no other instance may use them
+    #[serde(default)]
+    pub pci_devices: Vec<String>,
+    /// USB devices that may be attached to qemu, no other instance may use them
+    #[serde(default)]
+    pub usb_bindings: Vec<UsbBinding>,
+}
+
+/// A USB device full entry added to qemu.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttachedUsb {
+    /// qemu's id for it
+    pub id: String,
+    pub binding: UsbBinding,
+}
+
+impl InstanceState {
+    pub fn file(tmp: &Path) -> PathBuf {
+        tmp.join("instance.json")
+    }
+
+    /// Loads the state left behind in `tmp`, but only if its qemu is still running.
+    pub fn load_live(tmp: &Path) -> Option<InstanceState> {
+        let file = match File::open(InstanceState::file(tmp)) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read the instance state: {}", e);
+                return None;
+            }
+        };
+        let state: InstanceState = match serde_json::from_reader(file) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Ignoring malformed instance state: {}", e);
+                return None;
+            }
+        };
+
+        if !state.qemu_alive() {
+            debug!("qemu {} of the previous instance is gone", state.qemu_pid);
+            return None;
+        }
+        Some(state)
+    }
+
+    /// Describes the first device both this instance and `cfg` want to use.
+    pub fn conflict(&self, cfg: &Config) -> Option<String> {
+        let machine = &cfg.machine;
+        if let Some(pci) = machine.pci_devices.iter().find(|d| self.pci_devices.contains(&d.slot)) {
+            return Some(format!("PCI device {}", pci.slot));
+        }
+        machine.usb_devices.iter().find(|d| self.usb_bindings.contains(&d.binding))
+            .map(|usb| format!("USB device {:?}", usb.binding))
+    }
+
+    /// Writes the state to `tmp` atomically.
+    pub fn save(&self, tmp: &Path) -> io::Result<()> {
+        let file = InstanceState::file(tmp);
+        let partial = file.with_extension("json.partial");
+        serde_json::to_writer(File::create(&partial)?, self)?;
+        fs::rename(partial, file)
+    }
+
+    /// Whether `qemu_pid` still is the qemu we started, not just some process that inherited its pid.
+    pub fn qemu_alive(&self) -> bool {
+        if !pid_alive(self.qemu_pid) {
+            return false;
+        }
+        // qemu's command line contains its monitor socket, which is unique to our runtime directory
+        let cmdline = match fs::read(format!("/proc/{}/cmdline", self.qemu_pid)) {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+        let monitor = format!("path={},", self.monitor_socket.display());
+        cmdline.split(|&b| b == 0).any(|arg| String::from_utf8_lossy(arg).contains(&monitor))
+    }
+}
+
+/// Whether a process with this pid exists (zombies included).
+pub fn pid_alive(pid: libc::pid_t) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) }
+}
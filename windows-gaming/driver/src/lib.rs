@@ -5,6 +5,8 @@ extern crate log;
 extern crate dbus as libdbus;
 
 pub mod qemu;
+pub mod backup;
+pub mod instance;
 pub use crate::control::ControlCmdIn;
 use futures03::{FutureExt, StreamExt, TryFutureExt, TryStreamExt};
 use futures03::compat::Future01CompatExt;
@@ -17,87 +19,282 @@ mod control;
 mod monitor;
 mod clientpipe;
 mod controller;
+mod hooks;
 mod sd_notify;
 mod samba;
 mod dbus;
+mod dbus_service;
 mod sleep_inhibitor;
 mod libinput;
+mod evdev;
 mod clipboard;
+mod shutdown;
+mod teardown;
+mod error;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use std::os::unix::net::UnixStream;
 use std::fs::{self, Permissions};
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
-use std::process::Command;
 use std::io::ErrorKind;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use futures::{Future, Stream, future};
 use futures::unsync::mpsc;
 
-use common::config::Config;
+use common::config::{Config, RestartPolicy};
+use common::util;
 
-use crate::controller::Controller;
-use crate::monitor::Monitor;
+use crate::controller::{Controller, IoMode};
+use crate::hooks::{Hook, Hooks};
+use crate::instance::InstanceState;
+use crate::monitor::{Monitor, QmpCommand};
 use crate::clientpipe::Clientpipe;
 use crate::libinput::Input;
 use crate::clipboard::X11Clipboard;
+use crate::teardown::Teardown;
+pub use crate::error::DriverError;
+
+/// How a run of Windows ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Windows went down because we asked it to
+    Shutdown,
+    /// Windows shut down on its own
+    GuestShutdown,
+    /// Windows (or qemu) crashed
+    Crashed,
+    /// Something went wrong on our end
+    Error,
+}
+
+impl Outcome {
+    /// Exit code of the `windows-gaming` binary for this outcome
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Shutdown | Outcome::GuestShutdown => 0,
+            Outcome::Error => 1,
+            Outcome::Crashed => 2,
+        }
+    }
+
+    fn should_restart(self, policy: RestartPolicy) -> bool {
+        match (policy, self) {
+            (RestartPolicy::Never, _) => false,
+            (_, Outcome::Crashed) => true,
+            (RestartPolicy::Always, Outcome::GuestShutdown) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Runs Windows, starting it again according to the configured restart policy.
+///
+/// `instance` is the name of the instance, `None` for the default one.
+pub fn run(cfg: &Config, instance: Option<&str>, tmp: &Path, data: &Path, state: &Path, enable_gui: bool)
+           -> Result<Outcome, DriverError> {
+    let restart = &cfg.restart;
+    let mut backoff = Duration::from_secs(restart.backoff_initial);
+    let activated_control_socket = sd_notify::activated_socket();
+    loop {
+        let started = Instant::now();
+        let outcome = run_once(cfg, instance, tmp, data, state, activated_control_socket.as_ref(), enable_gui)?;
+        info!("Windows is down: {:?}", outcome);
+        if !outcome.should_restart(restart.policy) {
+            return Ok(outcome);
+        }
+
+        if started.elapsed() >= Duration::from_secs(restart.backoff_reset) {
+            backoff = Duration::from_secs(restart.backoff_initial);
+        }
+        warn!("Restarting Windows in {}s", backoff.as_secs());
+        sd_notify::notify_systemd(false, "Waiting to restart ...");
+        sd_notify::sleep(backoff);
+        backoff = (backoff * 2).min(Duration::from_secs(restart.backoff_max));
+    }
+}
 
 #[tokio::main(flavor = "current_thread")]
-pub async fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
+async fn run_once(cfg: &Config, instance_name: Option<&str>, tmp: &Path, data: &Path, state: &Path,
+                  activated_control_socket: Option<&std::os::unix::net::UnixListener>, enable_gui: bool)
+                  -> Result<Outcome, DriverError> {
+    // declared first so it is dropped last, after everything that might still use what it cleans up
+    let mut teardown = Teardown::new();
+    // binding devices to vfio and waiting for qemu's sockets takes a while, and `watchdog` only runs once we're up
+    let startup_watchdog = sd_notify::keep_alive();
+
     let control_socket_file = tmp.join("control.sock");
-    // first check for running sessions
-    match UnixStream::connect(&control_socket_file) {
-        Err(e) => match e.kind() {
-            ErrorKind::ConnectionRefused => (), // previous instance existed but is down now
-            ErrorKind::NotFound => (), // no previous instance
-            _ => warn!("Error while checking for running instances: {:?}", e), // ??? (but continue anyway)
-        },
-        Ok(_) => {
-            error!("An instance of windows-gaming is already running in this runtime directory.");
-            error!("Either quit that or select a different runtime directory.");
-            return;
+    if activated_control_socket.is_none() {
+        // first check for running sessions
+        match UnixStream::connect(&control_socket_file) {
+            Err(e) => match e.kind() {
+                ErrorKind::ConnectionRefused => (), // previous instance existed but is down now
+                ErrorKind::NotFound => (), // no previous instance
+                _ => warn!("Error while checking for running instances: {:?}", e), // ??? (but continue anyway)
+            },
+            Ok(_) => return Err(DriverError::AlreadyRunning(tmp.to_owned())),
         }
     }
 
-    let _ = fs::remove_dir_all(tmp); // may fail - we dont care
-    fs::create_dir(tmp).expect("Failed to create TMP_FOLDER"); // may not fail - has to be new
+    // a previous driver died without taking its qemu down
+    let previous = InstanceState::load_live(tmp);
+    match previous {
+        Some(ref previous) => {
+            warn!("Found qemu (pid {}) left behind by a previous driver, reattaching", previous.qemu_pid);
+            if activated_control_socket.is_none() {
+                match fs::remove_file(&control_socket_file) {
+                    Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+                    x => x.map_err(DriverError::setup("removing the stale control socket"))?,
+                }
+            }
+        }
+        // systemd holds the control socket, so we may not remove it along with the rest of the runtime directory
+        None if activated_control_socket.is_some() => clean_runtime_dir(tmp, &control_socket_file)
+            .map_err(DriverError::setup("cleaning the runtime directory"))?,
+        None => {
+            let _ = fs::remove_dir_all(tmp); // may fail - we dont care
+            fs::create_dir(tmp).map_err(DriverError::setup("creating the runtime directory"))?; // has to be new
+        }
+    }
     trace!("created tmp dir");
+    {
+        let tmp = tmp.to_owned();
+        let keep = match activated_control_socket {
+            Some(_) => control_socket_file.clone(),
+            None => tmp.clone(),
+        };
+        teardown.register_after_qemu("cleaning up the runtime directory", move || match keep == tmp {
+            true => fs::remove_dir_all(&tmp),
+            false => clean_runtime_dir(&tmp, &keep),
+        });
+    }
 
     let monitor_socket_file = tmp.join("monitor.sock");
-    let monitor_socket = UnixListener::bind(&monitor_socket_file)
-        .expect("Failed to create monitor socket");
-    debug!("Started Monitor");
-
     let clientpipe_socket_file = tmp.join("clientpipe.sock");
-    let clientpipe_socket = UnixListener::bind(&clientpipe_socket_file)
-        .expect("Failed to create clientpipe socket");
-    debug!("Started Clientpipe");
-
-    let control_socket = UnixListener::bind(&control_socket_file)
-        .expect("Failed to create control socket");
-    fs::set_permissions(control_socket_file, Permissions::from_mode(0o777))
-        .expect("Failed to set permissions on control socket");
+
+    let control_socket = match activated_control_socket {
+        Some(socket) => socket.try_clone()
+            .and_then(|socket| socket.set_nonblocking(true).map(|()| socket))
+            .and_then(UnixListener::from_std)
+            .map_err(DriverError::setup("using the control socket passed by systemd"))?,
+        None => {
+            let socket = UnixListener::bind(&control_socket_file)
+                .map_err(DriverError::setup("creating the control socket"))?;
+            fs::set_permissions(&control_socket_file, Permissions::from_mode(0o777))
+                .map_err(DriverError::setup("setting permissions on the control socket"))?;
+            socket
+        }
+    };
     debug!("Started Control socket");
 
-    let qemu_child = qemu::run(cfg, tmp, data, &clientpipe_socket_file, &monitor_socket_file, enable_gui);
-    let qemu = qemu_child.wait_with_output().boxed().compat().map(|code| {
-            if !code.status.success() {
-                warn!("QEMU returned with an error code: {}", code.status);
+    fs::create_dir_all(state).map_err(DriverError::setup("creating the state directory"))?;
+    let save_file = state.join("saved-vm.state");
+    let restoring = previous.is_none() && save_file.exists();
+    if restoring {
+        info!("Found saved state at {}, restoring", save_file.display());
+    } else if previous.is_some() && save_file.exists() {
+        // either qemu is done with it or it's still reading the file, which survives the unlink
+        fs::remove_file(&save_file).map_err(DriverError::setup("removing the consumed saved state"))?;
+    }
+    {
+        let partial = crate::controller::partial_save_file(&save_file);
+        teardown.register("removing incomplete saved state", move || match fs::remove_file(&partial) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            x => x,
+        });
+    }
+
+    let qemu_status = Rc::new(Cell::new(None));
+    let (instance, qemu): (_, Box<dyn Future<Item=(), Error=std::io::Error>>) = match previous {
+        Some(ref previous) => {
+            qemu::register_reattached(cfg, data, previous.swtpm_pid, &mut teardown);
+            // qemu isn't our child, so all we can do is watch whether it's still there
+            let pid = previous.qemu_pid;
+            let qemu = async move {
+                let mut timer = tokio::time::interval(Duration::from_secs(1));
+                while instance::pid_alive(pid) {
+                    timer.tick().await;
+                }
+                Ok::<(), std::io::Error>(())
+            };
+            (previous.clone(), Box::new(qemu.boxed_local().compat()))
+        }
+        None => {
+            check_conflicts(cfg, tmp)?;
+            let (qemu_child, swtpm_pid) = qemu::run(cfg, tmp, data, &clientpipe_socket_file, &monitor_socket_file,
+                                                    restoring, enable_gui, &mut teardown)?;
+            let qemu_pid = qemu_child.id().ok_or_else(|| DriverError::Monitor("qemu exited right away".to_owned()))?;
+            let instance = InstanceState {
+                qemu_pid: qemu_pid as libc::pid_t,
+                swtpm_pid,
+                monitor_socket: monitor_socket_file.clone(),
+                clientpipe_socket: clientpipe_socket_file.clone(),
+                ga_up: false,
+                io_mode: IoMode::Detached,
+                attached_usb: Vec::new(),
+                started: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+                pci_devices: cfg.machine.pci_devices.iter().map(|d| d.slot.clone()).collect(),
+                usb_bindings: cfg.machine.usb_devices.iter().map(|d| d.binding.clone()).collect(),
+            };
+            instance.save(tmp).map_err(DriverError::setup("saving the instance state"))?;
+            let qemu_status_ref = qemu_status.clone();
+            let qemu = qemu_child.wait_with_output().boxed().compat().map(move |code| {
+                    if !code.status.success() {
+                        warn!("QEMU returned with an error code: {}", code.status);
+                    }
+                    qemu_status_ref.set(Some(code.status));
+                });
+            (instance, Box::new(qemu))
+        }
+    };
+    {
+        // a qemu left running is reattached to by the next driver, but that needs the instance state
+        let tmp = tmp.to_owned();
+        let qemu_status = qemu_status.clone();
+        teardown.watch_qemu(move || qemu_status.get().is_none() && InstanceState::load_live(&tmp).is_some());
+    }
+    {
+        let instance = instance.clone();
+        let qemu_status = qemu_status.clone();
+        let instance_file = InstanceState::file(tmp);
+        teardown.register("stopping qemu", move || {
+            // qemu is normally long gone (and reaped) by now, this only matters if we are going down first
+            // before saving the instance state, so nobody could take over. Once reaped, its pid may well belong to
+            // someone else.
+            if qemu_status.get().is_none() && !instance_file.exists() && instance.qemu_alive() {
+                warn!("qemu is still running, killing it");
+                unsafe {
+                    libc::kill(instance.qemu_pid, libc::SIGKILL);
+                    libc::waitpid(instance.qemu_pid, std::ptr::null_mut(), 0);
+                }
             }
+            Ok(())
         });
+    }
+
+    let monitor_stream = connect(&monitor_socket_file, instance.qemu_pid).await
+        .map_err(DriverError::setup("connecting to the monitor socket"))?;
+    debug!("Connected to Monitor");
 
-    let (monitor_stream, _) = monitor_socket.accept().await.expect("Failed to get monitor");
-    drop(monitor_socket);
+    // qemu created it along with the monitor socket, unless an older driver started it
+    let passthrough_stream = match tokio::net::UnixStream::connect(monitor::passthrough::socket(tmp)).await {
+        Ok(stream) => Some(stream),
+        Err(e) => {
+            warn!("Can't connect to the QMP passthrough, running without: {}", e);
+            None
+        }
+    };
 
-    let (clientpipe_stream, _) = clientpipe_socket.accept().await.expect("Failed to get clientpipe");
-    drop(clientpipe_socket);
+    let clientpipe_stream = connect(&clientpipe_socket_file, instance.qemu_pid).await
+        .map_err(DriverError::setup("connecting to the clientpipe socket"))?;
+    debug!("Connected to Clientpipe");
 
-    sd_notify::notify_systemd(false, "Booting ...");
+    sd_notify::notify_systemd(false, if previous.is_some() { "Reattaching ..." } else { "Booting ..." });
     debug!("Windows is starting");
 
-    let mut monitor = Monitor::new(monitor_stream).await;
+    let mut monitor = Monitor::new(monitor_stream, passthrough_stream).await?;
     let mut clientpipe = Clientpipe::new(clientpipe_stream);
 
     let (mut input, input_events) = Input::new(cfg.machine.clone());
@@ -109,36 +306,121 @@ pub async fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
     let (clipread_send, clipread_recv) = mpsc::unbounded();
     let (resp_send, resp_recv) = mpsc::unbounded();
 
-    let ctrl = Controller::new(cfg.machine.clone(), cfg.hooks.clone(), monitor_sender.clone(), clientpipe.take_send(), input.clone(),
-                               resp_send, clipgrab_send, clipread_send);
+    let hooks = Hooks::new(cfg.hooks.clone(), instance_name);
+    let controller = Rc::new_cyclic(|this| {
+        RefCell::new(Controller::new(cfg.machine.clone(), hooks.clone(), cfg.backup.clone(),
+                                     cfg.shutdown.clone(), cfg.watchdog.clone(), save_file.clone(), restoring,
+                                     tmp.to_owned(), instance, monitor_sender.clone(),
+                                     clientpipe.take_send(), input.clone(),
+                                     resp_send, clipgrab_send, clipread_send, this.clone()))
+    });
+    let ga_was_up = previous.is_some() && controller.borrow_mut().reattach();
+    let ctrl = controller.clone();
+    let reattached_pinger = async move {
+        // the GA only says hello once, so it won't get our pings going again
+        if ga_was_up {
+            clientpipe::start_pinging(&ctrl.borrow(), ctrl.clone());
+        }
+    };
 
-    let controller = Rc::new(RefCell::new(ctrl));
+    let (restore_ack, restored) = tokio::sync::oneshot::channel();
+    if restoring {
+        let uri = format!("exec:cat {}", util::shell_quote(&save_file.display().to_string()));
+        if monitor_sender.unbounded_send(QmpCommand::MigrateIncoming { uri, ack: restore_ack }).is_err() {
+            return Err(DriverError::Monitor("qemu went away before restoring the saved state".to_owned()));
+        }
+    }
+    let ctrl = controller.clone();
+    let restore_watcher = async move {
+        if let Ok(Err(e)) = restored.await {
+            ctrl.borrow_mut().restore_failed(&e);
+        }
+    };
 
 
-    let clipboard = X11Clipboard::open().compat().await.expect("Failed to open X11 clipboard!");
-    let clipboard_listener = clipboard.run(controller.clone(), resp_recv);
+    let clipboard = match X11Clipboard::open().compat().await {
+        Ok(clipboard) => Some(clipboard),
+        Err(e) => {
+            warn!("Failed to open the clipboard, running without clipboard sharing: {:?}", e);
+            None
+        }
+    };
+    let clipboard = clipboard.as_ref();
+    let ctrl = controller.clone();
+    let clipboard_listener = async move {
+        if let Some(clipboard) = clipboard {
+            clipboard.run(ctrl, resp_recv).await;
+        }
+    };
 
-    let clipboard_grabber = clipgrab_recv.for_each(|types| {
-        clipboard.grab_clipboard(types);
+    // without a clipboard, requests from Windows just go nowhere
+    let clipboard_grabber = clipgrab_recv.for_each(move |types| {
+        if let Some(clipboard) = clipboard {
+            clipboard.grab_clipboard(types);
+        }
         Ok(())
     }).then(|_| Ok(()));
 
-    let clipboard_reader = clipread_recv.for_each(|kind| {
-        clipboard.read_clipboard(kind);
+    let clipboard_reader = clipread_recv.for_each(move |kind| {
+        if let Some(clipboard) = clipboard {
+            clipboard.read_clipboard(kind);
+        }
         Ok(())
     }).then(|_| Ok(()));
 
-    let sysbus = sleep_inhibitor::system_dbus();
+    let sysbus_conn = sleep_inhibitor::system_dbus();
+    let sysbus = sysbus_conn.as_ref().map(dbus::Bus::new);
+    let sessionbus_conn = sleep_inhibitor::session_dbus();
+    let sessionbus = sessionbus_conn.as_ref().map(dbus::Bus::new);
     let ctrl = controller.clone();
-    let inhibitor = sleep_inhibitor::sleep_inhibitor(&sysbus, move || ctrl.borrow_mut().suspend());
+    let ctrl2 = controller.clone();
+    let io_mode = controller.borrow().io_mode();
+    // system instances are exported on the system bus, everyone else's on their session bus
+    let system_instance = unsafe { libc::getuid() } == 0;
+    let dbus_handler = async {
+        let sysbus_handler = async {
+            if let Some(ref bus) = sysbus {
+                let logind_inhibitor = sleep_inhibitor::logind_inhibitor(bus, cfg.shutdown.host_max_delay.map(Duration::from_secs),
+                                                                         move || ctrl.borrow_mut().suspend(),
+                                                                         move || ctrl2.borrow_mut().shutdown());
+                let idle_inhibitor = sleep_inhibitor::idle_inhibitor(bus, sessionbus.as_ref(), cfg.idle_inhibit.clone(),
+                                                                     io_mode);
+                let service = async {
+                    if system_instance {
+                        dbus_service::serve(bus, controller.clone(), instance_name, Some(&cfg.access)).await;
+                    }
+                };
+                tokio::join!(bus.run(), logind_inhibitor, idle_inhibitor, service);
+            }
+        };
+        let sessionbus_handler = async {
+            if let Some(ref bus) = sessionbus {
+                let service = async {
+                    if !system_instance {
+                        dbus_service::serve(bus, controller.clone(), instance_name, None).await;
+                    }
+                };
+                tokio::join!(bus.run(), service);
+            }
+        };
+        tokio::join!(sysbus_handler, sessionbus_handler);
+        Ok::<(), std::io::Error>(())
+    };
 
     let ref input_ref = *input;
     let input_listener = libinput::InputListener(input_ref);
     let hotkey_bindings: Vec<_> = cfg.machine.hotkeys.iter().map(|x| x.key.clone()).collect();
-    let input_handler = libinput::create_handler(input_events, &hotkey_bindings, controller.clone(),
-                                                 monitor_sender);
+    let input_handler = libinput::handler(input_events, &hotkey_bindings, controller.clone(), monitor_sender);
+
+    let host_hotkeys = evdev::listener(cfg.machine.host_keyboards.clone(),
+                                       cfg.machine.host_hotkeys.iter().map(|x| x.key.clone()).collect(),
+                                       controller.clone());
+
+    let control_handler = control::create(control_socket, controller.clone(), cfg.access.clone(), monitor.qmp_events());
 
-    let control_handler = control::create(control_socket, controller.clone());
+    let status_reporter = sd_notify::status_reporter(controller.borrow().status());
+
+    let backup_scheduler = backup::scheduler(cfg.backup.clone(), controller.clone());
 
     let sigint = SignalStream::new(signal(SignalKind::interrupt()).unwrap());
     let sigterm = SignalStream::new(signal(SignalKind::terminate()).unwrap());
@@ -149,32 +431,42 @@ pub async fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
     }).then(|_| Ok(()));
 
     let joined = future::join_all(vec![
-        inhibitor,
         clientpipe.take_handler(controller.clone()),
+        Box::new(dbus_handler.boxed_local().compat()),
         clientpipe.take_sender(),
         control_handler,
         monitor.take_handler(controller.clone()),
         Box::new(catch_sigterm),
         Box::new(input_listener.compat()),
-        input_handler,
+        Box::new(input_handler.boxed_local().compat()),
         Box::new(clipboard_listener.map(Ok).boxed_local().compat()),
         Box::new(clipboard_grabber),
         Box::new(clipboard_reader),
+        Box::new(backup_scheduler.map(Ok).boxed_local().compat()),
+        Box::new(host_hotkeys.map(Ok).boxed_local().compat()),
+        Box::new(sd_notify::watchdog().map(Ok).boxed_local().compat()),
+        Box::new(status_reporter.map(Ok).boxed_local().compat()),
+        Box::new(reattached_pinger.map(Ok).boxed_local().compat()),
+        Box::new(restore_watcher.map(Ok).boxed_local().compat()),
     ]).map(|_| ());
 
     // in case of errors on our end, give qemu one second to wind down so we don't erroneously think that we crashed
     let joined = joined.or_else(|e| {
-        tokio::time::sleep(std::time::Duration::from_secs(1)).boxed_local().map(move |()| Err(e)).compat()
+        tokio::time::sleep(Duration::from_secs(1)).boxed_local().map(move |()| Err(e)).compat()
     });
 
+    drop(startup_watchdog);
     let ls = LocalSet::new();
-    let main_loop_legacy = qemu.select2(joined).then(|x| -> Box<dyn Future<Item=(), Error=std::io::Error>> {
+    let driver_failed = Rc::new(Cell::new(false));
+    let driver_failed_ref = driver_failed.clone();
+    let main_loop_legacy = qemu.select2(joined).then(move |x| -> Box<dyn Future<Item=(), Error=std::io::Error>> {
         match x {
             Ok(future::Either::A((_, _))) => info!("qemu down first, all ok"),
             Err(future::Either::A((e, _))) => return Box::new(future::err(e)),
             Ok(future::Either::B((_, _))) => unreachable!(), // we never return cleanly
             Err(future::Either::B((e, a))) => {
                 error!("We errored: {}", e);
+                driver_failed_ref.set(true);
                 return Box::new(a); // we errored first, wait for qemu to exit
             }
         }
@@ -184,17 +476,91 @@ pub async fn run(cfg: &Config, tmp: &Path, data: &Path, enable_gui: bool) {
     let main_loop_modern = async move {
         main_loop_legacy.compat().await
     };
-    ls.run_until(main_loop_modern).await.expect("Waiting for qemu errored");
-
-    info!("unbinding resettable vfio-things");
+    ls.run_until(main_loop_modern).await.map_err(DriverError::Qemu)?;
+
+    let outcome = match qemu_status.get() {
+        _ if driver_failed.get() => Outcome::Error,
+        // qemu didn't exit on its own, so it (or the host) crashed
+        Some(status) if status.code().is_none() => Outcome::Crashed,
+        Some(status) if !status.success() => match controller.borrow().outcome() {
+            Outcome::Crashed => Outcome::Crashed,
+            _ => Outcome::Error,
+        },
+        _ => controller.borrow().outcome(),
+    };
 
-    for dev in cfg.machine.pci_devices.iter().filter(|x| x.resettable) {
-        let mut child = Command::new(data.join("vfio-ubind")).arg(&dev.slot).arg("-r").spawn().expect("failed to run vfio-ubind");
-        match child.wait() {
-            Ok(status) if status.success() => (), // all is well
-            Ok(status) => error!("vfio-ubind failed with {}! The device might still be bound to the vfio-driver!", status),
-            Err(err) => error!("failed to wait on child. Got: {}", err)
+    // qemu gives up on a saved state it can't load by exiting
+    controller.borrow_mut().restore_failed("qemu went down before Windows ran again");
+
+    let status = controller.borrow().status().borrow().clone();
+    let details = serde_json::json!({ "outcome": format!("{:?}", outcome) });
+    {
+        let _watchdog = sd_notify::keep_alive();
+        // e.g. a detach or backup-stop hook may still be busy with what qemu let go of
+        hooks.finish_fired().await;
+        if let Err(e) = hooks.run(Hook::Down, &status, details).await {
+            warn!("The down hook failed: {}", e);
         }
     }
+
+    teardown.run();
     info!("windows-gaming-driver down.");
+    Ok(outcome)
+}
+
+/// Makes sure no other instance uses any of our devices.
+fn check_conflicts(cfg: &Config, tmp: &Path) -> Result<(), DriverError> {
+    let parent = match tmp.parent() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let others = match instance::running(parent) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to look for other instances, can't check whether they use our devices: {}", e);
+            return Ok(());
+        }
+    };
+    for other in others.iter().filter(|i| i.dir != tmp) {
+        if let Some(resource) = other.state.as_ref().and_then(|s| s.conflict(cfg)) {
+            return Err(DriverError::Conflict { instance: other.display_name().to_owned(), resource });
+        }
+    }
+    Ok(())
+}
+
+/// Connects to a socket qemu listens on, waiting for qemu to create it.
+async fn connect(path: &Path, qemu_pid: libc::pid_t) -> std::io::Result<tokio::net::UnixStream> {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        match tokio::net::UnixStream::connect(path).await {
+            Err(ref e) if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::ConnectionRefused => {
+                if !instance::pid_alive(qemu_pid) {
+                    return Err(std::io::Error::new(ErrorKind::Other, "qemu exited"));
+                }
+                if Instant::now() >= deadline {
+                    return Err(std::io::Error::new(ErrorKind::TimedOut, "qemu didn't create it in time"));
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            x => return x,
+        }
+    }
+}
+
+/// Empties the runtime directory except for `keep`.
+fn clean_runtime_dir(tmp: &Path, keep: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(tmp)?;
+    for entry in fs::read_dir(tmp)? {
+        let path = entry?.path();
+        if path == keep {
+            continue;
+        }
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
 }
@@ -6,9 +6,11 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use std::borrow::Cow;
 use std::os::unix::io::RawFd;
+use std::time::Instant;
 
-use futures::{Future, Stream, Sink};
 use futures::unsync::mpsc::{UnboundedSender, UnboundedReceiver, self};
+use futures03::compat::Stream01CompatExt;
+use futures03::StreamExt;
 use input::{Libinput, LibinputInterface, Device, AccelProfile};
 use input::event::{Event, KeyboardEvent, PointerEvent};
 use input::event::pointer::{Axis, ButtonState, PointerScrollEvent};
@@ -192,91 +194,113 @@ impl<'a> futures03::Future for InputListener<'a> {
     }
 }
 
-pub fn create_handler<'a>(input_events: UnboundedReceiver<Event>, hotkey_bindings: &'a [KeyBinding],
-                          controller: Rc<RefCell<Controller>>, monitor_sender: UnboundedSender<QmpCommand>)
-            -> Box<dyn Future<Item = (), Error = io::Error> + 'a> {
+pub async fn handler(input_events: UnboundedReceiver<Event>, hotkey_bindings: &[KeyBinding],
+                     controller: Rc<RefCell<Controller>>, monitor_sender: UnboundedSender<QmpCommand>) -> io::Result<()> {
     let mut keyboard_state = KeyboardState::new(hotkey_bindings);
-    let input_handler = input_events.filter_map(move |event| {
-        Some(match event {
-            Event::Pointer(PointerEvent::Motion(m)) =>
-                QmpCommand::InputSendEvent {
-                    events: Cow::from(vec![
-                        InputEvent::Rel { axis: "x", value: m.dx() as i64 },
-                        InputEvent::Rel { axis: "y", value: m.dy() as i64 },
-                    ])
-                },
-            Event::Pointer(PointerEvent::Button(b)) =>
-                QmpCommand::InputSendEvent {
-                    events: Cow::from(vec![InputEvent::Btn {
-                        down: b.button_state() == ButtonState::Pressed,
-                        button: match b.button() {
-                            BTN_LEFT => InputButton::Left,
-                            BTN_RIGHT => InputButton::Right,
-                            BTN_MIDDLE => InputButton::Middle,
-                            BTN_SIDE => InputButton::Side,
-                            BTN_EXTRA => InputButton::Extra,
-                            b => {
-                                warn!("Unknown mouse button {}", b);
-                                return None;
-                            }
-                        }
-                    }])
-                },
-            Event::Pointer(PointerEvent::ScrollWheel(ref e)) if e.has_axis(Axis::Vertical) => {
-                let steps = (e.scroll_value_v120(Axis::Vertical) / 120.) as i32;
-                if steps == 0 {
-                    // stop event, ignore
-                    return None;
+    let mut input_events = input_events.compat();
+    loop {
+        // libinput doesn't repeat keys, so hotkeys that repeat need a timer
+        let repeat = keyboard_state.next_repeat();
+        let event = tokio::select! {
+            event = input_events.next() => match event {
+                Some(Ok(event)) => event,
+                _ => return Ok(()),
+            },
+            _ = tokio::time::sleep_until(repeat.unwrap_or_else(Instant::now).into()), if repeat.is_some() => {
+                for hk in keyboard_state.repeat(Instant::now()) {
+                    controller.borrow_mut().hotkey(hk as u32);
                 }
+                continue;
+            }
+        };
+        if let Some(cmd) = translate(event, &mut keyboard_state, &controller) {
+            if (&monitor_sender).unbounded_send(cmd).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
 
-                let direction = if steps > 0 {
-                    InputButton::WheelDown
-                } else {
-                    InputButton::WheelUp
-                };
-
-                let events: Vec<_> = iter::repeat(direction).take(steps.abs() as usize).flat_map(|b| vec![
-                    InputEvent::Btn { down: true, button: b },
-                    InputEvent::Btn { down: false, button: b },
-                ]).collect();
-
-                QmpCommand::InputSendEvent { events: Cow::from(events) }
+/// What to tell qemu about `event`, after handling hotkeys.
+fn translate(event: Event, keyboard_state: &mut KeyboardState, controller: &RefCell<Controller>) -> Option<QmpCommand> {
+    Some(match event {
+        Event::Pointer(PointerEvent::Motion(m)) =>
+            QmpCommand::InputSendEvent {
+                events: Cow::from(vec![
+                    InputEvent::Rel { axis: "x", value: m.dx() as i64 },
+                    InputEvent::Rel { axis: "y", value: m.dy() as i64 },
+                ])
             },
-            #[allow(deprecated)] // we're specifically ignoring this because it's deprecated
-            Event::Pointer(PointerEvent::Axis(_)) => return None,
-            Event::Keyboard(KeyboardEvent::Key(k)) => {
-                let down = k.key_state() == KeyState::Pressed;
-                let KeyResolution { hotkeys, qcode } = match keyboard_state.input_linux(k.key(), down) {
-                    Some(x) => x,
-                    None => return None,
-                };
-
-                for &hk in &hotkeys {
-                    controller.borrow_mut().ga_hotkey(hk as u32);
-                }
-                if !hotkeys.is_empty() {
-                    // If this was an IoExit hotkey, we just released all keys.
-                    // To avoid hung keys, do not forward keypresses that trigger hotkeys.
-                    return None;
-                }
+        Event::Pointer(PointerEvent::Button(b)) =>
+            QmpCommand::InputSendEvent {
+                events: Cow::from(vec![InputEvent::Btn {
+                    down: b.button_state() == ButtonState::Pressed,
+                    button: match b.button() {
+                        BTN_LEFT => InputButton::Left,
+                        BTN_RIGHT => InputButton::Right,
+                        BTN_MIDDLE => InputButton::Middle,
+                        BTN_SIDE => InputButton::Side,
+                        BTN_EXTRA => InputButton::Extra,
+                        b => {
+                            warn!("Unknown mouse button {}", b);
+                            return None;
+                        }
+                    }
+                }])
+            },
+        Event::Pointer(PointerEvent::ScrollWheel(ref e)) if e.has_axis(Axis::Vertical) => {
+            let steps = (e.scroll_value_v120(Axis::Vertical) / 120.) as i32;
+            if steps == 0 {
+                // stop event, ignore
+                return None;
+            }
 
-                match qcode {
-                    Some(qcode) => QmpCommand::InputSendEvent {
-                        events: Cow::from(vec![InputEvent::Key {
-                            down,
-                            key: KeyValue::Qcode(qcode),
-                        }])
-                    },
-                    None => return None
-                }
+            let direction = if steps > 0 {
+                InputButton::WheelDown
+            } else {
+                InputButton::WheelUp
+            };
+
+            let events: Vec<_> = iter::repeat(direction).take(steps.abs() as usize).flat_map(|b| vec![
+                InputEvent::Btn { down: true, button: b },
+                InputEvent::Btn { down: false, button: b },
+            ]).collect();
+
+            QmpCommand::InputSendEvent { events: Cow::from(events) }
+        },
+        #[allow(deprecated)] // we're specifically ignoring this because it's deprecated
+        Event::Pointer(PointerEvent::Axis(_)) => return None,
+        Event::Keyboard(KeyboardEvent::Key(k)) => {
+            let down = k.key_state() == KeyState::Pressed;
+            let KeyResolution { hotkeys, consumed, qcode } = match keyboard_state.input_linux(k.key(), down) {
+                Some(x) => x,
+                None => return None,
+            };
+
+            for &hk in &hotkeys {
+                controller.borrow_mut().hotkey(hk as u32);
             }
-            event => {
-                info!("Unhandled input event {:?}", event);
+            if consumed {
+                // If this was an IoExit hotkey, we just released all keys.
+                // To avoid hung keys, do not forward keypresses that trigger hotkeys.
                 return None;
             }
-        })
-    }).forward(monitor_sender.sink_map_err(|_| ())).then(|_| Ok(()));
-    Box::new(input_handler)
+
+            match qcode {
+                Some(qcode) => QmpCommand::InputSendEvent {
+                    events: Cow::from(vec![InputEvent::Key {
+                        down,
+                        key: KeyValue::Qcode(qcode),
+                    }])
+                },
+                None => return None
+            }
+        }
+        event => {
+            info!("Unhandled input event {:?}", event);
+            return None;
+        }
+    })
 }
 
 /*
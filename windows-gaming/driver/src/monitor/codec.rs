@@ -14,21 +14,51 @@ pub enum QmpCommand {
         port: usize,
         hostbus: u64,
         hostaddr: u64,
+        ack: tokio::sync::oneshot::Sender<Result<(), String>>,
     },
-    DeviceDel { id: String },
+    /// Asks qemu to remove device `id`, the ack only says whether it agreed, the actual removal is signalled
+    /// by a `DEVICE_DELETED` event.
+    DeviceDel { id: String, ack: tokio::sync::oneshot::Sender<Result<(), String>> },
     SystemPowerdown,
     SystemWakeup,
+    Quit,
+    Stop,
+    Cont,
+    /// Migrate the VM state to `uri`, acking once the migration completed (or failed).
+    Migrate { uri: String, ack: tokio::sync::oneshot::Sender<Result<(), String>> },
+    /// Load the VM state from `uri` (qemu has to be started with `-incoming defer`), acking once it is loaded (or failed).
+    MigrateIncoming { uri: String, ack: tokio::sync::oneshot::Sender<Result<(), String>> },
     InputSendEvent {
         events: Cow<'static, [InputEvent]>,
     },
-    TakeSnapshot { disk_id: usize, snap_file: String, ack: tokio::sync::oneshot::Sender<()> },
-    CommitSnapshot { disk_id: usize, snap_file: String, ack: tokio::sync::oneshot::Sender<()> },
+    /// Put a new overlay `snapshot_node` (backed by `snap_file`) on top of `node`.
+    TakeSnapshot { node: String, snapshot_node: String, snap_file: String, ack: tokio::sync::oneshot::Sender<()> },
+    /// Commit the overlays of disk `disk_id` into its base image.
+    ///
+    /// `top_node` selects the topmost overlay to commit, the whole chain up to `active_node` is committed if it is `None`.
+    /// `overlays` are the (node, file) pairs that disappear from the chain once the commit is done.
+    CommitSnapshot {
+        disk_id: usize,
+        active_node: String,
+        top_node: Option<String>,
+        overlays: Vec<(String, String)>,
+        ack: tokio::sync::oneshot::Sender<()>,
+    },
+
+    /// An arbitrary command for the QMP passthrough, see `passthrough::Request`.
+    Raw {
+        execute: String,
+        arguments: Option<serde_json::Value>,
+        ack: tokio::sync::oneshot::Sender<Result<serde_json::Value, String>>,
+    },
 
     // synthetic:
     ReleaseAllKeys,
 
     // hack:
     JobReady(String),
+    JobCompleted { device: String, error: Option<String> },
+    MigrationStatus(qmp::MigrationStatus),
 }
 
 #[derive(Serialize, Clone)]
@@ -0,0 +1,138 @@
+use std::fmt::Debug;
+
+use qapi::qmp;
+use serde::Serialize;
+use windows_gaming_client::protocol::QemuEvent;
+
+/// qemu's name of a QAPI enum value, e.g. `guest-panic`.
+fn qapi_name<T: Serialize + Debug>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => format!("{:?}", value),
+    }
+}
+
+/// The driver's view of `event`, `None` for the ones we don't care about.
+pub fn translate(event: &qmp::Event) -> Option<QemuEvent> {
+    Some(match *event {
+        qmp::Event::SHUTDOWN { data: qmp::SHUTDOWN { guest, ref reason }, .. } =>
+            QemuEvent::Shutdown { guest, reason: qapi_name(reason) },
+        qmp::Event::RESET { data: qmp::RESET { guest, ref reason }, .. } =>
+            QemuEvent::Reset { guest, reason: qapi_name(reason) },
+        qmp::Event::POWERDOWN { .. } => QemuEvent::Powerdown,
+        qmp::Event::STOP { .. } => QemuEvent::Stop,
+        qmp::Event::RESUME { .. } => QemuEvent::Resume,
+        qmp::Event::SUSPEND { .. } => QemuEvent::Suspend,
+        qmp::Event::WAKEUP { .. } => QemuEvent::Wakeup,
+        qmp::Event::GUEST_PANICKED { data: qmp::GUEST_PANICKED { ref action, .. }, .. } =>
+            QemuEvent::GuestPanicked { action: qapi_name(action) },
+        qmp::Event::WATCHDOG { data: qmp::WATCHDOG { ref action }, .. } =>
+            QemuEvent::Watchdog { action: qapi_name(action) },
+        qmp::Event::DEVICE_DELETED { data: qmp::DEVICE_DELETED { ref device, ref path, .. }, .. } =>
+            QemuEvent::DeviceDeleted { device: device.clone(), path: path.clone() },
+        qmp::Event::RTC_CHANGE { data: qmp::RTC_CHANGE { offset, .. }, .. } => QemuEvent::RtcChange { offset },
+        qmp::Event::BALLOON_CHANGE { data: qmp::BALLOON_CHANGE { actual }, .. } => QemuEvent::BalloonChange { actual },
+        qmp::Event::BLOCK_JOB_READY { data: qmp::BLOCK_JOB_READY { ref device, .. }, .. } =>
+            QemuEvent::BlockJobReady { device: device.clone() },
+        qmp::Event::BLOCK_JOB_COMPLETED { data: qmp::BLOCK_JOB_COMPLETED { ref device, ref error, .. }, .. } =>
+            QemuEvent::BlockJobCompleted { device: device.clone(), error: error.clone() },
+        qmp::Event::BLOCK_JOB_CANCELLED { data: qmp::BLOCK_JOB_CANCELLED { ref device, .. }, .. } =>
+            QemuEvent::BlockJobCancelled { device: device.clone() },
+        qmp::Event::BLOCK_JOB_ERROR { data: qmp::BLOCK_JOB_ERROR { ref device, ref operation, ref action }, .. } =>
+            QemuEvent::BlockJobError { device: device.clone(), operation: qapi_name(operation), action: qapi_name(action) },
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    /// Translates `event` the way qemu would send it.
+    fn translated(event: &str, data: serde_json::Value) -> Option<QemuEvent> {
+        let raw = json!({ "event": event, "data": data, "timestamp": { "seconds": 1497035586, "microseconds": 395911 } });
+        translate(&serde_json::from_value(raw).unwrap())
+    }
+
+    fn block_job(device: &str) -> serde_json::Value {
+        json!({ "type": "commit", "device": device, "len": 1024, "offset": 1024, "speed": 0 })
+    }
+
+    #[test]
+    fn shutdown() {
+        assert_eq!(translated("SHUTDOWN", json!({ "guest": true, "reason": "guest-panic" })),
+                   Some(QemuEvent::Shutdown { guest: true, reason: "guest-panic".to_owned() }));
+        assert_eq!(translated("SHUTDOWN", json!({ "guest": false, "reason": "host-qmp-quit" })),
+                   Some(QemuEvent::Shutdown { guest: false, reason: "host-qmp-quit".to_owned() }));
+    }
+
+    #[test]
+    fn reset() {
+        assert_eq!(translated("RESET", json!({ "guest": true, "reason": "guest-panic" })),
+                   Some(QemuEvent::Reset { guest: true, reason: "guest-panic".to_owned() }));
+    }
+
+    #[test]
+    fn without_data() {
+        assert_eq!(translated("POWERDOWN", json!({})), Some(QemuEvent::Powerdown));
+        assert_eq!(translated("STOP", json!({})), Some(QemuEvent::Stop));
+        assert_eq!(translated("RESUME", json!({})), Some(QemuEvent::Resume));
+        assert_eq!(translated("SUSPEND", json!({})), Some(QemuEvent::Suspend));
+        assert_eq!(translated("WAKEUP", json!({})), Some(QemuEvent::Wakeup));
+    }
+
+    #[test]
+    fn guest_panicked() {
+        assert_eq!(translated("GUEST_PANICKED", json!({ "action": "pause" })),
+                   Some(QemuEvent::GuestPanicked { action: "pause".to_owned() }));
+    }
+
+    #[test]
+    fn watchdog() {
+        assert_eq!(translated("WATCHDOG", json!({ "action": "poweroff" })),
+                   Some(QemuEvent::Watchdog { action: "poweroff".to_owned() }));
+    }
+
+    #[test]
+    fn device_deleted() {
+        assert_eq!(translated("DEVICE_DELETED", json!({ "device": "usb0", "path": "/machine/peripheral/usb0" })),
+                   Some(QemuEvent::DeviceDeleted { device: Some("usb0".to_owned()), path: "/machine/peripheral/usb0".to_owned() }));
+        assert_eq!(translated("DEVICE_DELETED", json!({ "path": "/machine/peripheral-anon/device[3]" })),
+                   Some(QemuEvent::DeviceDeleted { device: None, path: "/machine/peripheral-anon/device[3]".to_owned() }));
+    }
+
+    #[test]
+    fn rtc_change() {
+        assert_eq!(translated("RTC_CHANGE", json!({ "offset": -3600, "qom-path": "/machine/unattached/device[12]" })),
+                   Some(QemuEvent::RtcChange { offset: -3600 }));
+    }
+
+    #[test]
+    fn balloon_change() {
+        assert_eq!(translated("BALLOON_CHANGE", json!({ "actual": 4294967296u64 })),
+                   Some(QemuEvent::BalloonChange { actual: 4294967296 }));
+    }
+
+    #[test]
+    fn block_jobs() {
+        assert_eq!(translated("BLOCK_JOB_READY", block_job("drive0")),
+                   Some(QemuEvent::BlockJobReady { device: "drive0".to_owned() }));
+        assert_eq!(translated("BLOCK_JOB_COMPLETED", block_job("drive0")),
+                   Some(QemuEvent::BlockJobCompleted { device: "drive0".to_owned(), error: None }));
+        let mut failed = block_job("drive0");
+        failed["error"] = json!("No space left on device");
+        assert_eq!(translated("BLOCK_JOB_COMPLETED", failed),
+                   Some(QemuEvent::BlockJobCompleted { device: "drive0".to_owned(), error: Some("No space left on device".to_owned()) }));
+        assert_eq!(translated("BLOCK_JOB_CANCELLED", block_job("drive0")),
+                   Some(QemuEvent::BlockJobCancelled { device: "drive0".to_owned() }));
+        assert_eq!(translated("BLOCK_JOB_ERROR", json!({ "device": "drive0", "operation": "write", "action": "stop" })),
+                   Some(QemuEvent::BlockJobError { device: "drive0".to_owned(), operation: "write".to_owned(), action: "stop".to_owned() }));
+    }
+
+    #[test]
+    fn ignored() {
+        assert_eq!(translated("VSERPORT_CHANGE", json!({ "id": "channel0", "open": true })), None);
+    }
+}
@@ -1,4 +1,6 @@
 mod codec;
+mod events;
+pub mod passthrough;
 
 pub use self::codec::{
     QmpCommand,
@@ -24,11 +26,13 @@ use std::cell::{RefCell, Cell};
 use futures::unsync::mpsc::{self, UnboundedSender};
 use futures::Future;
 use futures03::compat::Stream01CompatExt;
-use qapi::futures::{QapiStream, QmpStreamTokio};
+use qapi::futures::{QapiService, QapiStream, QmpStreamTokio};
 use qapi::qmp;
 use tokio::io::{ReadHalf, WriteHalf};
+use tokio::sync::broadcast;
 
 use crate::controller::Controller;
+use crate::error::DriverError;
 use futures03::{FutureExt, StreamExt, TryFutureExt};
 use tokio::net::UnixStream;
 
@@ -40,6 +44,8 @@ pub struct Monitor {
     send2: Option<Send>,
     recv: Option<mpsc::UnboundedReceiver<QmpCommand>>,
     qapi: Option<QapiStream<QmpStreamTokio<ReadHalf<UnixStream>>, QmpStreamTokio<WriteHalf<UnixStream>>>>,
+    passthrough: Option<UnixStream>,
+    qmp_events: broadcast::Sender<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -61,13 +67,62 @@ pub enum CpuInfoFast {
     },
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Migrate {
+    uri: String,
+}
+impl qapi::Command for Migrate {
+	const NAME: &'static str = "migrate";
+	const ALLOW_OOB: bool = false;
+
+	type Ok = <qmp::stop as qapi::Command>::Ok;
+}
+impl qapi::qmp::QmpCommand for Migrate {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MigrateIncoming {
+    uri: String,
+}
+impl qapi::Command for MigrateIncoming {
+	const NAME: &'static str = "migrate-incoming";
+	const ALLOW_OOB: bool = false;
+
+	type Ok = <qmp::stop as qapi::Command>::Ok;
+}
+impl qapi::qmp::QmpCommand for MigrateIncoming {}
+
+struct PendingCommit {
+    active: bool,
+    overlays: Vec<(String, String)>,
+    ack: tokio::sync::oneshot::Sender<()>,
+}
+
+/// Cleans up after a successful block commit: drops the committed overlays from qemu and deletes their files.
+async fn finish_commit(qapi: &QapiService<QmpStreamTokio<WriteHalf<UnixStream>>>, pending: PendingCommit) {
+    for (node, file) in pending.overlays.into_iter().rev() {
+        // if the snapshot was created in the same session, qemu will (for some reason I don't fully understand)
+        // automatically remove the blockdev node, causing this to fail
+        // so we just ignore the result and that's it
+        let _ = qapi.execute(&qmp::blockdev_del { node_name: node }).await;
+
+        if let Err(e) = tokio::fs::remove_file(&file).await {
+            error!("Failed to remove snapshot file {} after applying it, please remove it manually: {}", file, e);
+        }
+    }
+    let _ = pending.ack.send(());
+}
+
 impl Monitor {
-    pub async fn new(stream: UnixStream) -> Monitor {
+    /// Takes over qemu's QMP `stream`, raw commands go to `passthrough` if there is one.
+    pub async fn new(stream: UnixStream, passthrough: Option<UnixStream>) -> Result<Monitor, DriverError> {
         let (r, w) = tokio::io::split(stream);
-        let nego = QmpStreamTokio::open_split(r, w).await.unwrap();
-        let mut qapi = nego.negotiate().await.unwrap();
+        let nego = QmpStreamTokio::open_split(r, w).await
+            .map_err(|e| DriverError::Monitor(format!("failed to open QMP stream: {}", e)))?;
+        let mut qapi = nego.negotiate().await
+            .map_err(|e| DriverError::Monitor(format!("QMP negotiation failed: {}", e)))?;
 
-        let resp = qapi.execute(QueryCpusFast {}).await.unwrap();
+        let resp = qapi.execute(QueryCpusFast {}).await
+            .map_err(|e| DriverError::Monitor(format!("failed to query vcpus: {:?}", e)))?;
         for c in resp {
             let (cpu, tid) = match c {
                 CpuInfoFast::X86_64 { base } => (base.cpu_index, base.thread_id),
@@ -82,18 +137,25 @@ impl Monitor {
 
         let (send, recv) = mpsc::unbounded();
 
-        Monitor {
+        Ok(Monitor {
             send2: Some(send.clone()),
             send: Some(send),
             recv: Some(recv),
             qapi: Some(qapi),
-        }
+            passthrough,
+            qmp_events: broadcast::channel(64).0,
+        })
     }
 
     pub fn take_send(&mut self) -> Send {
         self.send.take().unwrap()
     }
 
+    /// The raw QMP events from the passthrough connection.
+    pub fn qmp_events(&self) -> broadcast::Sender<serde_json::Value> {
+        self.qmp_events.clone()
+    }
+
     pub fn take_handler(&mut self, controller: Rc<RefCell<Controller>>) -> Handler {
         let send_to_myself = self.send2.take().unwrap();
 
@@ -109,32 +171,97 @@ impl Monitor {
                 };
 
                 info!("QAPI event: {:?}", event);
+                if let Some(qemu_event) = events::translate(&event) {
+                    controller.borrow_mut().qemu_event(qemu_event);
+                }
+                // some commands wait for events, so they are fed back to the command handler
                 match event {
-                    qmp::Event::SUSPEND { .. } => {
-                        controller.borrow_mut().qemu_suspended();
+                    qmp::Event::MIGRATION { data: qmp::MIGRATION { status }, .. } => {
+                        let _ = send_to_myself.unbounded_send(QmpCommand::MigrationStatus(status));
                     }
                     qmp::Event::BLOCK_JOB_READY { data: qmp::BLOCK_JOB_READY { device, .. }, .. } => {
                         let _ = send_to_myself.unbounded_send(QmpCommand::JobReady(device));
                     }
+                    qmp::Event::BLOCK_JOB_COMPLETED { data: qmp::BLOCK_JOB_COMPLETED { device, error, .. }, .. } => {
+                        let _ = send_to_myself.unbounded_send(QmpCommand::JobCompleted { device, error });
+                    }
+                    qmp::Event::BLOCK_JOB_CANCELLED { data: qmp::BLOCK_JOB_CANCELLED { device, .. }, .. } => {
+                        let error = Some("the job was cancelled".to_owned());
+                        let _ = send_to_myself.unbounded_send(QmpCommand::JobCompleted { device, error });
+                    }
                     _ => (),
                 }
             }
         };
+        let (passthrough_send, passthrough_recv) = tokio::sync::mpsc::unbounded_channel();
+        let passthrough = self.passthrough.take();
+        let qmp_events = self.qmp_events.clone();
+        let passthrough_handler = async move {
+            match passthrough {
+                Some(stream) => passthrough::run(stream, passthrough_recv, qmp_events).await,
+                None => {
+                    let mut requests = passthrough_recv;
+                    while let Some(req) = requests.recv().await {
+                        let _ = req.ack.send(Err("qemu has no QMP passthrough, it was probably started by an older \
+                                                  driver".to_owned()));
+                    }
+                }
+            }
+        };
         let mut commands = self.recv.take().unwrap().compat();
         let pending_disk_commits = Rc::new(RefCell::new(HashMap::new()));
         let command_handler = async move {
             let mut held_keys = HashSet::new();
+            let mut pending_migration = None;
             while let Some(Ok(cmd)) = commands.next().await {
                 let res = match cmd {
-                    QmpCommand::DeviceAdd { driver, id, bus, port, hostbus, hostaddr } =>
-                        qapi.execute(&qmp::device_add { id: Some(id), bus: Some(bus), driver: driver.to_owned(), arguments: vec![
+                    QmpCommand::DeviceAdd { driver, id, bus, port, hostbus, hostaddr, ack } => {
+                        let res = qapi.execute(&qmp::device_add { id: Some(id), bus: Some(bus), driver: driver.to_owned(), arguments: vec![
                             ("port".to_owned(), port.to_string().into()),
                             ("hostbus".to_owned(), hostbus.into()),
                             ("hostaddr".to_owned(), hostaddr.into()),
-                        ].into_iter().collect() }).await,
-                    QmpCommand::DeviceDel { id } => qapi.execute(&qmp::device_del { id }).await,
+                        ].into_iter().collect() }).await;
+                        let _ = ack.send(res.as_ref().map(|_| ()).map_err(|e| format!("{:?}", e)));
+                        res
+                    }
+                    QmpCommand::DeviceDel { id, ack } => {
+                        let res = qapi.execute(&qmp::device_del { id }).await;
+                        let _ = ack.send(res.as_ref().map(|_| ()).map_err(|e| format!("{:?}", e)));
+                        res
+                    }
                     QmpCommand::SystemPowerdown => qapi.execute(&qmp::system_powerdown {}).await,
                     QmpCommand::SystemWakeup => qapi.execute(&qmp::system_wakeup {}).await,
+                    QmpCommand::Quit => qapi.execute(&qmp::quit {}).await,
+                    QmpCommand::Stop => qapi.execute(&qmp::stop {}).await,
+                    QmpCommand::Cont => qapi.execute(&qmp::cont {}).await,
+                    QmpCommand::Migrate { uri, ack } => {
+                        let res = qapi.execute(Migrate { uri }).await;
+                        match res {
+                            Ok(_) => pending_migration = Some(ack),
+                            Err(ref e) => { let _ = ack.send(Err(format!("{:?}", e))); }
+                        }
+                        res
+                    }
+                    QmpCommand::MigrateIncoming { uri, ack } => {
+                        let res = qapi.execute(MigrateIncoming { uri }).await;
+                        match res {
+                            Ok(_) => pending_migration = Some(ack),
+                            Err(ref e) => { let _ = ack.send(Err(format!("{:?}", e))); }
+                        }
+                        res
+                    }
+                    QmpCommand::MigrationStatus(status) => {
+                        let result = match status {
+                            qmp::MigrationStatus::completed => Ok(()),
+                            qmp::MigrationStatus::failed => Err("migration failed".to_owned()),
+                            qmp::MigrationStatus::cancelled => Err("migration was cancelled".to_owned()),
+                            _ => continue,
+                        };
+                        if let Some(ack) = pending_migration.take() {
+                            let _ = ack.send(result);
+                        }
+                        continue;
+                    }
                     QmpCommand::InputSendEvent { events } => {
                         for e in events.as_ref() {
                             match e {
@@ -146,16 +273,20 @@ impl Monitor {
                         let input_send_event = qmp::input_send_event { device: None, head: None, events: events.into_iter().map(|i| i.clone().into()).collect() };
                         qapi.execute(&input_send_event).await
                     }
+                    QmpCommand::Raw { execute, arguments, ack } => {
+                        let _ = passthrough_send.send(passthrough::Request { execute, arguments, ack });
+                        continue;
+                    }
                     QmpCommand::ReleaseAllKeys => {
                         let events = held_keys.drain().map(|key| InputEvent::Key { key, down: false });
                         let input_send_event = qmp::input_send_event { device: None, head: None, events: events.into_iter().map(|i| i.clone().into()).collect() };
                         qapi.execute(&input_send_event).await
                     }
-                    QmpCommand::TakeSnapshot { disk_id, snap_file, ack } => {
+                    QmpCommand::TakeSnapshot { node, snapshot_node, snap_file, ack } => {
                         let res = qapi.execute(qmp::blockdev_snapshot_sync(qmp::BlockdevSnapshotSync {
-                            node_name: Some(format!("disk{disk_id}")),
+                            node_name: Some(node),
                             snapshot_file: snap_file,
-                            snapshot_node_name: Some(format!("disk{disk_id}_snap")),
+                            snapshot_node_name: Some(snapshot_node),
                             device: None,
                             format: Some("qcow2".to_owned()),
                             mode: None,
@@ -165,17 +296,21 @@ impl Monitor {
                         }
                         res
                     }
-                    QmpCommand::CommitSnapshot { disk_id, snap_file, ack } => {
+                    QmpCommand::CommitSnapshot { disk_id, active_node, top_node, overlays, ack } => {
                         let jobid = format!("disk{disk_id}");
-                        pending_disk_commits.borrow_mut().insert(jobid.clone(), (snap_file, ack));
+                        pending_disk_commits.borrow_mut().insert(jobid.clone(), PendingCommit {
+                            active: top_node.is_none(),
+                            overlays,
+                            ack,
+                        });
                         #[allow(deprecated)]
-                        qapi.execute(qmp::block_commit {
-                            job_id: Some(jobid),
-                            device: format!("disk{disk_id}_snap"),
+                        let res = qapi.execute(qmp::block_commit {
+                            job_id: Some(jobid.clone()),
+                            device: active_node,
                             base_node: None,
 
                             base: None,
-                            top_node: None,
+                            top_node,
                             top: None,
                             backing_file: None,
                             speed: None,
@@ -184,23 +319,45 @@ impl Monitor {
                             auto_finalize: None,
                             auto_dismiss: None,
                             backing_mask_protocol: None,
-                        }).await
+                        }).await;
+                        if let Err(ref e) = res {
+                            // there won't be a job, so whoever waits for it gets its ack dropped
+                            error!("Failed to start committing the snapshot overlays of disk {disk_id}: {e:?}");
+                            pending_disk_commits.borrow_mut().remove(&jobid);
+                        }
+                        res
                     }
                     QmpCommand::JobReady(device) => {
-                        debug!("committing block job {device}");
-                        let (snap_file, ack) = pending_disk_commits.borrow_mut().remove(&device).unwrap();
+                        // only active commits (i.e. leaving backup mode) have to be completed manually
+                        match pending_disk_commits.borrow().get(&device) {
+                            Some(p) if p.active => (),
+                            _ => {
+                                warn!("Block job {device} is ready but we don't know about it");
+                                continue;
+                            }
+                        }
+                        debug!("completing block job {device}");
+                        // it is done once qemu says BLOCK_JOB_COMPLETED
                         let res = qapi.execute(&qmp::block_job_complete { device: device.clone() }).await;
-                        if res.is_ok() {
-                            // if the snapshot was created in the same session, qemu will (for some reason I don't fully understand)
-                            // automatically remove the blockdev node, causing this to fail
-                            // so we just ignore the result and that's it
-                            let _ = qapi.execute(&qmp::blockdev_del { node_name: format!("{device}_snap") }).await;
-
-                            tokio::fs::remove_file(snap_file).await.expect("failed to remove snapshot file after applying, please fix manually");
-                            let _ = ack.send(());
+                        if let Err(ref e) = res {
+                            error!("Failed to complete committing the snapshot overlays for {device}: {e:?}");
+                            pending_disk_commits.borrow_mut().remove(&device);
                         }
                         res
                     }
+                    QmpCommand::JobCompleted { device, error } => {
+                        let pending = pending_disk_commits.borrow_mut().remove(&device);
+                        match (pending, error) {
+                            (Some(pending), None) => {
+                                debug!("block job {device} completed");
+                                finish_commit(&qapi, pending).await;
+                            }
+                            // dropping the ack tells whoever waits for it
+                            (Some(_), Some(e)) => error!("Failed to commit snapshot overlay for {device}: {e}"),
+                            (None, _) => (),
+                        }
+                        continue;
+                    }
                 };
 
                 if let Err(e) = res {
@@ -213,7 +370,7 @@ impl Monitor {
             }
         };
         let handler = async move {
-            tokio::join!(event_handler, command_handler);
+            tokio::join!(event_handler, command_handler, passthrough_handler);
             Ok(())
         };
         Box::new(handler.boxed_local().compat())
@@ -0,0 +1,195 @@
+//! A second QMP connection that forwards raw commands and events, e.g. for debugging from the CLI.
+//!
+//! The main connection only speaks the commands we know, so this one does its own (line based) QMP:
+//! qapi names a command by `qapi::Command::NAME`, a constant, so commands that are only known at runtime
+//! can't go through its service, and its event stream is parsed into `qmp::Event`, losing what qapi doesn't know.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::UnixStream;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+/// Where qemu listens for the passthrough connection.
+pub fn socket(tmp: &Path) -> PathBuf {
+    tmp.join("qmp-passthrough.sock")
+}
+
+/// A raw QMP command along with where its result goes.
+pub struct Request {
+    pub execute: String,
+    pub arguments: Option<Value>,
+    pub ack: oneshot::Sender<Result<Value, String>>,
+}
+
+/// Forwards `requests` to qemu and everything qemu emits to `events` until either side goes away.
+pub async fn run(stream: UnixStream, mut requests: mpsc::UnboundedReceiver<Request>, events: broadcast::Sender<Value>) {
+    let (r, mut w) = stream.into_split();
+    let mut lines = BufReader::new(r).lines();
+    if let Err(e) = negotiate(&mut lines, &mut w).await {
+        warn!("QMP passthrough negotiation failed: {}", e);
+        while let Some(req) = requests.recv().await {
+            let _ = req.ack.send(Err(format!("the QMP passthrough is broken: {}", e)));
+        }
+        return;
+    }
+
+    let mut pending = HashMap::new();
+    let mut next_id = 0u64;
+    loop {
+        tokio::select! {
+            req = requests.recv() => {
+                let req = match req {
+                    Some(req) => req,
+                    None => return,
+                };
+                next_id += 1;
+                let mut msg = json!({ "execute": req.execute, "id": next_id });
+                if let Some(arguments) = req.arguments {
+                    msg["arguments"] = arguments;
+                }
+                match write(&mut w, &msg).await {
+                    Ok(()) => { pending.insert(next_id, req.ack); }
+                    Err(e) => {
+                        let _ = req.ack.send(Err(format!("failed to talk to qemu: {}", e)));
+                        return;
+                    }
+                }
+            }
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => dispatch(&line, &mut pending, &events),
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Error reading from the QMP passthrough: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn write(w: &mut OwnedWriteHalf, msg: &Value) -> io::Result<()> {
+    let mut line = serde_json::to_vec(msg)?;
+    line.push(b'\n');
+    w.write_all(&line).await
+}
+
+async fn read(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> io::Result<Value> {
+    match lines.next_line().await? {
+        Some(line) => Ok(serde_json::from_str(&line)?),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "qemu closed the connection")),
+    }
+}
+
+/// Reads the greeting and leaves capabilities negotiation mode.
+async fn negotiate(lines: &mut Lines<BufReader<OwnedReadHalf>>, w: &mut OwnedWriteHalf) -> io::Result<()> {
+    let greeting = read(lines).await?;
+    if greeting.get("QMP").is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected greeting {}", greeting)));
+    }
+    write(w, &json!({ "execute": "qmp_capabilities" })).await?;
+    loop {
+        let reply = read(lines).await?;
+        if reply.get("return").is_some() {
+            return Ok(());
+        }
+        if let Some(error) = reply.get("error") {
+            return Err(io::Error::new(io::ErrorKind::Other, error_message(error)));
+        }
+        // events can show up at any time
+    }
+}
+
+fn error_message(error: &Value) -> String {
+    match (error["class"].as_str(), error["desc"].as_str()) {
+        (Some(class), Some(desc)) => format!("{}: {}", class, desc),
+        _ => error.to_string(),
+    }
+}
+
+fn dispatch(line: &str, pending: &mut HashMap<u64, oneshot::Sender<Result<Value, String>>>,
+            events: &broadcast::Sender<Value>) {
+    let msg: Value = match serde_json::from_str(line) {
+        Ok(msg) => msg,
+        Err(e) => {
+            warn!("qemu sent invalid JSON on the QMP passthrough: {}", e);
+            return;
+        }
+    };
+    if msg.get("event").is_some() {
+        // nobody listening is fine
+        let _ = events.send(msg);
+        return;
+    }
+    let ack = match msg["id"].as_u64().and_then(|id| pending.remove(&id)) {
+        Some(ack) => ack,
+        None => {
+            warn!("Unexpected message on the QMP passthrough: {}", msg);
+            return;
+        }
+    };
+    let result = match (msg.get("return"), msg.get("error")) {
+        (Some(ret), _) => Ok(ret.clone()),
+        (None, Some(error)) => Err(error_message(error)),
+        (None, None) => Err(format!("malformed reply {}", msg)),
+    };
+    let _ = ack.send(result);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::dbus::testing::block_on;
+
+    /// Plays qemu on the other end of `stream`.
+    async fn fake_qemu(stream: UnixStream) {
+        let (r, mut w) = stream.into_split();
+        let mut lines = BufReader::new(r).lines();
+        write(&mut w, &json!({ "QMP": { "version": {}, "capabilities": [] } })).await.unwrap();
+        assert_eq!(read(&mut lines).await.unwrap(), json!({ "execute": "qmp_capabilities" }));
+        write(&mut w, &json!({ "return": {} })).await.unwrap();
+
+        write(&mut w, &json!({ "event": "RESUME", "timestamp": { "seconds": 1, "microseconds": 2 } })).await.unwrap();
+        loop {
+            let cmd = match lines.next_line().await.unwrap() {
+                Some(line) => serde_json::from_str::<Value>(&line).unwrap(),
+                None => return,
+            };
+            let reply = match cmd["execute"].as_str().unwrap() {
+                "query-status" => json!({ "return": { "status": "running" }, "id": cmd["id"] }),
+                _ => json!({ "error": { "class": "CommandNotFound", "desc": "nope" }, "id": cmd["id"] }),
+            };
+            write(&mut w, &reply).await.unwrap();
+        }
+    }
+
+    async fn execute(requests: &mpsc::UnboundedSender<Request>, execute: &str) -> Result<Value, String> {
+        let (ack, result) = oneshot::channel();
+        requests.send(Request { execute: execute.to_owned(), arguments: None, ack }).ok().unwrap();
+        result.await.unwrap()
+    }
+
+    #[test]
+    fn passthrough() {
+        block_on(async {
+            let (ours, theirs) = UnixStream::pair().unwrap();
+            let (requests, requests_rx) = mpsc::unbounded_channel();
+            let (events, mut events_rx) = broadcast::channel(16);
+            let test = async {
+                assert_eq!(events_rx.recv().await.unwrap()["event"], "RESUME");
+                assert_eq!(execute(&requests, "query-status").await, Ok(json!({ "status": "running" })));
+                assert_eq!(execute(&requests, "fly").await, Err("CommandNotFound: nope".to_owned()));
+            };
+            tokio::select! {
+                _ = run(ours, requests_rx, events) => panic!("passthrough went down"),
+                _ = fake_qemu(theirs) => panic!("qemu went down"),
+                _ = test => (),
+            }
+        });
+    }
+}
@@ -12,8 +12,12 @@ use libc;
 
 use common::config::{Config, SoundBackend, AlsaUnit, UsbBus};
 use crate::controller;
+use crate::monitor;
 use crate::sd_notify::notify_systemd;
 use crate::samba;
+use crate::backup;
+use crate::teardown::Teardown;
+use crate::error::DriverError;
 use common::util;
 use tokio::process::{Child, Command};
 
@@ -29,8 +33,13 @@ pub fn has_gtk_support() -> bool {
     supports_display("gtk")
 }
 
+/// Starts qemu, returning it along with the pid of swtpm if we started one.
+///
+/// Qemu listens on `clientpipe_path`, `monitor_path` and the QMP passthrough socket, so a driver can (re)connect
+/// whenever it likes.
 pub fn run(cfg: &Config, tmp: &Path, data: &Path, clientpipe_path: &Path, monitor_path: &Path,
-           enable_gui: bool) -> Child {
+           restore: bool, enable_gui: bool, teardown: &mut Teardown)
+           -> Result<(Child, Option<libc::pid_t>), DriverError> {
     trace!("qemu::run");
     let machine = &cfg.machine;
     let cpu = &machine.cpu.clone().unwrap_or("host".to_owned());
@@ -38,31 +47,34 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, clientpipe_path: &Path, monito
     let efivars_file = match cfg.tpm_state_folder.as_ref() {
         None => {
             let efivars_file = tmp.join("efivars.fd");
-            fs::copy("/usr/share/edk2-ovmf/x64/OVMF_VARS.4m.fd", &efivars_file).expect("Failed to copy efivars image");
+            fs::copy("/usr/share/edk2-ovmf/x64/OVMF_VARS.4m.fd", &efivars_file)
+                .map_err(DriverError::setup("copying the efivars image"))?;
             efivars_file
         }
         Some(tpm_folder) => {
             // also use it to store secure boot state
             let efivars_file = Path::new(tpm_folder).join("efivars.fd");
             if !efivars_file.exists() {
-                fs::copy("/usr/share/edk2-ovmf/x64/OVMF_VARS.4m.fd", &efivars_file).expect("Failed to copy efivars image");
+                fs::copy("/usr/share/edk2-ovmf/x64/OVMF_VARS.4m.fd", &efivars_file)
+                    .map_err(DriverError::setup("copying the efivars image"))?;
             }
             efivars_file
         }
     };
     trace!("efivars at {}", efivars_file.display());
 
-    let mut usernet = format!("user,id=unet,restrict=on,guestfwd=tcp:10.0.2.1:31337-unix:{}",
-                              clientpipe_path.display());
+    let mut usernet = "user,id=unet,restrict=on,guestfwd=tcp:10.0.2.1:31337-chardev:clientpipe".to_owned();
 
     if let Some(ref samba) = cfg.samba {
         trace!("setting up samba");
-        samba::setup(&tmp, samba, &mut usernet);
+        samba::setup(&tmp, samba, &mut usernet)?;
         debug!("Samba started");
     }
 
     let ga_iso = data.join("windows-gaming-ga.iso");
-    assert!(ga_iso.exists());
+    if !ga_iso.exists() {
+        return Err(DriverError::MissingGaIso(ga_iso));
+    }
 
     notify_systemd(false, "Starting qemu ...");
     trace!("starting qemu setup");
@@ -90,8 +102,15 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, clientpipe_path: &Path, monito
                 "-net",
                 "none",
                 "-display", "none", "-vga", "none",
-                "-qmp",
-                &format!("unix:{}", monitor_path.display()),
+                "-chardev",
+                &format!("socket,id=qmp,path={},server=on,wait=off", monitor_path.display()),
+                "-mon", "chardev=qmp,mode=control",
+                "-chardev",
+                &format!("socket,id=qmp-passthrough,path={},server=on,wait=off",
+                         monitor::passthrough::socket(tmp).display()),
+                "-mon", "chardev=qmp-passthrough,mode=control",
+                "-chardev",
+                &format!("socket,id=clientpipe,path={},server=on,wait=off", clientpipe_path.display()),
                 "-drive",
                 &format!("if=pflash,format=raw,unit=0,readonly=on,file={}",
                     "/usr/share/edk2-ovmf/x64/OVMF_CODE.secboot.4m.fd"),
@@ -105,13 +124,26 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, clientpipe_path: &Path, monito
 
                 "-drive", &format!("if=none,id=iso,media=cdrom,file={}", ga_iso.display()),
                 "-device", "scsi-cd,id=cdrom,drive=iso",
+
+                // lets us tell a bluescreen from a regular shutdown
+                "-device", "pvpanic",
     ]);
 
 
+    let mut swtpm_pid = None;
     if let Some(tpm_folder) = cfg.tpm_state_folder.as_ref() {
         let tpm_socket = tmp.join("tpm.socket");
         qemu.args(&["-chardev", &format!("socket,id=chrtpm,path={}", tpm_socket.display()), "-tpmdev", "emulator,id=tpm0,chardev=chrtpm", "-device", "tpm-tis,tpmdev=tpm0"]);
-        Command::new("swtpm").args(&["socket", "--tpmstate", &format!("dir={tpm_folder}"), "--ctrl", &format!("type=unixio,path={}", tpm_socket.display()), "--tpm2"]).spawn().unwrap();
+        let mut swtpm = std::process::Command::new("swtpm").args(&["socket", "--tpmstate", &format!("dir={tpm_folder}"), "--ctrl", &format!("type=unixio,path={}", tpm_socket.display()), "--tpm2"]).spawn()
+            .map_err(DriverError::setup("starting swtpm"))?;
+        swtpm_pid = Some(swtpm.id() as libc::pid_t);
+        teardown.register_after_qemu("stopping swtpm", move || {
+            if swtpm.try_wait()?.is_none() {
+                swtpm.kill()?;
+                swtpm.wait()?;
+            }
+            Ok(())
+        });
     }
     if enable_gui {
         qemu.args(&["-display", "gtk", "-vga", "qxl"]);
@@ -161,17 +193,19 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, clientpipe_path: &Path, monito
 
     for device in cfg.machine.pci_devices.iter() {
         if device.resettable {
-            let mut child = std::process::Command::new(data.join("vfio-ubind")).arg(&device.slot).spawn().expect("failed to run vfio-ubind");
-            match child.wait() {
-                Ok(status) if !status.success() =>
-                    panic!("vfio-ubind failed with {}! The device might not be bound to the \
-                            vfio-driver and therefore not function correctly", status),
-                Err(err) => panic!("failed to wait on child. Got: {}", err),
-                _ => (),
+            let vfio_error = |reason| DriverError::Vfio { slot: device.slot.clone(), reason };
+            let status = std::process::Command::new(data.join("vfio-ubind")).arg(&device.slot).status()
+                .map_err(|e| vfio_error(format!("failed to run vfio-ubind: {}", e)))?;
+            if !status.success() {
+                return Err(vfio_error(format!("vfio-ubind failed with {}. The device might not be bound to the \
+                                               vfio-driver and therefore not function correctly", status)));
             }
+
+            register_vfio_unbind(teardown, data, &device.slot);
         }
 
-        let (slot_no_fn, only_fn) = device.slot.split_once('.').expect("malformed pcie device address");
+        let (slot_no_fn, only_fn) = device.slot.split_once('.')
+            .ok_or_else(|| DriverError::InvalidConfig(format!("malformed pcie device address {}", device.slot)))?;
         let new_root_port_id = root_ports.len();
         let root_port_id = *root_ports.entry(slot_no_fn).or_insert_with(|| {
             qemu.arg("-device").arg(format!("pcie-root-port,id=root_port_{},bus=pcie.0", new_root_port_id));
@@ -218,7 +252,7 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, clientpipe_path: &Path, monito
             i += 1;
             if dev.permanent {
             if let Some((hostbus, hostaddr)) = controller::resolve_binding(&dev.binding)
-                    .expect("Failed to resolve usb binding")
+                    .map_err(|e| DriverError::Udev(format!("resolving {:?}: {}", dev.binding, e)))?
                 {
                     qemu.args(&["-device", &format!(
                         "usb-host,hostbus={},hostaddr={},bus={}{}.0,port={}", hostbus, hostaddr,
@@ -257,7 +291,7 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, clientpipe_path: &Path, monito
             match std::fs::File::open(path) {
                 Err(e) => warn!("Failed to check metadata for {path}: {e}"),
                 Ok(f) => {
-                    let metadata = f.metadata().unwrap();
+                    let metadata = f.metadata().map_err(DriverError::setup(format!("checking metadata of {path}")))?;
 
                     let devnum = if metadata.rdev() == 0 {
                         metadata.dev()
@@ -299,14 +333,15 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, clientpipe_path: &Path, monito
         // TODO: configure cache
         qemu.args(&["-blockdev", &format!("file.filename={},file.driver={file_driver},node-name=disk{},driver={},discard=unmap{aio_params}", path, idx, format)]);
 
-        let blockdev_name = match &drive.snapshot_file {
-            Some(snap) if Path::new(snap).exists() => {
-                debug!("Using disk snapshot: {snap}");
-                qemu.arg("-blockdev").arg(format!("file.filename={snap},file.driver=file,driver=qcow2,node-name=disk{idx}_snap,backing=disk{idx},discard=unmap,file.aio=io_uring,cache.direct=on"));
-                format!("disk{idx}_snap")
-            }
-            _ => format!("disk{idx}"),
-        };
+        // stack all existing snapshot overlays on top of the base image, oldest first
+        let mut blockdev_name = format!("disk{idx}");
+        for overlay in drive.snapshot_chain() {
+            let snap = &overlay.path;
+            let node_name = backup::overlay_node(idx, overlay.generation);
+            debug!("Using disk snapshot: {snap}");
+            qemu.arg("-blockdev").arg(format!("file.filename={snap},file.driver=file,driver=qcow2,node-name={node_name},backing={blockdev_name},discard=unmap,file.aio=io_uring,cache.direct=on"));
+            blockdev_name = node_name;
+        }
 
         qemu.args(&[//"-device", &format!("ahci,id=ahci{idx}"),
                     "-device", &format!("scsi-hd,{hd_params}drive={blockdev_name},id=myscsi{idx},rotation_rate=1,discard_granularity=0"),
@@ -388,6 +423,15 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, clientpipe_path: &Path, monito
     }
 */
 
+    if let Some(action) = cfg.watchdog.action {
+        qemu.args(&["-device", "i6300esb", "-action", &format!("watchdog={}", action)]);
+    }
+
+    if restore {
+        // the driver kicks off the actual migration once it is listening for the events
+        qemu.args(&["-incoming", "defer"]);
+    }
+
     if let Some(ref cmd) = cfg.additional_qemu_cmdline {
         qemu.args(cmd.split(' '));
     }
@@ -406,9 +450,41 @@ pub fn run(cfg: &Config, tmp: &Path, data: &Path, clientpipe_path: &Path, monito
             Ok(())
         });
     }
-    let qemu = qemu.spawn().expect("Failed to start qemu");
+    let qemu = qemu.spawn().map_err(DriverError::Qemu)?;
     trace!("qemu spawned");
-    return qemu;
+    Ok((qemu, swtpm_pid))
+}
+
+fn register_vfio_unbind(teardown: &mut Teardown, data: &Path, slot: &str) {
+    let vfio_ubind = data.join("vfio-ubind");
+    let slot = slot.to_owned();
+    teardown.register_after_qemu(format!("unbinding {} from vfio", slot), move || {
+        let status = std::process::Command::new(vfio_ubind).arg(&slot).arg("-r").status()?;
+        if !status.success() {
+            return Err(io::Error::new(io::ErrorKind::Other, format!(
+                "vfio-ubind failed with {}! The device might still be bound to the vfio-driver!", status)));
+        }
+        Ok(())
+    });
+}
+
+/// Registers the cleanups `run` would have registered for a qemu started by a previous driver.
+pub fn register_reattached(cfg: &Config, data: &Path, swtpm_pid: Option<libc::pid_t>, teardown: &mut Teardown) {
+    if let Some(pid) = swtpm_pid {
+        // not our child, so there's nothing to reap
+        teardown.register_after_qemu("stopping swtpm", move || {
+            if unsafe { libc::kill(pid, libc::SIGTERM) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() != Some(libc::ESRCH) {
+                    return Err(e);
+                }
+            }
+            Ok(())
+        });
+    }
+    for device in cfg.machine.pci_devices.iter().filter(|d| d.resettable) {
+        register_vfio_unbind(teardown, data, &device.slot);
+    }
 }
 
 fn option2env(cmd: &mut Command, name: &str, val: &Option<String>) {
@@ -3,12 +3,17 @@ use std::fmt::Write as FmtWrite;
 
 use common::config::SambaConfig;
 
+use crate::error::DriverError;
+
 pub fn is_installed() -> bool {
     Path::new("/usr/sbin/smbd").is_file()
 }
 
-pub fn setup(_tmp: &Path, samba: &SambaConfig, usernet: &mut String) {
-    assert!(is_installed(), "Optional samba dependency not installed!");
+pub fn setup(_tmp: &Path, samba: &SambaConfig, usernet: &mut String) -> Result<(), DriverError> {
+    if !is_installed() {
+        return Err(DriverError::SambaMissing);
+    }
 
     write!(usernet, ",smb={}", samba.path).unwrap();
+    Ok(())
 }
@@ -1,15 +1,149 @@
 extern crate sd_notify as api;
 use self::api::NotifyState;
 
+use std::env;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixListener;
+use std::process;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+use windows_gaming_client::protocol::Status;
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = api::notify(false, state) {
+        debug!("sd_notify error: {:?}", e);
+    }
+}
+
 /// Attempts to notify systemd about our status.
 /// Doesn't do anything unless we're running as a systemd service.
-pub fn notify_systemd(ready: bool, status: &'static str) {
+pub fn notify_systemd(ready: bool, status: &str) {
     trace!("Notifying systemd (ready={} status='{}')", ready, status);
-    let mut state = vec![NotifyState::Status(status.to_owned())];
+    let mut state = vec![NotifyState::Status(status)];
     if ready {
         state.push(NotifyState::Ready);
     }
-    if let Err(e) = api::notify(false, &state) {
-        debug!("sd_notify error: {:?}", e);
+    notify(&state);
+}
+
+/// Tells systemd that we are shutting down.
+pub fn notify_stopping(status: &str) {
+    trace!("Notifying systemd (stopping, status='{}')", status);
+    notify(&[NotifyState::Stopping, NotifyState::Status(status)]);
+}
+
+/// Asks systemd to wait another `duration` for us to stop before it gets impatient.
+pub fn extend_timeout(duration: Duration) {
+    trace!("Extending systemd timeout by {:?}", duration);
+    notify(&[NotifyState::ExtendTimeoutUsec(duration.as_micros().min(u32::MAX as u128) as u32)]);
+}
+
+/// Keeps the systemd status text in sync with the driver status.
+pub async fn status_reporter(mut status: watch::Receiver<Status>) {
+    loop {
+        let current = status.borrow().clone();
+        // the shutdown escalation reports its progress on its own
+        if !current.shutting_down {
+            notify_systemd(false, &current.to_string());
+        }
+        if status.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// The control socket systemd passed us, if we were socket activated.
+pub fn activated_socket() -> Option<UnixListener> {
+    env::var_os("LISTEN_FDS")?;
+    let mut fds = match api::listen_fds() {
+        Ok(fds) => fds,
+        Err(e) => {
+            warn!("Ignoring sockets passed by systemd: {:?}", e);
+            return None;
+        }
+    };
+    let fd = fds.next()?;
+    if fds.next().is_some() {
+        warn!("systemd passed us more than one socket, only using the first one as control socket");
+    }
+    debug!("Using control socket passed by systemd");
+    Some(unsafe { UnixListener::from_raw_fd(fd) })
+}
+
+/// How often systemd wants to hear from us, if it has a watchdog configured for this service.
+fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    match env::var("WATCHDOG_PID") {
+        Ok(ref pid) if pid.parse::<u32>().ok() != Some(process::id()) => None,
+        _ => Some(Duration::from_micros(usec)),
+    }
+}
+
+fn pet_watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
+
+/// Keeps the systemd watchdog happy for as long as the event loop is responsive.
+pub async fn watchdog() {
+    let interval = match watchdog_interval() {
+        Some(x) => x,
+        None => return,
+    };
+    // sd_watchdog_enabled(3) recommends pinging at half the interval
+    let mut timer = tokio::time::interval(interval / 2);
+    loop {
+        timer.tick().await;
+        pet_watchdog();
+    }
+}
+
+/// Keeps the systemd watchdog happy from another thread until dropped.
+///
+/// For setting up and tearing down, which block or wait for long on purpose and happen while `watchdog` doesn't run.
+pub struct KeepAlive(Option<(mpsc::Sender<()>, JoinHandle<()>)>);
+
+pub fn keep_alive() -> KeepAlive {
+    let interval = match watchdog_interval() {
+        Some(x) => x / 2,
+        None => return KeepAlive(None),
+    };
+    let (stop, stopped) = mpsc::channel();
+    let thread = thread::spawn(move || loop {
+        pet_watchdog();
+        match stopped.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => (),
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+        }
+    });
+    KeepAlive(Some((stop, thread)))
+}
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        if let Some((stop, thread)) = self.0.take() {
+            drop(stop);
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Blocks the current thread for `duration` while keeping the systemd watchdog happy.
+pub fn sleep(duration: Duration) {
+    let interval = match watchdog_interval() {
+        Some(x) => x / 2,
+        None => return thread::sleep(duration),
+    };
+    let deadline = Instant::now() + duration;
+    loop {
+        pet_watchdog();
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        thread::sleep(interval.min(deadline - now));
     }
 }
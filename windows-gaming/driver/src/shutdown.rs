@@ -0,0 +1,123 @@
+use std::rc::Rc;
+use std::time::Duration;
+
+use common::config::ShutdownConfig;
+use windows_gaming_client::protocol;
+use futures::unsync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+
+use crate::monitor::QmpCommand;
+use crate::sd_notify;
+
+/// How long we ask systemd to wait for qemu to go down on top of our own timeouts.
+const STOP_GRACE: Duration = Duration::from_secs(30);
+
+/// The stages a shutdown escalates through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownStage {
+    /// Asked the guest agent to shut down
+    GuestAgent,
+    /// Sent an ACPI powerdown
+    Acpi,
+    /// Told qemu to quit
+    Quit,
+}
+
+impl ShutdownStage {
+    fn next(self) -> Option<ShutdownStage> {
+        match self {
+            ShutdownStage::GuestAgent => Some(ShutdownStage::Acpi),
+            ShutdownStage::Acpi => Some(ShutdownStage::Quit),
+            ShutdownStage::Quit => None,
+        }
+    }
+
+    fn timeout(self, config: &ShutdownConfig) -> Option<u64> {
+        match self {
+            ShutdownStage::GuestAgent => config.guest_agent_timeout,
+            ShutdownStage::Acpi => config.acpi_timeout,
+            ShutdownStage::Quit => None,
+        }
+    }
+
+    fn status(self) -> &'static str {
+        match self {
+            ShutdownStage::GuestAgent => "Shutting down via guest agent ...",
+            ShutdownStage::Acpi => "Shutting down via ACPI ...",
+            ShutdownStage::Quit => "Forcing qemu to quit ...",
+        }
+    }
+}
+
+impl From<ShutdownStage> for protocol::ShutdownStage {
+    fn from(stage: ShutdownStage) -> protocol::ShutdownStage {
+        match stage {
+            ShutdownStage::GuestAgent => protocol::ShutdownStage::GuestAgent,
+            ShutdownStage::Acpi => protocol::ShutdownStage::Acpi,
+            ShutdownStage::Quit => protocol::ShutdownStage::Quit,
+        }
+    }
+}
+
+/// Reports that we reached `stage`.
+pub fn report(progress: &watch::Sender<Option<ShutdownStage>>, stage: ShutdownStage) {
+    info!("Shutdown: {}", stage.status());
+    sd_notify::notify_stopping(stage.status());
+    let _ = progress.send(Some(stage));
+}
+
+/// Escalates a shutdown that was started at stage `stage` whenever the configured timeouts expire.
+///
+/// Keeps systemd from killing us while we wait. A disabled timeout waits (and keeps extending) forever.
+/// Qemu going down ends the driver (and therefore this task) anyway.
+pub async fn escalate(config: ShutdownConfig, mut stage: ShutdownStage, monitor: UnboundedSender<QmpCommand>,
+                      progress: Rc<watch::Sender<Option<ShutdownStage>>>) {
+    while let Some(next) = stage.next() {
+        let timeout = match stage.timeout(&config) {
+            Some(timeout) => timeout,
+            None => loop {
+                sd_notify::extend_timeout(STOP_GRACE * 2);
+                tokio::time::sleep(STOP_GRACE).await;
+            },
+        };
+        sd_notify::extend_timeout(Duration::from_secs(timeout) + STOP_GRACE);
+        tokio::time::sleep(Duration::from_secs(timeout)).await;
+
+        warn!("Windows did not shut down within {}s, escalating", timeout);
+        stage = next;
+        report(&progress, stage);
+        let cmd = match stage {
+            ShutdownStage::GuestAgent => unreachable!(),
+            ShutdownStage::Acpi => QmpCommand::SystemPowerdown,
+            ShutdownStage::Quit => QmpCommand::Quit,
+        };
+        if monitor.unbounded_send(cmd).is_err() {
+            return;
+        }
+    }
+    sd_notify::extend_timeout(STOP_GRACE);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::Stream as _;
+    use futures::unsync::mpsc;
+
+    use crate::dbus::testing::block_on;
+
+    #[test]
+    fn escalates_to_quit() {
+        let config = ShutdownConfig { guest_agent_timeout: Some(0), acpi_timeout: Some(0), ..ShutdownConfig::default() };
+        let (monitor, monitor_rx) = mpsc::unbounded();
+        let (progress, progress_rx) = watch::channel(Some(ShutdownStage::GuestAgent));
+        block_on(escalate(config, ShutdownStage::GuestAgent, monitor, Rc::new(progress)));
+
+        let mut sent = monitor_rx.wait();
+        assert!(matches!(sent.next(), Some(Ok(QmpCommand::SystemPowerdown))));
+        assert!(matches!(sent.next(), Some(Ok(QmpCommand::Quit))));
+        assert!(sent.next().is_none());
+        assert_eq!(*progress_rx.borrow(), Some(ShutdownStage::Quit));
+    }
+}
@@ -1,63 +1,421 @@
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::io::Error;
+use std::time::Duration;
 
-use crate::libdbus::{Connection, ConnectionItem, BusType, Message, OwnedFd};
-use futures::{Stream, Future, IntoFuture};
+use crate::libdbus::{Connection, BusType, Message, OwnedFd};
+use common::config::IdleInhibitConfig;
+use futures::IntoFuture;
+use futures03::compat::Future01CompatExt;
+use tokio::sync::watch;
 
-use crate::dbus::DBusItems;
-use futures03::TryStreamExt;
+use crate::controller::IoMode;
+use crate::dbus::Bus;
 
-pub fn system_dbus() -> Connection {
-    Connection::get_private(BusType::System).unwrap()
+/// The system bus, if we can reach it.
+pub fn system_dbus() -> Option<Connection> {
+    match Connection::get_private(BusType::System) {
+        Ok(conn) => Some(conn),
+        Err(e) => {
+            warn!("Can't connect to the system bus, running without sleep and idle inhibitors: {:?}", e);
+            None
+        }
+    }
 }
 
-fn inhibit(dbus: &Connection) -> OwnedFd {
+/// The session bus, if we have one (we don't when running as a system service).
+pub fn session_dbus() -> Option<Connection> {
+    match Connection::get_private(BusType::Session) {
+        Ok(c) => Some(c),
+        Err(e) => {
+            debug!("No session bus: {:?}", e);
+            None
+        }
+    }
+}
+
+async fn inhibit(bus: &Bus<'_>, what: &str, why: &str, mode: &str) -> Option<OwnedFd> {
     let mut ih = Message::new_method_call("org.freedesktop.login1",
                                           "/org/freedesktop/login1",
                                           "org.freedesktop.login1.Manager",
                                           "Inhibit").unwrap();
-    ih.append_items(&["sleep".into(),
+    ih.append_items(&[what.into(),
                       "windows-gaming-driver".into(),
-                      "Suspend guest with the host".into(),
-                      "delay".into()]);
-    // TODO: make this async.
-    // for now, it just blocks the entire eventloop *shrug*
-    // whatever, this is rare
-    let resp = dbus.send_with_reply_and_block(ih, 2000).unwrap();
-    resp.get1().unwrap()
-}
-
-pub fn sleep_inhibitor<'a, R, F>(bus: &'a Connection, mut callback: F)
-                                 -> Box<dyn Future<Item = (), Error = Error> + 'a>
-    where F : FnMut() -> R + 'a, R : IntoFuture<Item = (), Error = ()> + 'a
+                      why.into(),
+                      mode.into()]);
+    match bus.call(ih).await {
+        Ok(resp) => resp.get1(),
+        Err(e) => {
+            warn!("Failed to take logind {} inhibitor: {:?}", what, e);
+            None
+        }
+    }
+}
+
+async fn inhibit_sleep(bus: &Bus<'_>) -> Option<OwnedFd> {
+    inhibit(bus, "sleep", "Suspend guest with the host", "delay").await
+}
+
+async fn inhibit_shutdown(bus: &Bus<'_>) -> Option<OwnedFd> {
+    inhibit(bus, "shutdown", "Shut down guest with the host", "delay").await
+}
+
+/// Holds logind delay locks for sleep and shutdown.
+///
+/// When the host prepares for sleep, `suspend` is called and the lock is released once its future resolves,
+/// meanwhile we keep following logind.
+/// When the host prepares for shutdown, `shutdown` is called and the lock is held until this future is dropped
+/// (i.e. qemu is down) or `shutdown_max_delay` passed.
+pub async fn logind_inhibitor<R, F, G>(bus: &Bus<'_>, shutdown_max_delay: Option<Duration>,
+                                       mut suspend: F, mut shutdown: G)
+    where F : FnMut() -> R, R : IntoFuture<Item = (), Error = ()>, R::Future : 'static, G : FnMut()
 {
-    let items = DBusItems::new(&bus).compat();
+    let mut signals = bus.subscribe();
 
-    bus.add_match("interface='org.freedesktop.login1.Manager',member='PrepareForSleep'").unwrap();
+    let conn = bus.connection();
+    conn.add_match("interface='org.freedesktop.login1.Manager',member='PrepareForSleep'").unwrap();
+    conn.add_match("interface='org.freedesktop.login1.Manager',member='PrepareForShutdown'").unwrap();
 
-    let fd = Rc::new(RefCell::new(Some(inhibit(&bus))));
+    let mut sleep_fd = inhibit_sleep(bus).await;
+    let shutdown_fd = Rc::new(RefCell::new(inhibit_shutdown(bus).await));
 
-    Box::new(items.for_each(move |ci| {
-        match ci {
-            ConnectionItem::Signal(ref s) if &*s.interface().unwrap() == "org.freedesktop.login1.Manager"
-                    && &*s.member().unwrap() == "PrepareForSleep" => {
+    while let Some(s) = signals.recv().await {
+        if s.interface().as_deref() != Some("org.freedesktop.login1.Manager") {
+            continue;
+        }
+        match s.member().as_deref() {
+            Some("PrepareForSleep") => {
                 let starting: bool = s.get1().unwrap();
                 debug!("dbus reports PrepareForSleep");
                 if starting {
                     // run callback, then drop
-                    let myfd = fd.clone();
-                    let b: Box<dyn Future<Item=(), Error=()>> = Box::new(callback().into_future().map(move |()| {
-                        *myfd.borrow_mut() = None;
-                    }));
-                    return b;
+                    let suspended = suspend().into_future().compat();
+                    let fd = sleep_fd.take();
+                    tokio::task::spawn_local(async move {
+                        let _ = suspended.await;
+                        drop(fd);
+                    });
                 } else {
                     // re-acquire
-                    *fd.borrow_mut() = Some(inhibit(&bus));
+                    sleep_fd = inhibit_sleep(bus).await;
+                }
+            }
+            Some("PrepareForShutdown") => {
+                let starting: bool = s.get1().unwrap();
+                debug!("dbus reports PrepareForShutdown");
+                if starting {
+                    shutdown();
+
+                    // the lock goes away together with us once qemu is down
+                    // but don't hold up the host forever if windows doesn't cooperate
+                    if let Some(max_delay) = shutdown_max_delay {
+                        let myfd = Rc::downgrade(&shutdown_fd);
+                        tokio::task::spawn_local(async move {
+                            tokio::time::sleep(max_delay).await;
+                            if let Some(fd) = myfd.upgrade() {
+                                if fd.borrow_mut().take().is_some() {
+                                    warn!("Windows did not shut down within {:?}, letting the host go ahead anyway", max_delay);
+                                }
+                            }
+                        });
+                    }
+                } else if shutdown_fd.borrow().is_none() {
+                    // shutdown was cancelled, re-acquire
+                    let fd = inhibit_shutdown(bus).await;
+                    *shutdown_fd.borrow_mut() = fd;
                 }
             }
             _ => (),
         }
-        Box::new(Ok(()).into_future())
-    }).then(|_| Ok(())))
+    }
+    drop(sleep_fd);
+}
+
+/// An idle inhibitor held with logind and (if available) the desktop's screensaver.
+struct IdleLock {
+    logind: Option<OwnedFd>,
+    screensaver: Option<u32>,
+}
+
+impl IdleLock {
+    async fn acquire(system: &Bus<'_>, session: Option<&Bus<'_>>) -> IdleLock {
+        debug!("inhibiting idle");
+        let logind = inhibit(system, "idle", "Windows has the input devices", "block").await;
+
+        let mut screensaver = None;
+        if let Some(session) = session {
+            let mut ih = Message::new_method_call("org.freedesktop.ScreenSaver",
+                                                  "/org/freedesktop/ScreenSaver",
+                                                  "org.freedesktop.ScreenSaver",
+                                                  "Inhibit").unwrap();
+            ih.append_items(&["windows-gaming-driver".into(), "Windows has the input devices".into()]);
+            match session.call(ih).await {
+                Ok(resp) => screensaver = resp.get1(),
+                Err(e) => warn!("Failed to inhibit the screensaver: {:?}", e),
+            }
+        }
+
+        IdleLock { logind, screensaver }
+    }
+
+    async fn release(self, session: Option<&Bus<'_>>) {
+        debug!("no longer inhibiting idle");
+        drop(self.logind);
+
+        if let (Some(cookie), Some(session)) = (self.screensaver, session) {
+            let mut uh = Message::new_method_call("org.freedesktop.ScreenSaver",
+                                                  "/org/freedesktop/ScreenSaver",
+                                                  "org.freedesktop.ScreenSaver",
+                                                  "UnInhibit").unwrap();
+            uh.append_items(&[cookie.into()]);
+            if let Err(e) = session.call(uh).await {
+                warn!("Failed to uninhibit the screensaver: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Keeps the host from going idle while Windows has our input devices.
+pub async fn idle_inhibitor(system: &Bus<'_>, session: Option<&Bus<'_>>, config: IdleInhibitConfig,
+                            mut io_mode: watch::Receiver<IoMode>) {
+    let mut lock = None;
+    loop {
+        let mode = *io_mode.borrow();
+        let wanted = match mode {
+            IoMode::Detached => false,
+            IoMode::LightEntry => config.light_entry,
+            IoMode::FullEntry => config.full_entry,
+        };
+        match lock.take() {
+            None if wanted => lock = Some(IdleLock::acquire(system, session).await),
+            Some(l) if !wanted => l.release(session).await,
+            l => lock = l,
+        }
+
+        if io_mode.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::os::unix::io::RawFd;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use futures::Future as _;
+
+    use crate::libdbus::{ConnectionItem, NameFlag};
+    use crate::dbus::testing::{block_on, PrivateBus};
+
+    enum FakeLogindCmd {
+        PrepareForSleep(bool),
+        PrepareForShutdown(bool),
+    }
+
+    /// Pretends to be logind: hands out pipes as inhibitor locks and sends the read ends back to the test.
+    fn fake_logind(locks: mpsc::Sender<(String, RawFd)>) -> mpsc::Sender<FakeLogindCmd> {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let conn = PrivateBus::connect();
+            conn.register_name("org.freedesktop.login1", NameFlag::ReplaceExisting as u32).unwrap();
+            ready_tx.send(()).unwrap();
+            fake_logind_loop(conn, locks, cmd_rx);
+        });
+        ready_rx.recv().unwrap();
+        cmd_tx
+    }
+
+    fn fake_logind_loop(conn: Connection, locks: mpsc::Sender<(String, RawFd)>, cmd_rx: mpsc::Receiver<FakeLogindCmd>) {
+        loop {
+            for item in conn.iter(50) {
+                match item {
+                    ConnectionItem::MethodCall(ref m) if &*m.member().unwrap() == "Inhibit" => {
+                        let what: &str = m.get1().unwrap();
+                        let mut fds = [0; 2];
+                        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+                        let reply = Message::new_method_return(m).unwrap().append1(OwnedFd::new(fds[1]));
+                        conn.send(reply).unwrap();
+                        locks.send((what.to_owned(), fds[0])).unwrap();
+                    }
+                    ConnectionItem::Nothing => break,
+                    _ => (),
+                }
+            }
+            let (member, starting) = match cmd_rx.try_recv() {
+                Ok(FakeLogindCmd::PrepareForSleep(starting)) => ("PrepareForSleep", starting),
+                Ok(FakeLogindCmd::PrepareForShutdown(starting)) => ("PrepareForShutdown", starting),
+                Err(mpsc::TryRecvError::Empty) => continue,
+                Err(mpsc::TryRecvError::Disconnected) => return,
+            };
+            let signal = Message::new_signal("/org/freedesktop/login1", "org.freedesktop.login1.Manager",
+                                             member).unwrap().append1(starting);
+            conn.send(signal).unwrap();
+        }
+    }
+
+    /// Whether the other end of an inhibitor pipe was closed, i.e. the lock is released.
+    fn released(fd: RawFd) -> bool {
+        let mut pfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        unsafe { libc::poll(&mut pfd, 1, 0) };
+        pfd.revents & libc::POLLHUP != 0
+    }
+
+    #[test]
+    fn shutdown_delay() {
+        let _bus = PrivateBus::start();
+        let (locks_tx, locks_rx) = mpsc::channel();
+        let logind = fake_logind(locks_tx);
+
+        block_on(async move {
+            let conn = PrivateBus::connect();
+            let bus = Bus::new(&conn);
+            let shutdowns = Rc::new(Cell::new(0));
+            let shutdowns2 = shutdowns.clone();
+            let inhibitor = logind_inhibitor(&bus, Some(Duration::from_millis(500)),
+                                             || Ok(()), move || shutdowns2.set(shutdowns2.get() + 1));
+
+            let test = async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let mut locks: Vec<_> = locks_rx.try_iter().collect();
+                locks.sort();
+                assert_eq!(locks.iter().map(|(what, _)| what.as_str()).collect::<Vec<_>>(), vec!["shutdown", "sleep"]);
+                let shutdown_lock = locks[0].1;
+                assert!(!released(shutdown_lock));
+
+                logind.send(FakeLogindCmd::PrepareForShutdown(true)).unwrap();
+
+                // the driver starts shutting down windows but keeps holding the lock
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                assert_eq!(shutdowns.get(), 1);
+                assert!(!released(shutdown_lock));
+
+                // until windows took too long
+                tokio::time::sleep(Duration::from_millis(600)).await;
+                assert!(released(shutdown_lock));
+            };
+
+            tokio::select! {
+                _ = bus.run() => panic!("bus went down"),
+                _ = inhibitor => panic!("inhibitor went down"),
+                _ = test => (),
+            }
+        });
+    }
+
+    #[test]
+    fn shutdown_lock_released_with_driver() {
+        let _bus = PrivateBus::start();
+        let (locks_tx, locks_rx) = mpsc::channel();
+        let logind = fake_logind(locks_tx);
+
+        block_on(async move {
+            let conn = PrivateBus::connect();
+            let bus = Bus::new(&conn);
+            let inhibitor = logind_inhibitor(&bus, None, || Ok(()), || ());
+
+            let mut shutdown_lock = None;
+            let test = async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                shutdown_lock = locks_rx.try_iter().find(|(what, _)| what == "shutdown").map(|(_, fd)| fd);
+
+                logind.send(FakeLogindCmd::PrepareForShutdown(true)).unwrap();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                assert!(!released(shutdown_lock.unwrap()));
+            };
+
+            // once the test is done, the inhibitor gets dropped just like when qemu went down
+            tokio::select! {
+                _ = bus.run() => panic!("bus went down"),
+                _ = inhibitor => panic!("inhibitor went down"),
+                _ = test => (),
+            }
+            assert!(released(shutdown_lock.unwrap()));
+        });
+    }
+
+    #[test]
+    fn sleep_lock_released_after_suspend() {
+        let _bus = PrivateBus::start();
+        let (locks_tx, locks_rx) = mpsc::channel();
+        let logind = fake_logind(locks_tx);
+
+        block_on(async move {
+            let conn = PrivateBus::connect();
+            let bus = Bus::new(&conn);
+            let (suspended_tx, suspended_rx) = futures::unsync::oneshot::channel();
+            let suspended_rx = Cell::new(Some(suspended_rx));
+            let shutdowns = Rc::new(Cell::new(0));
+            let shutdowns2 = shutdowns.clone();
+            let inhibitor = logind_inhibitor(&bus, None, move || suspended_rx.take().unwrap().map_err(|_| ()),
+                                             move || shutdowns2.set(shutdowns2.get() + 1));
+
+            let test = async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let sleep_lock = locks_rx.try_iter().find(|(what, _)| what == "sleep").unwrap().1;
+
+                logind.send(FakeLogindCmd::PrepareForSleep(true)).unwrap();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                assert!(!released(sleep_lock));
+
+                // windows is still suspending, but we keep listening to logind
+                logind.send(FakeLogindCmd::PrepareForShutdown(true)).unwrap();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                assert_eq!(shutdowns.get(), 1);
+                assert!(!released(sleep_lock));
+
+                suspended_tx.send(()).unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert!(released(sleep_lock));
+            };
+
+            tokio::select! {
+                _ = bus.run() => panic!("bus went down"),
+                _ = inhibitor => panic!("inhibitor went down"),
+                _ = test => (),
+            }
+        });
+    }
+
+    #[test]
+    fn idle_follows_io_mode() {
+        let _bus = PrivateBus::start();
+        let (locks_tx, locks_rx) = mpsc::channel();
+        let _logind = fake_logind(locks_tx);
+
+        block_on(async move {
+            let conn = PrivateBus::connect();
+            let bus = Bus::new(&conn);
+            let (mode_tx, mode_rx) = watch::channel(IoMode::Detached);
+            let config = IdleInhibitConfig { light_entry: true, full_entry: false };
+            let inhibitor = idle_inhibitor(&bus, None, config, mode_rx);
+
+            let test = async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert!(locks_rx.try_recv().is_err());
+
+                mode_tx.send(IoMode::LightEntry).unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let (what, lock) = locks_rx.try_recv().unwrap();
+                assert_eq!(what, "idle");
+                assert!(!released(lock));
+
+                // full entry is configured to not inhibit
+                mode_tx.send(IoMode::FullEntry).unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert!(released(lock));
+                assert!(locks_rx.try_recv().is_err());
+            };
+
+            tokio::select! {
+                _ = bus.run() => panic!("bus went down"),
+                _ = inhibitor => panic!("inhibitor went down"),
+                _ = test => (),
+            }
+        });
+    }
 }
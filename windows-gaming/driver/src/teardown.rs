@@ -0,0 +1,145 @@
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::sd_notify;
+
+type Cleanup = Box<dyn FnOnce() -> io::Result<()>>;
+
+/// Undoes the host-side setup of a run, most recent step first.
+///
+/// Every setup step that leaves something behind on the host registers its cleanup right after it succeeded.
+/// The cleanups run when the registry is dropped, i.e. on a normal exit, on an error and while unwinding from a
+/// panic alike.
+///
+/// Cleanups a running qemu still depends on are skipped if we go down before it, so the next driver can reattach.
+pub struct Teardown {
+    steps: Vec<(String, bool, Cleanup)>,
+    qemu_running: Option<Box<dyn Fn() -> bool>>,
+}
+
+impl Teardown {
+    pub fn new() -> Teardown {
+        Teardown { steps: Vec::new(), qemu_running: None }
+    }
+
+    /// Registers `cleanup` to run on teardown. `what` describes it for the log.
+    pub fn register<S, F>(&mut self, what: S, cleanup: F)
+        where S: Into<String>, F: FnOnce() -> io::Result<()> + 'static {
+        self.steps.push((what.into(), false, Box::new(cleanup)));
+    }
+
+    /// Like `register`, but for things qemu needs. They stay as they are while qemu is still running.
+    pub fn register_after_qemu<S, F>(&mut self, what: S, cleanup: F)
+        where S: Into<String>, F: FnOnce() -> io::Result<()> + 'static {
+        self.steps.push((what.into(), true, Box::new(cleanup)));
+    }
+
+    /// Sets how to tell whether qemu is still running and can be reattached to.
+    ///
+    /// Until then, qemu counts as gone.
+    pub fn watch_qemu<F: Fn() -> bool + 'static>(&mut self, running: F) {
+        self.qemu_running = Some(Box::new(running));
+    }
+
+    /// Runs all registered cleanups now.
+    pub fn run(&mut self) {
+        if self.steps.is_empty() {
+            return;
+        }
+        // unbinding from vfio alone can take a while
+        let _watchdog = sd_notify::keep_alive();
+        while let Some((what, after_qemu, cleanup)) = self.steps.pop() {
+            if after_qemu && self.qemu_running.as_ref().map_or(false, |running| running()) {
+                info!("Teardown: qemu is still running, skipping {} so we can reattach", what);
+                continue;
+            }
+            debug!("Teardown: {}", what);
+            // a panic in here would abort if we are already unwinding, so contain it
+            match panic::catch_unwind(AssertUnwindSafe(cleanup)) {
+                Ok(Ok(())) => info!("Teardown: {} done", what),
+                Ok(Err(e)) => error!("Teardown: {} failed: {}", what, e),
+                Err(_) => error!("Teardown: {} panicked", what),
+            }
+        }
+    }
+}
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        if !self.steps.is_empty() {
+            info!("Tearing down");
+        }
+        self.run();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn most_recent_first() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        {
+            let mut teardown = Teardown::new();
+            for i in 0..3 {
+                let log = log.clone();
+                teardown.register(format!("step {}", i), move || {
+                    log.borrow_mut().push(i);
+                    Ok(())
+                });
+            }
+        }
+        assert_eq!(*log.borrow(), [2, 1, 0]);
+    }
+
+    #[test]
+    fn failures_and_panics_dont_stop_the_rest() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut teardown = Teardown::new();
+        let first = log.clone();
+        teardown.register("first", move || {
+            first.borrow_mut().push("first");
+            Ok(())
+        });
+        teardown.register("panicking", || panic!("oops"));
+        teardown.register("failing", || Err(io::Error::new(io::ErrorKind::Other, "nope")));
+        teardown.run();
+        assert_eq!(*log.borrow(), ["first"]);
+        // everything ran, nothing is left for the drop
+        assert!(teardown.steps.is_empty());
+    }
+
+    #[test]
+    fn keeps_what_qemu_needs_while_it_runs() {
+        let dir = std::env::temp_dir().join(format!("windows-gaming-teardown-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let instance = dir.join("instance.json");
+        std::fs::write(&instance, "{}").unwrap();
+        let stopped = Rc::new(RefCell::new(false));
+
+        let teardown = |running: bool| {
+            let mut teardown = Teardown::new();
+            let dir = dir.clone();
+            teardown.register_after_qemu("cleaning up the runtime directory", move || std::fs::remove_dir_all(&dir));
+            let stopped = stopped.clone();
+            teardown.register("stopping the rest", move || {
+                *stopped.borrow_mut() = true;
+                Ok(())
+            });
+            teardown.watch_qemu(move || running);
+            teardown
+        };
+
+        // going down while qemu still runs
+        drop(teardown(true));
+        assert!(instance.exists());
+        assert!(*stopped.borrow());
+
+        drop(teardown(false));
+        assert!(!dir.exists());
+    }
+}
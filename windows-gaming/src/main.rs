@@ -6,18 +6,19 @@ extern crate time;
 #[macro_use] extern crate clap;
 extern crate common;
 extern crate driver;
+extern crate windows_gaming_client;
+#[macro_use] extern crate serde_json;
 
-use std::fs;
 use std::path::Path;
-use std::os::unix::net::UnixStream;
-use std::io::{self, Write, Read, ErrorKind};
-use std::process::Command;
+use std::io;
+use std::process;
 
 use clap::{Arg, App, SubCommand, AppSettings, ArgGroup, Shell};
 use nix::unistd;
 
 use common::config::Config;
-use driver::ControlCmdIn;
+use windows_gaming_client::blocking::Client;
+use windows_gaming_client::protocol::{AttachMode, Command, Error as ControlError, Event, Reply, ShutdownStage};
 
 enum RunMode {
     System,
@@ -41,22 +42,65 @@ fn main() {
             .help("Config to use")
             .takes_value(true)
             .global(true)
+        ).arg(Arg::with_name("instance")
+            .long("instance")
+            .value_name("NAME")
+            .help("Instance to use. Every instance has its own config, runtime and state directory, so \
+                   several VMs can run side by side.")
+            .takes_value(true)
+            .global(true)
+            .validator(|name| driver::instance::validate_name(&name))
         ).arg(Arg::with_name("generate-bash-completions")
             .long("generate-bash-completions")
             .hidden(true)
             .takes_value(false)
         ).subcommand(SubCommand::with_name("run")
             .about("Starts Windows")
+            .long_about("Starts Windows. Exits with 0 once Windows shut down, 1 if the driver failed while \
+            Windows was running and 2 if Windows or qemu crashed. Failing to start exits with 3 if another \
+            instance is running, 4 if preparing the host failed, 5 for an invalid configuration, 6 if binding \
+            devices to vfio failed and 7 if qemu failed to start or talk to us. Another instance using the \
+            same PCI or USB devices also exits with 3.")
             .visible_alias("start")
             .arg(Arg::with_name("virtual-gpu")
                 .long("virtual-gpu")
                 .help("Run QEMU with a virtual QXL GPU that draws to a GUI window (useful for troubleshooting)")
                 .takes_value(false))
+        ).subcommand(SubCommand::with_name("status")
+            .about("Shows what Windows and the driver are up to")
+            .long_about("Shows what Windows and the driver are up to. Exits with 3 if the driver isn't running.")
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Print JSON instead of text, one object per line")
+                .takes_value(false))
+            .arg(Arg::with_name("follow")
+                .long("follow")
+                .short("f")
+                .help("Keep printing the status whenever it changes")
+                .takes_value(false))
+        ).subcommand(SubCommand::with_name("list")
+            .about("Lists running instances")
+        ).subcommand(SubCommand::with_name("qmp")
+            .about("Executes a raw QMP command, for debugging")
+            .long_about("Executes a raw QMP command and prints what qemu returned, for debugging. Only the commands \
+            in the qmp_commands access setting are allowed, which are just the query-* ones by default.")
+            .arg(Arg::with_name("command")
+                .help("The QMP command, e.g. query-status")
+                .required_unless("events"))
+            .arg(Arg::with_name("arguments")
+                .help("Arguments of the command as a JSON object")
+                .validator(|args| serde_json::from_str::<serde_json::Value>(&args).map(|_| ()).map_err(|e| e.to_string())))
+            .arg(Arg::with_name("events")
+                .long("events")
+                .help("Print QMP events as they happen instead, one JSON object per line")
+                .conflicts_with("command")
+                .takes_value(false))
         ).subcommand(SubCommand::with_name("wizard")
             .about("Runs the wizard")
         ).subcommand(SubCommand::with_name("backup")
             .about("Support functionality for performing block-level backups of your Windows VM")
-            .subcommand(SubCommand::with_name("start").about("Enter backup mode. Redirect disks to snapshot files where configured."))
+            .subcommand(SubCommand::with_name("start").about("Enter backup mode. Redirect disks to a new snapshot overlay where configured, \
+                committing the oldest overlays beyond the configured retention."))
             .subcommand(SubCommand::with_name("stop").about("Leave backup mode. Commit and then remove all active snapshot files."))
         ).subcommand(SubCommand::with_name("control")
             .about("Commands to interact with the driver")
@@ -84,8 +128,21 @@ fn main() {
                 .about("Detaches configured attached devices")
             ).subcommand(SubCommand::with_name("shutdown")
                 .about("Shuts down Windows, gracefully stopping execution of the driver")
+                .arg(Arg::with_name("wait")
+                    .long("wait")
+                    .help("Wait until Windows is down, printing the shutdown progress")
+                    .takes_value(false))
             ).subcommand(SubCommand::with_name("suspend")
                 .about("Suspends Windows")
+            ).subcommand(SubCommand::with_name("pause")
+                .about("Freezes the VM without involving Windows")
+            ).subcommand(SubCommand::with_name("resume")
+                .about("Resumes a paused VM")
+            ).subcommand(SubCommand::with_name("save")
+                .about("Saves the VM state to disk and stops qemu. The next run restores it.")
+                .long_about("Saves the VM state to disk and stops qemu. The next run restores it. \
+                This is impossible while devices that qemu can't migrate (VFIO PCI devices, host USB devices) \
+                are attached to the VM.")
             )
         );
     let matches = cli.clone().get_matches();
@@ -103,13 +160,19 @@ fn main() {
         RunMode::User
     };
 
-    let xdg_dirs = xdg::BaseDirectories::with_prefix("windows-gaming-driver").unwrap();
+    let instance = matches.value_of("instance");
+    if let Some(name) = instance {
+        debug!("Using instance {}", name);
+    }
+    let instance_name = driver::instance::dir_name(instance);
+
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(&instance_name).unwrap();
 
     let config_path = match matches.value_of("config") {
         Some(x) => Path::new(&x).to_path_buf(),
         None => {
             match mode {
-                RunMode::System => Path::new("/etc/windows-gaming-driver.toml").to_path_buf(),
+                RunMode::System => Path::new("/etc").join(format!("{}.toml", instance_name)),
                 RunMode::User => xdg_dirs.place_config_file("config").expect("Failed to create config directory."),
             }
         }
@@ -117,82 +180,110 @@ fn main() {
     debug!("Using config file {:?}", config_path);
 
     let workdir_path = match mode {
-        RunMode::System => Path::new("/run/windows-gaming-driver").to_path_buf(),
+        RunMode::System => Path::new("/run").join(&instance_name),
         RunMode::User => xdg_dirs.create_runtime_directory("").expect("Failed to create runtime directory."),
     };
     debug!("Working directory is {:?}", workdir_path);
 
-    let cfg = Config::load(&config_path);
+    let cfg = match Config::load(&config_path) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            let e = driver::DriverError::InvalidConfig(e);
+            error!("{}", e);
+            process::exit(e.exit_code());
+        }
+    };
     trace!("Successfully loaded configuration file.");
 
-    let control_socket = xdg_dirs.place_runtime_file("control.sock").unwrap();
-
     let data_folder = Path::new(match cfg {
         Some(Config { data_directory_override: Some(ref x), .. }) => x.as_str(),
         _ => DATA_FOLDER,
     }).to_owned();
 
+    // other instances live next to the default runtime directory
+    let instances_path = workdir_path.parent().expect("Runtime directory has no parent").to_owned();
+
     let workdir_path = match cfg {
         Some(Config { runtime_directory_override: Some(ref x), .. }) => Path::new(x).to_path_buf(),
         _ => workdir_path,
     };
+    let control_socket = workdir_path.join("control.sock");
+
+    let state_path = match cfg {
+        Some(Config { state_directory_override: Some(ref x), .. }) => Path::new(x).to_path_buf(),
+        _ => match mode {
+            RunMode::System => Path::new("/var/lib").join(&instance_name),
+            RunMode::User => xdg_dirs.create_data_directory("").expect("Failed to create data directory."),
+        },
+    };
+    debug!("State directory is {:?}", state_path);
 
     match matches.subcommand() {
-        ("run", cmd) => driver::run(cfg.as_ref().unwrap(), &workdir_path, &data_folder, cmd.unwrap().is_present("virtual-gpu")),
+        ("run", cmd) => run(cfg.as_ref().unwrap(), instance, &workdir_path, &data_folder, &state_path,
+                            cmd.unwrap().is_present("virtual-gpu")),
+        ("status", cmd) => {
+            let cmd = cmd.unwrap();
+            status(&control_socket, cmd.is_present("json"), cmd.is_present("follow"));
+        }
+        ("list", _) => list(&instances_path),
+        ("qmp", cmd) => {
+            let cmd = cmd.unwrap();
+            match cmd.value_of("command") {
+                Some(execute) => {
+                    let arguments = cmd.value_of("arguments").map(|args| serde_json::from_str(args).unwrap());
+                    qmp(&control_socket, execute, arguments);
+                }
+                None => qmp_events(&control_socket),
+            }
+        }
         ("wizard", _) => unimplemented!("wizard"),
         ("control", cmd) => {
             match cmd.unwrap().subcommand() {
                 ("attach", cmd) => {
                     let cmd = cmd.unwrap();
                     if cmd.is_present("try") {
-                        control_send(ControlCmdIn::TryIoEntry, &control_socket);
+                        control_send(Command::Attach { mode: AttachMode::Try }, &control_socket);
                     } else if cmd.is_present("force") {
-                        control_send(ControlCmdIn::ForceIoEntry, &control_socket);
+                        control_send(Command::Attach { mode: AttachMode::Force }, &control_socket);
                     } else if cmd.is_present("light") {
-                        control_send(ControlCmdIn::LightEntry, &control_socket);
+                        control_send(Command::Attach { mode: AttachMode::Light }, &control_socket);
+                    } else {
+                        control_send(Command::Attach { mode: AttachMode::Auto }, &control_socket);
+                    }
+                }
+                ("detach", _) => control_send(Command::Detach, &control_socket),
+                ("shutdown", cmd) => {
+                    if cmd.unwrap().is_present("wait") {
+                        control_shutdown_wait(&control_socket);
                     } else {
-                        control_send(ControlCmdIn::IoEntry, &control_socket);
+                        control_send(Command::Shutdown, &control_socket);
                     }
                 }
-                ("detach", _) => control_send(ControlCmdIn::IoExit, &control_socket),
-                ("shutdown", _) => control_send(ControlCmdIn::Shutdown, &control_socket),
-                ("suspend", _) => control_send(ControlCmdIn::Suspend, &control_socket),
+                ("suspend", _) => control_send(Command::Suspend, &control_socket),
+                ("pause", _) => control_send(Command::Pause, &control_socket),
+                ("resume", _) => control_send(Command::Resume, &control_socket),
+                ("save", _) => control_save(&control_socket),
                 _ => unreachable!()
             }
         }
         ("backup", cmd) => {
+            let cfg = cfg.as_ref().unwrap();
             match cmd.unwrap().subcommand() {
                 ("start", _) => {
-                    if !control_send_fallible(ControlCmdIn::EnterBackupMode, &control_socket) {
+                    if !control_send_fallible(Command::EnterBackupMode, &control_socket) {
                         // qemu is down, so invoke qemu-img to do it
-                        let todos = cfg.as_ref().unwrap().machine.storage.iter()
-                            .filter_map(|d| d.snapshot_file.as_ref().map(|s| (s, &d.path, &d.format)))
-                            .filter(|(s, _, _)| !Path::new(s).exists());
-                        for (snap, path, format) in todos {
-                            // qemu-img create -f qcow2 -b /dev/tank/windows -F raw qemu-snaps/windows.qcow2
-                            let status = Command::new("qemu-img")
-                                .args(&["create", "-f", "qcow2", "-b", path, "-F", format, snap])
-                                .status().expect("Failed to call qemu-img");
-                            if !status.success() {
-                                panic!("qemu-img reported an error");
-                            }
+                        if let Err(e) = driver::backup::enter_offline(&cfg.machine, cfg.backup.keep_overlays) {
+                            error!("Failed to enter backup mode: {}", e);
+                            process::exit(1);
                         }
                     }
                 }
                 ("stop", _) => {
-                    if !control_send_fallible(ControlCmdIn::LeaveBackupMode, &control_socket) {
+                    if !control_send_fallible(Command::LeaveBackupMode, &control_socket) {
                         // qemu is down, so invoke qemu-img to do it
-                        let todos = cfg.as_ref().unwrap().machine.storage.iter()
-                            .filter_map(|d| d.snapshot_file.as_ref())
-                            .filter(|s| Path::new(s).exists());
-                        for snap in todos {
-                            let status = Command::new("qemu-img")
-                                .args(&["commit", "-d", snap])
-                                .status().expect("Failed to call qemu-img");
-                            if !status.success() {
-                                panic!("qemu-img reported an error");
-                            }
-                            fs::remove_file(snap).expect("Failed to delete old snapshot file");
+                        if let Err(e) = driver::backup::leave_offline(&cfg.machine) {
+                            error!("Failed to leave backup mode: {}", e);
+                            process::exit(1);
                         }
                     }
                 }
@@ -200,46 +291,261 @@ fn main() {
             }
         }
         _ => match cfg {
-            Some(ref cfg) if cfg.setup.is_none() => driver::run(cfg, &workdir_path, &data_folder, false),
+            Some(ref cfg) if cfg.setup.is_none() => run(cfg, instance, &workdir_path, &data_folder, &state_path, false),
             _cfg => unimplemented!("wizard"),
         }
     }
 }
 
-fn control_send<P: AsRef<Path>>(cmd: ControlCmdIn, socket_path: P) {
+fn run(cfg: &Config, instance: Option<&str>, workdir: &Path, data: &Path, state: &Path, enable_gui: bool) -> ! {
+    match driver::run(cfg, instance, workdir, data, state, enable_gui) {
+        Ok(outcome) => process::exit(outcome.exit_code()),
+        Err(e) => {
+            error!("{}", e);
+            process::exit(e.exit_code());
+        }
+    }
+}
+
+fn status(socket_path: &Path, json: bool, follow: bool) {
+    let mut client = match control_connect(socket_path) {
+        Some(client) => client,
+        None => {
+            match json {
+                true => println!("{}", json!({ "status": null })),
+                false => println!("Not running"),
+            }
+            process::exit(3);
+        }
+    };
+
+    let mut last = match client.request(Command::Status) {
+        Ok(Ok(Reply::Status { status, uptime })) => {
+            match json {
+                true => println!("{}", json!({ "status": status, "uptime": uptime })),
+                false => println!("{}\nUp for {}", status, format_duration(uptime)),
+            }
+            status
+        }
+        Ok(Ok(other)) => {
+            eprintln!("Unexpected reply from the driver: {:?}", other);
+            process::exit(1);
+        }
+        Ok(Err(e)) => {
+            eprintln!("The driver refused: {}", e);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to talk to the driver: {}", e);
+            process::exit(1);
+        }
+    };
+    if !follow {
+        return;
+    }
+
+    control_request(&mut client, Command::Subscribe);
+    loop {
+        match client.next_event() {
+            // the subscription starts with the current status, which we probably just printed
+            Ok(Some(Event::Status { status })) if status == last => (),
+            Ok(Some(Event::Status { status })) => {
+                match json {
+                    true => println!("{}", json!({ "status": status })),
+                    false => println!("{}", status),
+                }
+                last = status;
+            }
+            Ok(Some(_)) => (),
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Lost the connection to the driver: {}", e);
+                break;
+            }
+        }
+    }
+    match json {
+        true => println!("{}", json!({ "status": null })),
+        false => println!("Not running"),
+    }
+}
+
+/// Formats `secs` like 1d 2h 3m 4s, leaving out leading zeros.
+fn format_duration(secs: u64) -> String {
+    let parts = [(secs / 86400, "d"), (secs / 3600 % 24, "h"), (secs / 60 % 60, "m"), (secs % 60, "s")];
+    let mut out = String::new();
+    for &(value, unit) in parts.iter().skip_while(|&&(value, unit)| value == 0 && unit != "s") {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push_str(&format!("{}{}", value, unit));
+    }
+    out
+}
+
+fn list(instances_path: &Path) {
+    let instances = driver::instance::running(instances_path).expect("Failed to look for instances");
+    if instances.is_empty() {
+        println!("No instances running");
+        return;
+    }
+    println!("{:<20} {:<10} {}", "INSTANCE", "QEMU PID", "STATUS");
+    for instance in instances {
+        let pid = instance.state.as_ref().map(|s| s.qemu_pid.to_string()).unwrap_or("-".to_owned());
+        let status = match (instance.driver_running, &instance.state) {
+            (true, _) => "running",
+            // will be reattached by the next run
+            (false, &Some(_)) => "driver down",
+            (false, &None) => "qemu down",
+        };
+        println!("{:<20} {:<10} {}", instance.display_name(), pid, status);
+    }
+}
+
+/// Connects to the driver, `None` if it isn't running.
+fn control_connect(socket_path: &Path) -> Option<Client> {
+    match Client::connect(socket_path) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to talk to the driver: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Sends `cmd` and waits for it to complete, exiting on errors.
+fn control_request(client: &mut Client, cmd: Command) {
+    control_result(client.request(cmd))
+}
+
+fn control_request_with<F: FnMut(Event)>(client: &mut Client, cmd: Command, on_event: F) {
+    control_result(client.request_with(cmd, on_event))
+}
+
+fn control_result(result: io::Result<Result<Reply, ControlError>>) {
+    match result {
+        Ok(Ok(Reply::Done)) => (),
+        Ok(Ok(other)) => {
+            eprintln!("Unexpected reply from the driver: {:?}", other);
+            process::exit(1);
+        }
+        Ok(Err(e)) => {
+            eprintln!("The driver refused: {}", e);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to talk to the driver: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn control_send(cmd: Command, socket_path: &Path) {
     if !control_send_fallible(cmd, socket_path) {
-        panic!("Windows is down");
-    }
-}
-fn control_send_fallible<P: AsRef<Path>>(cmd: ControlCmdIn, socket_path: P) -> bool {
-    let mut writer = match UnixStream::connect(socket_path) {
-        Err(e) if e.kind() == ErrorKind::ConnectionRefused || e.kind() == ErrorKind::NotFound => return false,
-        x => x,
-    }.unwrap();
-    writer.write(&[match cmd {
-        ControlCmdIn::IoEntry => 1,
-        ControlCmdIn::TryIoEntry => 6,
-        ControlCmdIn::LightEntry => 7,
-        ControlCmdIn::Shutdown => 2,
-        ControlCmdIn::ForceIoEntry => 3,
-        ControlCmdIn::IoExit => 4,
-        ControlCmdIn::Suspend => 5,
-        ControlCmdIn::TemporaryLightEntry { .. } => unimplemented!(),
-        ControlCmdIn::EnterBackupMode => 9,
-        ControlCmdIn::LeaveBackupMode => 10,
-    }]).unwrap();
-    writer.flush().unwrap();
-
-    match cmd {
-        ControlCmdIn::EnterBackupMode | ControlCmdIn::LeaveBackupMode => {
-            // wait for ack
-            let mut v = [0];
-            writer.read_exact(&mut v).unwrap();
-            let [v] = v;
-            assert_eq!(v, 4); // ack command
-        }
-        _ => (),
-    }
-
-    true
+        eprintln!("Windows is down");
+        process::exit(1);
+    }
+}
+
+/// Like `control_send`, but returns false if the driver isn't running.
+fn control_send_fallible(cmd: Command, socket_path: &Path) -> bool {
+    match control_connect(socket_path) {
+        Some(mut client) => {
+            control_request(&mut client, cmd);
+            true
+        }
+        None => false,
+    }
+}
+
+fn qmp(socket_path: &Path, execute: &str, arguments: Option<serde_json::Value>) {
+    let mut client = control_connect(socket_path).unwrap_or_else(|| {
+        eprintln!("Windows is down");
+        process::exit(1);
+    });
+    match client.request(Command::Qmp { execute: execute.to_owned(), arguments }) {
+        Ok(Ok(Reply::Qmp { result })) => println!("{}", serde_json::to_string_pretty(&result).unwrap()),
+        Ok(Ok(other)) => {
+            eprintln!("Unexpected reply from the driver: {:?}", other);
+            process::exit(1);
+        }
+        Ok(Err(e)) => {
+            eprintln!("{} failed: {}", execute, e);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to talk to the driver: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn qmp_events(socket_path: &Path) {
+    let mut client = control_connect(socket_path).unwrap_or_else(|| {
+        eprintln!("Windows is down");
+        process::exit(1);
+    });
+    control_request(&mut client, Command::QmpEvents);
+    loop {
+        match client.next_event() {
+            Ok(Some(Event::Qmp { qmp })) => println!("{}", qmp),
+            Ok(Some(_)) => (),
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Lost the connection to the driver: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+fn control_save(socket_path: &Path) {
+    let mut client = control_connect(socket_path).unwrap_or_else(|| {
+        eprintln!("Windows is down");
+        process::exit(1);
+    });
+    match client.request(Command::Save) {
+        Ok(Ok(Reply::Done)) => println!("Saved Windows. It will be restored on the next run."),
+        Ok(Ok(other)) => {
+            eprintln!("Unexpected reply from the driver: {:?}", other);
+            process::exit(1);
+        }
+        Ok(Err(e)) => {
+            eprintln!("Can't save Windows: {}", e);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Driver went away while saving: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn control_shutdown_wait(socket_path: &Path) {
+    let mut client = control_connect(socket_path).unwrap_or_else(|| {
+        eprintln!("Windows is down");
+        process::exit(1);
+    });
+    fn print_progress(event: Event) {
+        match event {
+            Event::ShutdownProgress { stage: ShutdownStage::GuestAgent } => println!("Asked the guest agent to shut down"),
+            Event::ShutdownProgress { stage: ShutdownStage::Acpi } => println!("Sent ACPI powerdown"),
+            Event::ShutdownProgress { stage: ShutdownStage::Quit } => println!("Forcing qemu to quit"),
+            other => debug!("Ignoring event {:?}", other),
+        }
+    }
+    control_request_with(&mut client, Command::Shutdown, print_progress);
+
+    // the driver reports every escalation step and closes the connection once qemu is gone
+    loop {
+        match client.next_event() {
+            Ok(Some(event)) => print_progress(event),
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Lost the connection to the driver: {}", e);
+                break;
+            }
+        }
+    }
+    println!("Windows is down");
 }